@@ -0,0 +1,29 @@
+// Example usage for crawling the DHT and writing a network census.
+
+use rust_libp2p_kad_swarm as synack_node;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[async_std::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    println!("Arguments: {:?}", args);
+    let output = args.get(1).cloned().unwrap_or_else(|| "census".to_string());
+    // The crawler only runs queries, there is no need to serve the DHT.
+    let mut a = synack_node::LookupClient::new_with_mode(
+        &synack_node::Network::Kusama,
+        synack_node::KademliaMode::Client
+    );
+    let config = synack_node::CrawlerConfig {
+        state_file: Some(PathBuf::from(format!("{}.state.json", output))),
+        ..Default::default()
+    };
+    let census = match a.crawl(config).await {
+        Ok(census) => census,
+        Err(e) => panic!("There was an error : {:?}", e)
+    };
+    println!("Crawled {} peers.", census.entries.len());
+    census.write_json(format!("{}.json", output)).unwrap();
+    census.write_csv(format!("{}.csv", output)).unwrap();
+    a.shutdown(Duration::from_secs(5)).await;
+}
@@ -6,7 +6,7 @@ use libp2p::core::{
     Multiaddr
 };
 use std::str::FromStr;
-use test_protocol;
+use std::time::Duration;
 
 #[async_std::main]
 async fn main() {
@@ -36,14 +36,17 @@ async fn main() {
     let _ = a.add_address(expected_peer_id, expected_address).await;
 
     let _ = a.listen().await;
-    let payload = test_protocol::SYN("SYN".to_string().into_bytes());
-    let _ = a.send_request(expected_peer_id, payload).await;
+    if let Err(e) = a.start_handshake(expected_peer_id).await {
+        panic!("There was an error : {:?}",e)
+    }
     match a.init_protocol().await {
         Ok(peer) => {
             println!("Handshake with {:?} succeded.", peer);
         }
         Err(e) => panic!("There was an error : {:?}",e)
     }
+    let report = a.shutdown(Duration::from_secs(5)).await;
+    println!("Closed {} connections.", report.closed_connections);
 }
 
 fn usage_message() {
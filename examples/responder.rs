@@ -1,8 +1,10 @@
-// Example usage for listening a Request and emit a Response.
+// Example usage for listening to Requests and emitting Responses.
 
 use rust_libp2p_kad_swarm as synack_node;
+use futures::StreamExt;
 use libp2p::core::PeerId;
 use std::str::FromStr;
+use std::time::Duration;
 
 #[async_std::main]
 async fn main() {
@@ -26,12 +28,23 @@ async fn main() {
     };
     println!("Found {:?} at address {:?}", peer.peer_id, peer.listen_addrs);
     println!("Observed peer_id and addresses : {:?} {:?}", a.local_peer_id, peer.observed_addr);
-    match a.init_protocol().await {
-        Ok(peer) => {
-            println!("Handshake with {:?} succeded.", peer);
+    // Serves requesters until Enter is pressed.
+    let mut events = a.handshake_events();
+    async_std::task::spawn(async move {
+        while let Some(event) = events.next().await {
+            match event {
+                synack_node::HandshakeEvent::Completed { peer_id, .. } => println!("Handshake with {:?} succeded.", peer_id),
+                synack_node::HandshakeEvent::Failed(e) => println!("There was an error : {:?}", e),
+            }
         }
-        Err(e) => panic!("There was an error : {:?}",e)
-    }
+    });
+    let stop = async {
+        let mut line = String::new();
+        let _ = async_std::io::stdin().read_line(&mut line).await;
+    };
+    a.serve_handshakes_until(stop).await;
+    let report = a.shutdown(Duration::from_secs(5)).await;
+    println!("Closed {} connections.", report.closed_connections);
 }
 
 
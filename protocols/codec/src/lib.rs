@@ -0,0 +1,587 @@
+// Request-response codec for any pair of serde message types, optionally spread over
+// several versions of a protocol. Shared by the main crate and the protocol crates, which
+// declare their codecs with it.
+
+use std::borrow::Cow;
+use std::io;
+use std::iter;
+use std::marker::PhantomData;
+use async_trait::async_trait;
+use ciborium::value::Value;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::request_response::{
+    ProtocolName,
+    ProtocolSupport,
+    RequestResponse,
+    RequestResponseCodec,
+    RequestResponseConfig
+};
+use libp2p_core::upgrade::{
+    read_varint,
+    write_length_prefixed
+};
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Cbor,
+    Json,
+    // The bytes of messages that serialize to a byte string or a sequence of bytes, such as
+    // newtypes over `Vec<u8>`. Other messages fail to encode.
+    Raw,
+}
+
+impl Encoding {
+    fn encode<M: Serialize>(&self, message: &M) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(message, &mut bytes).map_err(|e| invalid_data(e.to_string()))?;
+                Ok(bytes)
+            },
+            Encoding::Json => serde_json::to_vec(message).map_err(|e| invalid_data(e.to_string())),
+            Encoding::Raw => match Value::serialized(message).map_err(|e| invalid_data(e.to_string()))? {
+                Value::Bytes(bytes) => Ok(bytes),
+                Value::Array(items) => items
+                    .into_iter()
+                    .map(|item| match item {
+                        Value::Integer(byte) => u8::try_from(byte).map_err(|e| invalid_data(e.to_string())),
+                        _ => Err(invalid_data("raw messages are sequences of bytes".to_string())),
+                    })
+                    .collect(),
+                _ => Err(invalid_data("raw messages are sequences of bytes".to_string())),
+            },
+        }
+    }
+    fn decode<M: DeserializeOwned>(&self, bytes: &[u8]) -> io::Result<M> {
+        match self {
+            Encoding::Cbor => ciborium::de::from_reader(bytes).map_err(|e| invalid_data(e.to_string())),
+            Encoding::Json => serde_json::from_slice(bytes).map_err(|e| invalid_data(e.to_string())),
+            // A sequence deserializes into byte vectors and byte buffers alike.
+            Encoding::Raw => Value::Array(bytes.iter().map(|byte| Value::Integer((*byte).into())).collect())
+                .deserialized()
+                .map_err(|e| invalid_data(e.to_string())),
+        }
+    }
+}
+
+fn invalid_data(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Request,
+    Response,
+}
+
+// Maximum encoded size of each message, without the length prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeLimits {
+    pub max_request_size: usize,
+    pub max_response_size: usize,
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        SizeLimits {
+            max_request_size: 64 * 1024,
+            max_response_size: 64 * 1024,
+        }
+    }
+}
+
+impl SizeLimits {
+    pub fn new(max_request_size: usize, max_response_size: usize) -> Self {
+        SizeLimits { max_request_size, max_response_size }
+    }
+    fn of(&self, kind: MessageKind) -> usize {
+        match kind {
+            MessageKind::Request => self.max_request_size,
+            MessageKind::Response => self.max_response_size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("{kind:?} of {size} bytes exceeds the limit of {limit} bytes")]
+pub struct MessageTooLarge {
+    pub kind: MessageKind,
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl MessageTooLarge {
+    // Looks through the source chain, I/O errors included, so that it also finds the
+    // cause of a connection closed by the request-response handler.
+    pub fn find<'a>(error: &'a (dyn std::error::Error + 'static)) -> Option<&'a MessageTooLarge> {
+        let mut current = Some(error);
+        while let Some(error) = current {
+            if let Some(too_large) = error.downcast_ref::<MessageTooLarge>() {
+                return Some(too_large);
+            }
+            let inner = error
+                .downcast_ref::<io::Error>()
+                .and_then(|error| error.get_ref())
+                .and_then(|inner| inner.downcast_ref::<MessageTooLarge>());
+            if inner.is_some() {
+                return inner;
+            }
+            current = error.source();
+        }
+        None
+    }
+}
+
+// A protocol name and, when it differs from the one of the codec, the encoding used under it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerdeProtocol {
+    name: Cow<'static, str>,
+    encoding: Option<Encoding>,
+}
+
+impl SerdeProtocol {
+    pub fn new<N: Into<Cow<'static, str>>>(name: N) -> Self {
+        SerdeProtocol { name: name.into(), encoding: None }
+    }
+    pub fn with_encoding<N: Into<Cow<'static, str>>>(name: N, encoding: Encoding) -> Self {
+        SerdeProtocol { name: name.into(), encoding: Some(encoding) }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.encoding
+    }
+}
+
+impl ProtocolName for SerdeProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        self.name.as_bytes()
+    }
+}
+
+// A message with the protocol version it was read under. Outbound messages have none, the
+// version is only known once negotiated with the remote.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated<M> {
+    pub message: M,
+    pub protocol: Option<SerdeProtocol>,
+}
+
+impl<M> From<M> for Negotiated<M> {
+    fn from(message: M) -> Self {
+        Negotiated { message, protocol: None }
+    }
+}
+
+// Messages are length prefixed, one request or response per substream.
+pub struct SerdeCodec<Req, Res> {
+    encoding: Encoding,
+    limits: SizeLimits,
+    messages: PhantomData<fn() -> (Req, Res)>,
+}
+
+impl<Req, Res> Clone for SerdeCodec<Req, Res> {
+    fn clone(&self) -> Self {
+        SerdeCodec::new(self.encoding, self.limits)
+    }
+}
+
+impl<Req, Res> SerdeCodec<Req, Res> {
+    pub fn new(encoding: Encoding, limits: SizeLimits) -> Self {
+        SerdeCodec {
+            encoding,
+            limits,
+            messages: PhantomData,
+        }
+    }
+    pub fn cbor(limits: SizeLimits) -> Self {
+        Self::new(Encoding::Cbor, limits)
+    }
+    pub fn json(limits: SizeLimits) -> Self {
+        Self::new(Encoding::Json, limits)
+    }
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+    pub fn limits(&self) -> SizeLimits {
+        self.limits
+    }
+    fn encoding_for(&self, protocol: &SerdeProtocol) -> Encoding {
+        protocol.encoding.unwrap_or(self.encoding)
+    }
+    fn check(&self, kind: MessageKind, size: usize) -> Result<(), MessageTooLarge> {
+        let limit = self.limits.of(kind);
+        if size > limit {
+            return Err(MessageTooLarge { kind, size, limit });
+        }
+        Ok(())
+    }
+}
+
+impl<Req, Res> SerdeCodec<Req, Res>
+where
+    Req: Serialize + DeserializeOwned + Send + 'static,
+    Res: Serialize + DeserializeOwned + Send + 'static,
+{
+    pub fn behaviour(self, protocol: SerdeProtocol, support: ProtocolSupport, config: RequestResponseConfig) -> RequestResponse<Self> {
+        RequestResponse::new(self, iter::once((protocol, support)), config)
+    }
+    // Lets the sender fail early instead of having the remote close the connection.
+    // Messages that fail to encode pass, the codec reports them when writing.
+    pub fn check_request(&self, request: &Req) -> Result<(), MessageTooLarge> {
+        match self.encoding.encode(request) {
+            Ok(bytes) => self.check(MessageKind::Request, bytes.len()),
+            Err(_) => Ok(()),
+        }
+    }
+    pub fn check_response(&self, response: &Res) -> Result<(), MessageTooLarge> {
+        match self.encoding.encode(response) {
+            Ok(bytes) => self.check(MessageKind::Response, bytes.len()),
+            Err(_) => Ok(()),
+        }
+    }
+    // The declared length is checked before anything else is read.
+    async fn read_message<T, M>(&self, io: &mut T, kind: MessageKind, encoding: Encoding) -> io::Result<M>
+    where
+        T: AsyncRead + Unpin + Send,
+        M: DeserializeOwned,
+    {
+        let size = read_varint(&mut *io).await?;
+        self.check(kind, size).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if size == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut bytes = vec![0; size];
+        io.read_exact(&mut bytes).await?;
+        encoding.decode(&bytes)
+    }
+    async fn write_message<T>(&self, io: &mut T, kind: MessageKind, bytes: Vec<u8>) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        // The remote would fail reading it anyway, fail before sending.
+        self.check(kind, bytes.len()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        write_length_prefixed(io, bytes).await?;
+        io.close().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<Req, Res> RequestResponseCodec for SerdeCodec<Req, Res>
+where
+    Req: Serialize + DeserializeOwned + Send + 'static,
+    Res: Serialize + DeserializeOwned + Send + 'static,
+{
+    type Protocol = SerdeProtocol;
+    type Request = Req;
+    type Response = Res;
+
+    async fn read_request<T>(&mut self, protocol: &SerdeProtocol, io: &mut T) -> io::Result<Req>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.read_message(io, MessageKind::Request, self.encoding_for(protocol)).await
+    }
+
+    async fn read_response<T>(&mut self, protocol: &SerdeProtocol, io: &mut T) -> io::Result<Res>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.read_message(io, MessageKind::Response, self.encoding_for(protocol)).await
+    }
+
+    async fn write_request<T>(&mut self, protocol: &SerdeProtocol, io: &mut T, request: Req) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let bytes = self.encoding_for(protocol).encode(&request)?;
+        self.write_message(io, MessageKind::Request, bytes).await
+    }
+
+    async fn write_response<T>(&mut self, protocol: &SerdeProtocol, io: &mut T, response: Res) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let bytes = self.encoding_for(protocol).encode(&response)?;
+        self.write_message(io, MessageKind::Response, bytes).await
+    }
+}
+
+// Advertises several versions of a protocol in order of preference. The dialer proposes
+// them in that order and the first one the remote supports is used for the exchange.
+pub struct VersionedCodec<Req, Res> {
+    versions: Vec<SerdeProtocol>,
+    codec: SerdeCodec<Req, Res>,
+    rewrite_request: fn(&SerdeProtocol, Req) -> Req,
+    rewrite_response: fn(&SerdeProtocol, Res) -> Res,
+}
+
+impl<Req, Res> Clone for VersionedCodec<Req, Res> {
+    fn clone(&self) -> Self {
+        VersionedCodec {
+            versions: self.versions.clone(),
+            codec: self.codec.clone(),
+            rewrite_request: self.rewrite_request,
+            rewrite_response: self.rewrite_response,
+        }
+    }
+}
+
+impl<Req, Res> VersionedCodec<Req, Res> {
+    // Versions without an encoding of their own are encoded as CBOR.
+    pub fn new(versions: Vec<SerdeProtocol>, limits: SizeLimits) -> Self {
+        VersionedCodec {
+            versions,
+            codec: SerdeCodec::cbor(limits),
+            rewrite_request: |_, request| request,
+            rewrite_response: |_, response| response,
+        }
+    }
+    // Outbound messages are passed through these once their version is negotiated, for
+    // versions that expect another form of the same message.
+    pub fn with_rewrite(mut self, request: fn(&SerdeProtocol, Req) -> Req, response: fn(&SerdeProtocol, Res) -> Res) -> Self {
+        self.rewrite_request = request;
+        self.rewrite_response = response;
+        self
+    }
+    pub fn versions(&self) -> &[SerdeProtocol] {
+        &self.versions
+    }
+    pub fn limits(&self) -> SizeLimits {
+        self.codec.limits
+    }
+}
+
+impl<Req, Res> VersionedCodec<Req, Res>
+where
+    Req: Serialize + DeserializeOwned + Send + 'static,
+    Res: Serialize + DeserializeOwned + Send + 'static,
+{
+    pub fn behaviour(self, support: ProtocolSupport, config: RequestResponseConfig) -> RequestResponse<Self> {
+        let protocols: Vec<_> = self.versions.iter().cloned().map(|version| (version, support)).collect();
+        RequestResponse::new(self, protocols, config)
+    }
+    // The version is not known before sending, the message has to fit under all of them.
+    pub fn check_request(&self, request: &Req) -> Result<(), MessageTooLarge>
+    where
+        Req: Clone,
+    {
+        self.check_all(MessageKind::Request, request, self.rewrite_request)
+    }
+    pub fn check_response(&self, response: &Res) -> Result<(), MessageTooLarge>
+    where
+        Res: Clone,
+    {
+        self.check_all(MessageKind::Response, response, self.rewrite_response)
+    }
+    fn check_all<M: Serialize + Clone>(&self, kind: MessageKind, message: &M, rewrite: fn(&SerdeProtocol, M) -> M) -> Result<(), MessageTooLarge> {
+        for version in &self.versions {
+            let message = rewrite(version, message.clone());
+            if let Ok(bytes) = self.codec.encoding_for(version).encode(&message) {
+                self.codec.check(kind, bytes.len())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<Req, Res> RequestResponseCodec for VersionedCodec<Req, Res>
+where
+    Req: Serialize + DeserializeOwned + Send + 'static,
+    Res: Serialize + DeserializeOwned + Send + 'static,
+{
+    type Protocol = SerdeProtocol;
+    type Request = Negotiated<Req>;
+    type Response = Negotiated<Res>;
+
+    async fn read_request<T>(&mut self, protocol: &SerdeProtocol, io: &mut T) -> io::Result<Negotiated<Req>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let message = self.codec.read_request(protocol, io).await?;
+        Ok(Negotiated { message, protocol: Some(protocol.clone()) })
+    }
+
+    async fn read_response<T>(&mut self, protocol: &SerdeProtocol, io: &mut T) -> io::Result<Negotiated<Res>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let message = self.codec.read_response(protocol, io).await?;
+        Ok(Negotiated { message, protocol: Some(protocol.clone()) })
+    }
+
+    async fn write_request<T>(&mut self, protocol: &SerdeProtocol, io: &mut T, request: Negotiated<Req>) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let request = (self.rewrite_request)(protocol, request.message);
+        self.codec.write_request(protocol, io, request).await
+    }
+
+    async fn write_response<T>(&mut self, protocol: &SerdeProtocol, io: &mut T, response: Negotiated<Res>) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let response = (self.rewrite_response)(protocol, response.message);
+        self.codec.write_response(protocol, io, response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Query {
+        nonce: u64,
+        topic: String,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum Answer {
+        Found(Vec<String>),
+        Missing,
+    }
+
+    async fn round_trip(mut codec: SerdeCodec<Query, Answer>) {
+        let protocol = SerdeProtocol::new("/query/1.0.0");
+        let query = Query { nonce: 7, topic: "peers".to_string() };
+        let mut wire = Vec::new();
+        codec.write_request(&protocol, &mut Cursor::new(&mut wire), query.clone()).await.unwrap();
+        assert_eq!(codec.read_request(&protocol, &mut Cursor::new(wire)).await.unwrap(), query);
+        for answer in [Answer::Found(vec!["/ip4/1.2.3.4/tcp/30333".to_string()]), Answer::Missing] {
+            let mut wire = Vec::new();
+            codec.write_response(&protocol, &mut Cursor::new(&mut wire), answer.clone()).await.unwrap();
+            assert_eq!(codec.read_response(&protocol, &mut Cursor::new(wire)).await.unwrap(), answer);
+        }
+    }
+
+    #[async_std::test]
+    async fn cbor_and_json_round_trip() {
+        round_trip(SerdeCodec::cbor(SizeLimits::default())).await;
+        round_trip(SerdeCodec::json(SizeLimits::default())).await;
+    }
+
+    #[async_std::test]
+    async fn rejects_malformed_payloads() {
+        let protocol = SerdeProtocol::new("/query/1.0.0");
+        let mut wire = Vec::new();
+        write_length_prefixed(&mut Cursor::new(&mut wire), b"{\"nonce\":\"seven\"}").await.unwrap();
+        let mut codec = SerdeCodec::<Query, Answer>::json(SizeLimits::default());
+        let error = codec.read_request(&protocol, &mut Cursor::new(wire)).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    // JSON strings encode to their length plus two quotes.
+    fn payload(size: usize) -> String {
+        "a".repeat(size - 2)
+    }
+
+    #[async_std::test]
+    async fn limits_are_enforced_at_the_boundary() {
+        let protocol = SerdeProtocol::new("/echo/1.0.0");
+        let mut codec = SerdeCodec::<String, String>::json(SizeLimits::new(10, 20));
+        let mut wire = Vec::new();
+        codec.write_request(&protocol, &mut Cursor::new(&mut wire), payload(10)).await.unwrap();
+        assert_eq!(codec.read_request(&protocol, &mut Cursor::new(wire)).await.unwrap(), payload(10));
+        let mut wire = Vec::new();
+        codec.write_response(&protocol, &mut Cursor::new(&mut wire), payload(20)).await.unwrap();
+        assert_eq!(codec.read_response(&protocol, &mut Cursor::new(wire)).await.unwrap(), payload(20));
+
+        let error = codec.write_request(&protocol, &mut Cursor::new(&mut Vec::new()), payload(11)).await.unwrap_err();
+        assert_eq!(
+            MessageTooLarge::find(&error),
+            Some(&MessageTooLarge { kind: MessageKind::Request, size: 11, limit: 10 })
+        );
+        assert_eq!(
+            codec.check_response(&payload(21)),
+            Err(MessageTooLarge { kind: MessageKind::Response, size: 21, limit: 20 })
+        );
+        assert_eq!(codec.check_response(&payload(20)), Ok(()));
+    }
+
+    #[async_std::test]
+    async fn oversized_messages_are_rejected_when_read() {
+        let protocol = SerdeProtocol::new("/echo/1.0.0");
+        let mut sender = SerdeCodec::<String, String>::json(SizeLimits::default());
+        let mut receiver = SerdeCodec::<String, String>::json(SizeLimits::new(10, 20));
+        let mut wire = Vec::new();
+        sender.write_request(&protocol, &mut Cursor::new(&mut wire), payload(11)).await.unwrap();
+        let error = receiver.read_request(&protocol, &mut Cursor::new(wire)).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            MessageTooLarge::find(&error),
+            Some(&MessageTooLarge { kind: MessageKind::Request, size: 11, limit: 10 })
+        );
+        let mut wire = Vec::new();
+        sender.write_response(&protocol, &mut Cursor::new(&mut wire), payload(21)).await.unwrap();
+        let error = receiver.read_response(&protocol, &mut Cursor::new(wire)).await.unwrap_err();
+        assert_eq!(MessageTooLarge::find(&error).map(|e| e.kind), Some(MessageKind::Response));
+    }
+
+    #[async_std::test]
+    async fn versions_use_their_own_encoding() {
+        let cbor = SerdeProtocol::new("/query/2.0.0");
+        let json = SerdeProtocol::with_encoding("/query/1.0.0", Encoding::Json);
+        let mut codec = VersionedCodec::<Query, Answer>::new(vec![cbor.clone(), json.clone()], SizeLimits::default());
+        let query = Query { nonce: 7, topic: "peers".to_string() };
+        let mut wire = Vec::new();
+        codec.write_request(&json, &mut Cursor::new(&mut wire), query.clone().into()).await.unwrap();
+        let decoded: Query = serde_json::from_slice(&wire[1..]).unwrap();
+        assert_eq!(decoded, query);
+        let negotiated = codec.read_request(&json, &mut Cursor::new(wire)).await.unwrap();
+        assert_eq!(negotiated, Negotiated { message: query.clone(), protocol: Some(json) });
+        let mut wire = Vec::new();
+        codec.write_response(&cbor, &mut Cursor::new(&mut wire), Answer::Missing.into()).await.unwrap();
+        let negotiated = codec.read_response(&cbor, &mut Cursor::new(wire)).await.unwrap();
+        assert_eq!(negotiated.protocol.as_ref().map(SerdeProtocol::name), Some("/query/2.0.0"));
+        assert_eq!(negotiated.message, Answer::Missing);
+    }
+
+    #[test]
+    fn versioned_checks_cover_every_encoding() {
+        // 10 bytes as JSON, 9 as CBOR.
+        let message = payload(10);
+        let cbor_only = VersionedCodec::<String, String>::new(vec![SerdeProtocol::new("/echo/2.0.0")], SizeLimits::new(9, 9));
+        assert_eq!(cbor_only.check_request(&message), Ok(()));
+        let both = VersionedCodec::<String, String>::new(
+            vec![SerdeProtocol::new("/echo/2.0.0"), SerdeProtocol::with_encoding("/echo/1.0.0", Encoding::Json)],
+            SizeLimits::new(9, 9)
+        );
+        assert_eq!(both.check_request(&message), Err(MessageTooLarge { kind: MessageKind::Request, size: 10, limit: 9 }));
+    }
+
+    #[async_std::test]
+    async fn raw_versions_carry_the_bytes_alone() {
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        struct Bytes(Vec<u8>);
+        let raw = SerdeProtocol::with_encoding("/bytes/0.0.1", Encoding::Raw);
+        let upper = |protocol: &SerdeProtocol, Bytes(bytes): Bytes| match protocol.encoding() {
+            Some(Encoding::Raw) => Bytes(bytes.to_ascii_uppercase()),
+            _ => Bytes(bytes),
+        };
+        let mut codec = VersionedCodec::<Bytes, Bytes>::new(vec![SerdeProtocol::new("/bytes/0.0.2"), raw.clone()], SizeLimits::new(3, 3))
+            .with_rewrite(upper, |_, response| response);
+        let mut wire = Vec::new();
+        codec.write_request(&raw, &mut Cursor::new(&mut wire), Bytes(b"syn".to_vec()).into()).await.unwrap();
+        assert_eq!(wire, b"\x03SYN");
+        let negotiated = codec.read_request(&raw, &mut Cursor::new(wire)).await.unwrap();
+        assert_eq!(negotiated.message, Bytes(b"SYN".to_vec()));
+        // Fits as raw bytes, not as CBOR.
+        assert!(codec.check_request(&Bytes(b"syn".to_vec())).is_err());
+        let error = Encoding::Raw.encode(&Query { nonce: 7, topic: "peers".to_string() }).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn protocol_name() {
+        assert_eq!(SerdeProtocol::new("/SYNACK/0.0.2").protocol_name(), b"/SYNACK/0.0.2");
+    }
+}
@@ -1,4 +1,179 @@
-#[derive(Debug, Clone, PartialEq, Eq)]
+use protocol_codec::{Encoding, SerdeProtocol, VersionedCodec};
+use serde::{Deserialize, Serialize};
+
+// Paths used by the code `declare_protocol!` generates, so that crates declaring a protocol
+// need no dependency of their own but the ones of their message types.
+#[doc(hidden)]
+pub mod __private {
+    pub use libp2p;
+    pub use protocol_codec;
+}
+
+// Declares a request-response protocol over existing serde message types : the protocol
+// name with its encoding and size limits, and the `SerdeCodec` instance carrying them.
+// Messages above their size limit fail to be written, and to be read.
+//
+// declare_protocol! {
+//     name: "/SYNACK/0.0.3",
+//     protocol: SynAckProtocol,
+//     codec: SynAckCodec,
+//     request: SYN,
+//     response: SYNACK,
+//     encoding: Cbor,
+//     max_request_size: 64 * 1024,
+//     max_response_size: 64 * 1024,
+// }
+#[macro_export]
+macro_rules! declare_protocol {
+    (
+        name: $name:literal,
+        protocol: $protocol:ident,
+        codec: $codec:ident,
+        request: $request:ty,
+        response: $response:ty,
+        encoding: $encoding:ident,
+        max_request_size: $max_request_size:expr,
+        max_response_size: $max_response_size:expr $(,)?
+    ) => {
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+        pub struct $protocol;
+
+        pub type $codec = $crate::__private::protocol_codec::SerdeCodec<$request, $response>;
+
+        impl $protocol {
+            pub const NAME: &'static str = $name;
+            pub const ENCODING: $crate::__private::protocol_codec::Encoding = $crate::__private::protocol_codec::Encoding::$encoding;
+            pub const MAX_REQUEST_SIZE: usize = $max_request_size;
+            pub const MAX_RESPONSE_SIZE: usize = $max_response_size;
+
+            pub fn limits() -> $crate::__private::protocol_codec::SizeLimits {
+                $crate::__private::protocol_codec::SizeLimits::new(Self::MAX_REQUEST_SIZE, Self::MAX_RESPONSE_SIZE)
+            }
+            pub fn version() -> $crate::__private::protocol_codec::SerdeProtocol {
+                $crate::__private::protocol_codec::SerdeProtocol::with_encoding(Self::NAME, Self::ENCODING)
+            }
+            pub fn codec() -> $codec {
+                $crate::__private::protocol_codec::SerdeCodec::new(Self::ENCODING, Self::limits())
+            }
+            pub fn behaviour(
+                config: $crate::__private::libp2p::request_response::RequestResponseConfig
+            ) -> $crate::__private::libp2p::request_response::RequestResponse<$codec> {
+                Self::codec().behaviour(
+                    Self::version(),
+                    $crate::__private::libp2p::request_response::ProtocolSupport::Full,
+                    config
+                )
+            }
+        }
+
+        impl $crate::__private::libp2p::request_response::ProtocolName for $protocol {
+            fn protocol_name(&self) -> &[u8] {
+                Self::NAME.as_bytes()
+            }
+        }
+    };
+}
+
+// Payloads of the SYN/SYNACK handshake, parsed by the main crate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SYN(pub Vec<u8>);
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct SYNACK(pub Vec<u8>);
\ No newline at end of file
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SYNACK(pub Vec<u8>);
+
+// The handshake payloads carry a signed challenge since 0.0.3, which goes as CBOR first and
+// as JSON second.
+declare_protocol! {
+    name: "/SYNACK/0.0.3",
+    protocol: SynAckProtocol,
+    codec: SynAckCodec,
+    request: SYN,
+    response: SYNACK,
+    encoding: Cbor,
+    max_request_size: 64 * 1024,
+    max_response_size: 64 * 1024,
+}
+
+pub type TestProtocol = SynAckProtocol;
+
+pub const TEST_PROTOCOL: &str = SynAckProtocol::NAME;
+pub const TEST_PROTOCOL_JSON: &str = "/SYNACK/0.0.3/json";
+// The first version, raw bytes without a challenge, proves nothing and is only offered by
+// nodes opting in to reach deployed ones.
+pub const TEST_PROTOCOL_LEGACY: &str = "/SYNACK/0.0.1";
+
+// Every handshake version shares one codec.
+pub type TestCodec = VersionedCodec<SYN, SYNACK>;
+
+// Default handshake versions, in order of preference.
+pub fn test_protocol_versions() -> Vec<SerdeProtocol> {
+    vec![
+        SynAckProtocol::version(),
+        SerdeProtocol::with_encoding(TEST_PROTOCOL_JSON, Encoding::Json),
+    ]
+}
+
+// The default versions and, last, the unauthenticated first one.
+pub fn test_protocol_versions_with_legacy() -> Vec<SerdeProtocol> {
+    let mut versions = test_protocol_versions();
+    versions.push(SerdeProtocol::with_encoding(TEST_PROTOCOL_LEGACY, Encoding::Raw));
+    versions
+}
+
+pub fn is_legacy(protocol: &SerdeProtocol) -> bool {
+    protocol.name() == TEST_PROTOCOL_LEGACY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+    use libp2p::request_response::{ProtocolName, RequestResponseCodec};
+
+    #[test]
+    fn protocol_name() {
+        assert_eq!(SynAckProtocol.protocol_name(), b"/SYNACK/0.0.3");
+        assert_eq!(SynAckProtocol::version(), SerdeProtocol::with_encoding(TEST_PROTOCOL, Encoding::Cbor));
+        assert!(test_protocol_versions_with_legacy().last().map_or(false, is_legacy));
+        assert!(!test_protocol_versions().iter().any(is_legacy));
+    }
+
+    #[async_std::test]
+    async fn messages_round_trip() {
+        let mut codec = SynAckProtocol::codec();
+        for protocol in test_protocol_versions_with_legacy() {
+            let mut wire = Vec::new();
+            codec.write_request(&protocol, &mut Cursor::new(&mut wire), SYN(b"SYN".to_vec())).await.unwrap();
+            assert_eq!(codec.read_request(&protocol, &mut Cursor::new(wire)).await.unwrap(), SYN(b"SYN".to_vec()));
+            let mut wire = Vec::new();
+            codec.write_response(&protocol, &mut Cursor::new(&mut wire), SYNACK(b"SYNACK".to_vec())).await.unwrap();
+            assert_eq!(codec.read_response(&protocol, &mut Cursor::new(wire)).await.unwrap(), SYNACK(b"SYNACK".to_vec()));
+        }
+        // Deployed nodes of the first version send the bytes length prefixed.
+        let legacy = SerdeProtocol::with_encoding(TEST_PROTOCOL_LEGACY, Encoding::Raw);
+        let mut wire = Vec::new();
+        codec.write_request(&legacy, &mut Cursor::new(&mut wire), SYN(b"SYN".to_vec())).await.unwrap();
+        assert_eq!(wire, b"\x03SYN");
+    }
+
+    #[async_std::test]
+    async fn size_limits_are_enforced() {
+        let mut codec = SynAckProtocol::codec();
+        let protocol = SerdeProtocol::with_encoding(TEST_PROTOCOL_LEGACY, Encoding::Raw);
+        let largest = vec![0; SynAckProtocol::MAX_REQUEST_SIZE];
+        let mut wire = Vec::new();
+        codec.write_request(&protocol, &mut Cursor::new(&mut wire), SYN(largest.clone())).await.unwrap();
+        assert_eq!(codec.read_request(&protocol, &mut Cursor::new(wire)).await.unwrap(), SYN(largest));
+        let oversized = vec![0; SynAckProtocol::MAX_RESPONSE_SIZE + 1];
+        let error = codec
+            .write_response(&protocol, &mut Cursor::new(&mut Vec::new()), SYNACK(oversized.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        // Written by a peer without the limit.
+        let mut wire = Vec::new();
+        libp2p::core::upgrade::write_length_prefixed(&mut Cursor::new(&mut wire), oversized).await.unwrap();
+        assert!(codec.read_response(&protocol, &mut Cursor::new(wire)).await.is_err());
+        assert!(codec.read_request(&protocol, &mut Cursor::new(vec![0])).await.is_err());
+    }
+}
@@ -0,0 +1,159 @@
+// Address hygiene for the addresses peers advertise through identify.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressScope {
+    Unspecified,
+    Loopback,
+    LinkLocal,
+    Private,
+    Public,
+}
+
+pub fn address_scope(address: &Multiaddr) -> AddressScope {
+    match address.iter().next() {
+        Some(Protocol::Ip4(ip)) => ipv4_scope(&ip),
+        Some(Protocol::Ip6(ip)) => ipv6_scope(&ip),
+        Some(Protocol::Dns(name)) | Some(Protocol::Dns4(name)) | Some(Protocol::Dns6(name)) | Some(Protocol::Dnsaddr(name)) => {
+            if name == "localhost" || name.ends_with(".localhost") {
+                AddressScope::Loopback
+            } else {
+                AddressScope::Public
+            }
+        },
+        _ => AddressScope::Unspecified,
+    }
+}
+
+fn ipv4_scope(ip: &Ipv4Addr) -> AddressScope {
+    let octets = ip.octets();
+    if ip.is_unspecified() || ip.is_broadcast() {
+        AddressScope::Unspecified
+    } else if ip.is_loopback() {
+        AddressScope::Loopback
+    } else if ip.is_link_local() {
+        AddressScope::LinkLocal
+    // 100.64.0.0/10 is the carrier-grade NAT shared address space.
+    } else if ip.is_private() || (octets[0] == 100 && (octets[1] & 0b1100_0000) == 64) {
+        AddressScope::Private
+    } else {
+        AddressScope::Public
+    }
+}
+
+fn ipv6_scope(ip: &Ipv6Addr) -> AddressScope {
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return ipv4_scope(&ipv4);
+    }
+    let first_segment = ip.segments()[0];
+    if ip.is_unspecified() {
+        AddressScope::Unspecified
+    } else if ip.is_loopback() {
+        AddressScope::Loopback
+    // fe80::/10
+    } else if (first_segment & 0xffc0) == 0xfe80 {
+        AddressScope::LinkLocal
+    // Unique local addresses, fc00::/7.
+    } else if (first_segment & 0xfe00) == 0xfc00 {
+        AddressScope::Private
+    } else {
+        AddressScope::Public
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddressPolicy {
+    pub allow_loopback: bool,
+    pub allow_private: bool,
+    pub allow_link_local: bool,
+}
+
+impl AddressPolicy {
+    // Only globally routable addresses, the default for public networks.
+    pub fn public() -> Self {
+        AddressPolicy::default()
+    }
+    // Networks whose peers share a LAN.
+    pub fn lan() -> Self {
+        AddressPolicy {
+            allow_loopback: false,
+            allow_private: true,
+            allow_link_local: true,
+        }
+    }
+    // Keeps every specified address, for tests and single host setups.
+    pub fn permissive() -> Self {
+        AddressPolicy {
+            allow_loopback: true,
+            allow_private: true,
+            allow_link_local: true,
+        }
+    }
+    pub fn allows(&self, address: &Multiaddr) -> bool {
+        match address_scope(address) {
+            AddressScope::Unspecified => false,
+            AddressScope::Loopback => self.allow_loopback,
+            AddressScope::LinkLocal => self.allow_link_local,
+            AddressScope::Private => self.allow_private,
+            AddressScope::Public => true,
+        }
+    }
+    // Addresses of a peer worth adding to the routing table. Peers reached over a public
+    // address only keep the addresses allowed by the policy, peers reached over a local
+    // address share our network so their local addresses are kept as well.
+    pub fn filter(&self, reached_over: Option<&Multiaddr>, addresses: &[Multiaddr]) -> Vec<Multiaddr> {
+        let reached_publicly = reached_over.map_or(true, |address| address_scope(address) == AddressScope::Public);
+        addresses
+            .iter()
+            .filter(|address| {
+                if reached_publicly {
+                    self.allows(address)
+                } else {
+                    address_scope(address) != AddressScope::Unspecified
+                }
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn classifies_addresses() {
+        assert_eq!(address_scope(&addr("/ip4/127.0.0.1/tcp/30333")), AddressScope::Loopback);
+        assert_eq!(address_scope(&addr("/ip4/192.168.1.10/tcp/30333")), AddressScope::Private);
+        assert_eq!(address_scope(&addr("/ip4/100.72.0.1/tcp/30333")), AddressScope::Private);
+        assert_eq!(address_scope(&addr("/ip4/169.254.3.4/tcp/30333")), AddressScope::LinkLocal);
+        assert_eq!(address_scope(&addr("/ip4/0.0.0.0/tcp/30333")), AddressScope::Unspecified);
+        assert_eq!(address_scope(&addr("/ip4/1.1.1.1/tcp/30333")), AddressScope::Public);
+        assert_eq!(address_scope(&addr("/ip6/fe80::1/tcp/30333")), AddressScope::LinkLocal);
+        assert_eq!(address_scope(&addr("/ip6/fd00::1/tcp/30333")), AddressScope::Private);
+        assert_eq!(address_scope(&addr("/ip6/2001:db8::1/tcp/30333")), AddressScope::Public);
+        assert_eq!(address_scope(&addr("/dns/p2p.cc3-0.kusama.network/tcp/30100")), AddressScope::Public);
+    }
+
+    #[test]
+    fn filters_by_reachability() {
+        let addresses = vec![
+            addr("/ip4/127.0.0.1/tcp/30333"),
+            addr("/ip4/10.0.0.7/tcp/30333"),
+            addr("/ip4/1.2.3.4/tcp/30333"),
+            addr("/ip4/0.0.0.0/tcp/30333"),
+        ];
+        let public = addr("/ip4/1.2.3.4/tcp/30333");
+        let private = addr("/ip4/10.0.0.7/tcp/30333");
+        assert_eq!(AddressPolicy::public().filter(Some(&public), &addresses), vec![addresses[2].clone()]);
+        assert_eq!(AddressPolicy::lan().filter(Some(&public), &addresses), addresses[1..3].to_vec());
+        assert_eq!(AddressPolicy::public().filter(Some(&private), &addresses), addresses[..3].to_vec());
+    }
+}
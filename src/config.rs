@@ -0,0 +1,396 @@
+// Typed configuration of a LookupClient.
+
+use std::num::NonZeroUsize;
+use std::time::Duration;
+use libp2p_kad::KademliaConfig;
+use libp2p::swarm::ConnectionLimits;
+use thiserror::Error;
+#[cfg(feature = "test-protocol")]
+use crate::{SerdeProtocol, SizeLimits, TestProtocol};
+use crate::{AddressPolicy, ConnectionGate, EvictionPolicy, KademliaMode, ReputationConfig, TransportKind};
+
+#[derive(Debug, Clone)]
+pub struct KademliaSettings {
+    // Number of peers a record is replicated to and a closest peers query looks for. The
+    // k-buckets keep the fixed size of `libp2p_kad::K_VALUE` whatever the value.
+    pub replication_factor: usize,
+    // Number of concurrent requests of an iterative query (alpha).
+    pub parallelism: usize,
+    pub query_timeout: Duration,
+    // `None` means records never expire.
+    pub record_ttl: Option<Duration>,
+    pub provider_record_ttl: Option<Duration>,
+    // `None` disables the periodic replication or publication.
+    pub replication_interval: Option<Duration>,
+    pub publication_interval: Option<Duration>,
+    pub provider_publication_interval: Option<Duration>,
+    pub connection_idle_timeout: Duration,
+}
+
+impl Default for KademliaSettings {
+    // Same values as `KademliaConfig::default()`.
+    fn default() -> Self {
+        KademliaSettings {
+            replication_factor: 20,
+            parallelism: 3,
+            query_timeout: Duration::from_secs(60),
+            record_ttl: Some(Duration::from_secs(36 * 60 * 60)),
+            provider_record_ttl: Some(Duration::from_secs(24 * 60 * 60)),
+            replication_interval: Some(Duration::from_secs(60 * 60)),
+            publication_interval: Some(Duration::from_secs(24 * 60 * 60)),
+            provider_publication_interval: Some(Duration::from_secs(12 * 60 * 60)),
+            connection_idle_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl KademliaSettings {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.replication_factor == 0 {
+            return Err(ConfigError::Zero("replication_factor"));
+        }
+        if self.parallelism == 0 {
+            return Err(ConfigError::Zero("parallelism"));
+        }
+        if self.parallelism > self.replication_factor {
+            return Err(ConfigError::ParallelismAboveReplication {
+                parallelism: self.parallelism,
+                replication_factor: self.replication_factor,
+            });
+        }
+        let durations = [
+            ("query_timeout", Some(self.query_timeout)),
+            ("record_ttl", self.record_ttl),
+            ("provider_record_ttl", self.provider_record_ttl),
+            ("replication_interval", self.replication_interval),
+            ("publication_interval", self.publication_interval),
+            ("provider_publication_interval", self.provider_publication_interval),
+            ("connection_idle_timeout", Some(self.connection_idle_timeout)),
+        ];
+        if let Some((name, _)) = durations.iter().find(|(_, duration)| *duration == Some(Duration::ZERO)) {
+            return Err(ConfigError::Zero(*name));
+        }
+        // Records have to be re-published before they expire and replicated more often than
+        // they are re-published, otherwise they silently vanish from the network.
+        Self::check_below("replication_interval", self.replication_interval, "publication_interval", self.publication_interval)?;
+        Self::check_below("publication_interval", self.publication_interval, "record_ttl", self.record_ttl)?;
+        Self::check_below("provider_publication_interval", self.provider_publication_interval, "provider_record_ttl", self.provider_record_ttl)?;
+        Ok(())
+    }
+    fn check_below(name: &'static str, value: Option<Duration>, bound_name: &'static str, bound: Option<Duration>) -> Result<(), ConfigError> {
+        match (value, bound) {
+            (Some(value), Some(bound)) if value >= bound => Err(ConfigError::NotBelow { name, bound_name }),
+            _ => Ok(()),
+        }
+    }
+    // Protocol names and the idle timeout are applied by `SwitchableKademlia`.
+    pub(crate) fn to_kademlia_config(&self) -> KademliaConfig {
+        let mut config = KademliaConfig::default();
+        config
+            .set_replication_factor(NonZeroUsize::new(self.replication_factor).expect("Validated replication factor."))
+            .set_parallelism(NonZeroUsize::new(self.parallelism).expect("Validated parallelism."))
+            .set_query_timeout(self.query_timeout)
+            .set_record_ttl(self.record_ttl)
+            .set_provider_record_ttl(self.provider_record_ttl)
+            .set_replication_interval(self.replication_interval)
+            .set_publication_interval(self.publication_interval)
+            .set_provider_publication_interval(self.provider_publication_interval);
+        config
+    }
+}
+
+// Unlimited by default, like the swarm without connection limits.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionLimitSettings {
+    // `None` lifts the corresponding limit.
+    pub max_pending_incoming: Option<u32>,
+    pub max_pending_outgoing: Option<u32>,
+    pub max_established_incoming: Option<u32>,
+    pub max_established_outgoing: Option<u32>,
+    pub max_established_per_peer: Option<u32>,
+}
+
+impl ConnectionLimitSettings {
+    pub fn unlimited() -> Self {
+        ConnectionLimitSettings::default()
+    }
+    // Every connection is kept alive, these limits stay well below the usual file
+    // descriptor limit of 1024.
+    pub fn conservative() -> Self {
+        ConnectionLimitSettings {
+            max_pending_incoming: Some(32),
+            max_pending_outgoing: Some(64),
+            max_established_incoming: Some(128),
+            max_established_outgoing: Some(256),
+            max_established_per_peer: Some(2),
+        }
+    }
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let limits = [
+            ("max_pending_incoming", self.max_pending_incoming),
+            ("max_pending_outgoing", self.max_pending_outgoing),
+            ("max_established_incoming", self.max_established_incoming),
+            ("max_established_outgoing", self.max_established_outgoing),
+            ("max_established_per_peer", self.max_established_per_peer),
+        ];
+        if let Some((name, _)) = limits.iter().find(|(_, limit)| *limit == Some(0)) {
+            return Err(ConfigError::Zero(*name));
+        }
+        Ok(())
+    }
+    pub(crate) fn to_connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits::default()
+            .with_max_pending_incoming(self.max_pending_incoming)
+            .with_max_pending_outgoing(self.max_pending_outgoing)
+            .with_max_established_incoming(self.max_established_incoming)
+            .with_max_established_outgoing(self.max_established_outgoing)
+            .with_max_established_per_peer(self.max_established_per_peer)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LookupConfig {
+    pub kademlia_mode: KademliaMode,
+    pub kademlia: KademliaSettings,
+    // Which identify addresses are fed into the routing table.
+    pub address_policy: AddressPolicy,
+    pub peer_store: EvictionPolicy,
+    // Transports tried first when a peer has several addresses.
+    pub transport_preference: Vec<TransportKind>,
+    pub reputation: ReputationConfig,
+    // Number of ping round trips kept per peer for the latency statistics.
+    pub latency_window: usize,
+    pub connection_limits: ConnectionLimitSettings,
+    // Time `LookupClient::dial` waits for the connection.
+    pub dial_timeout: Duration,
+    // Initial gate rules, updatable at runtime through `LookupClient::update_gate`.
+    pub gate: ConnectionGate,
+    // Message size limits of the SYN/SYNACK handshake protocol.
+    #[cfg(feature = "test-protocol")]
+    pub handshake_limits: SizeLimits,
+    // Handshake protocol versions offered and accepted, in order of preference.
+    #[cfg(feature = "test-protocol")]
+    pub handshake_versions: Vec<SerdeProtocol>,
+    // Time a handshake may wait for the next message before it is abandoned.
+    pub handshake_timeout: Duration,
+    // Maximum clock skew accepted on handshake timestamps. Nonces are remembered for as long.
+    pub handshake_replay_window: Duration,
+    // Handshakes answered concurrently by a responder.
+    pub max_handshake_sessions: usize,
+    // Requests given to the request handlers at once, further ones are refused as busy.
+    pub max_handled_requests: usize,
+    // Time a request handler has to answer before the request fails.
+    pub request_handler_timeout: Duration,
+    // Data carried by each file transfer request.
+    #[cfg(feature = "file-transfer")]
+    pub transfer_chunk_size: usize,
+    // Whether received files may replace the files of the served directory.
+    #[cfg(feature = "file-transfer")]
+    pub transfer_overwrite: bool,
+}
+
+impl Default for LookupConfig {
+    fn default() -> Self {
+        LookupConfig {
+            kademlia_mode: KademliaMode::default(),
+            kademlia: KademliaSettings::default(),
+            address_policy: AddressPolicy::default(),
+            peer_store: EvictionPolicy::default(),
+            transport_preference: TransportKind::default_preference(),
+            reputation: ReputationConfig::default(),
+            latency_window: 16,
+            connection_limits: ConnectionLimitSettings::default(),
+            dial_timeout: Duration::from_secs(30),
+            gate: ConnectionGate::default(),
+            #[cfg(feature = "test-protocol")]
+            handshake_limits: TestProtocol::limits(),
+            #[cfg(feature = "test-protocol")]
+            handshake_versions: crate::test_protocol_versions(),
+            handshake_timeout: Duration::from_secs(30),
+            handshake_replay_window: Duration::from_secs(60),
+            max_handshake_sessions: 64,
+            max_handled_requests: 64,
+            request_handler_timeout: Duration::from_secs(30),
+            #[cfg(feature = "file-transfer")]
+            transfer_chunk_size: 64 * 1024,
+            #[cfg(feature = "file-transfer")]
+            transfer_overwrite: false,
+        }
+    }
+}
+
+impl LookupConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.kademlia.validate()?;
+        self.peer_store.validate()?;
+        self.reputation.validate()?;
+        if self.latency_window == 0 {
+            return Err(ConfigError::Zero("latency_window"));
+        }
+        let durations = [
+            ("dial_timeout", self.dial_timeout),
+            ("handshake_timeout", self.handshake_timeout),
+            ("handshake_replay_window", self.handshake_replay_window),
+            ("request_handler_timeout", self.request_handler_timeout),
+        ];
+        if let Some((name, _)) = durations.iter().find(|(_, duration)| *duration == Duration::ZERO) {
+            return Err(ConfigError::Zero(*name));
+        }
+        if self.max_handshake_sessions == 0 {
+            return Err(ConfigError::Zero("max_handshake_sessions"));
+        }
+        if self.max_handled_requests == 0 {
+            return Err(ConfigError::Zero("max_handled_requests"));
+        }
+        #[cfg(feature = "test-protocol")]
+        if self.handshake_versions.is_empty() {
+            return Err(ConfigError::Empty("handshake_versions"));
+        }
+        #[cfg(feature = "file-transfer")]
+        if self.transfer_chunk_size == 0 {
+            return Err(ConfigError::Zero("transfer_chunk_size"));
+        }
+        self.connection_limits.validate()
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("{0} must not be zero")]
+    Zero(&'static str),
+    #[error("{0} must not be empty")]
+    Empty(&'static str),
+    #[error("{0} must not be positive")]
+    Positive(&'static str),
+    #[error("{0} must be negative")]
+    NotNegative(&'static str),
+    #[error("parallelism ({parallelism}) exceeds the replication factor ({replication_factor})")]
+    ParallelismAboveReplication {
+        parallelism: usize,
+        replication_factor: usize,
+    },
+    #[error("{name} must be shorter than {bound_name}")]
+    NotBelow {
+        name: &'static str,
+        bound_name: &'static str,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_settings_are_valid() {
+        assert_eq!(LookupConfig::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn invalid_combinations_are_rejected() {
+        let settings = KademliaSettings {
+            parallelism: 30,
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.validate(),
+            Err(ConfigError::ParallelismAboveReplication { parallelism: 30, replication_factor: 20 })
+        );
+        let settings = KademliaSettings {
+            publication_interval: Some(Duration::from_secs(48 * 60 * 60)),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.validate(),
+            Err(ConfigError::NotBelow { name: "publication_interval", bound_name: "record_ttl" })
+        );
+        let settings = KademliaSettings {
+            query_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        assert_eq!(settings.validate(), Err(ConfigError::Zero("query_timeout")));
+        // Disabling expiry lifts the bound on the publication interval.
+        let settings = KademliaSettings {
+            record_ttl: None,
+            publication_interval: Some(Duration::from_secs(48 * 60 * 60)),
+            ..Default::default()
+        };
+        assert_eq!(settings.validate(), Ok(()));
+        let limits = ConnectionLimitSettings {
+            max_established_per_peer: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(limits.validate(), Err(ConfigError::Zero("max_established_per_peer")));
+        assert_eq!(ConnectionLimitSettings::unlimited().validate(), Ok(()));
+        assert_eq!(ConnectionLimitSettings::conservative().validate(), Ok(()));
+    }
+
+    #[test]
+    fn every_section_is_validated() {
+        let config = LookupConfig {
+            latency_window: 0,
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::Zero("latency_window")));
+        let config = LookupConfig {
+            handshake_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::Zero("handshake_timeout")));
+        let config = LookupConfig {
+            handshake_replay_window: Duration::ZERO,
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::Zero("handshake_replay_window")));
+        let config = LookupConfig {
+            max_handled_requests: 0,
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::Zero("max_handled_requests")));
+        let config = LookupConfig {
+            request_handler_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::Zero("request_handler_timeout")));
+        let config = LookupConfig {
+            peer_store: EvictionPolicy {
+                max_peers: Some(0),
+                max_age: None,
+            },
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::Zero("max_peers")));
+        let config = LookupConfig {
+            reputation: ReputationConfig {
+                ping_timeout: 5.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::Positive("ping_timeout")));
+        let config = LookupConfig {
+            reputation: ReputationConfig {
+                ban_threshold: 0.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::NotNegative("ban_threshold")));
+        let config = LookupConfig {
+            reputation: ReputationConfig {
+                half_life: Duration::ZERO,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::Zero("half_life")));
+        // No limit at all is fine.
+        let config = LookupConfig {
+            peer_store: EvictionPolicy {
+                max_peers: None,
+                max_age: None,
+            },
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+    }
+}
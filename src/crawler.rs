@@ -0,0 +1,668 @@
+// DHT crawler producing a census of the peers reachable through the network's Kademlia DHT.
+//
+// The keyspace is walked with `get_closest_peers` queries towards one target in every
+// k-bucket of the local key. Every discovered peer is dialed and identified,
+// and the outcome is recorded in a `Census` that can be exported as JSON or CSV. The
+// crawl state can be checkpointed to a file and resumed later.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use libp2p::swarm::{
+    dial_opts::{DialOpts, PeerCondition},
+    DialError,
+    SwarmEvent
+};
+use libp2p::{identify, Multiaddr};
+use libp2p_core::PeerId;
+use libp2p_kad::{
+    kbucket,
+    GetClosestPeersError,
+    GetClosestPeersOk,
+    KademliaEvent,
+    QueryId,
+    QueryResult
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use crate::{serde_util, LookupBehaviourEvent, LookupClient, LookupSwarmEvent};
+
+// Kademlia key of a peer, the SHA-256 digest of its id as in `kbucket::Key::from`.
+type KeyBits = [u8; 32];
+
+#[derive(Debug, Clone)]
+pub struct CrawlerConfig {
+    // Maximum number of `get_closest_peers` queries running at the same time.
+    pub max_concurrent_queries: usize,
+    // Maximum number of dials initiated by the crawler at the same time.
+    pub max_concurrent_dials: usize,
+    // Number of buckets (counted from the farthest one) that receive a target per round.
+    pub buckets: u32,
+    // Number of passes over the buckets.
+    pub rounds: usize,
+    // Overall deadline of the crawl.
+    pub timeout: Duration,
+    // How long an identified peer stays connected before the crawler closes the connection.
+    pub linger: Duration,
+    // File used to checkpoint and resume the crawl.
+    pub state_file: Option<PathBuf>,
+    // Number of census updates between two checkpoints.
+    pub checkpoint_interval: usize,
+}
+
+impl Default for CrawlerConfig {
+    fn default() -> Self {
+        CrawlerConfig {
+            max_concurrent_queries: 4,
+            max_concurrent_dials: 32,
+            buckets: 10,
+            rounds: 3,
+            timeout: Duration::from_secs(600),
+            linger: Duration::from_secs(10),
+            state_file: None,
+            checkpoint_interval: 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Reachability {
+    Unknown,
+    Reachable,
+    Unreachable,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CensusEntry {
+    #[serde(with = "serde_util::peer_id")]
+    pub peer_id: PeerId,
+    pub agent_version: Option<String>,
+    pub protocol_version: Option<String>,
+    pub protocols: Vec<String>,
+    #[serde(with = "serde_util::multiaddrs")]
+    pub listen_addrs: Vec<Multiaddr>,
+    pub reachability: Reachability,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub identified_at: Option<DateTime<Utc>>,
+}
+
+impl CensusEntry {
+    fn new(peer_id: PeerId) -> Self {
+        let now = Utc::now();
+        CensusEntry {
+            peer_id,
+            agent_version: None,
+            protocol_version: None,
+            protocols: Vec::new(),
+            listen_addrs: Vec::new(),
+            reachability: Reachability::Unknown,
+            first_seen: now,
+            last_seen: now,
+            identified_at: None,
+        }
+    }
+    fn is_identified(&self) -> bool {
+        self.identified_at.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Census {
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub rounds_completed: usize,
+    pub entries: Vec<CensusEntry>,
+}
+
+impl Census {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "peer_id,agent_version,protocol_version,protocols,listen_addrs,reachability,first_seen,last_seen,identified_at\n"
+        );
+        for entry in &self.entries {
+            let protocols = entry.protocols.join(" ");
+            let listen_addrs = entry.listen_addrs
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<_>>()
+                .join(" ");
+            let reachability = match entry.reachability {
+                Reachability::Unknown => "unknown",
+                Reachability::Reachable => "reachable",
+                Reachability::Unreachable => "unreachable",
+            };
+            let fields = [
+                entry.peer_id.to_base58(),
+                entry.agent_version.clone().unwrap_or_default(),
+                entry.protocol_version.clone().unwrap_or_default(),
+                protocols,
+                listen_addrs,
+                reachability.to_string(),
+                entry.first_seen.to_rfc3339(),
+                entry.last_seen.to_rfc3339(),
+                entry.identified_at.map(|t| t.to_rfc3339()).unwrap_or_default(),
+            ];
+            let row = fields.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(",");
+            csv.push_str(&row);
+            csv.push('\n');
+        }
+        csv
+    }
+    pub fn write_json<P: AsRef<Path>>(&self, path: P) -> Result<(), CrawlError> {
+        std::fs::write(path, self.to_json()?)?;
+        Ok(())
+    }
+    pub fn write_csv<P: AsRef<Path>>(&self, path: P) -> Result<(), CrawlError> {
+        std::fs::write(path, self.to_csv())?;
+        Ok(())
+    }
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CrawlError> {
+        let encoded = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&encoded)?)
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CrawlError {
+    #[error("State file error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("State encoding error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+pub struct Crawler<'a> {
+    client: &'a mut LookupClient,
+    config: CrawlerConfig,
+    census: HashMap<PeerId, CensusEntry>,
+    started_at: DateTime<Utc>,
+    rounds_completed: usize,
+    targets: VecDeque<PeerId>,
+    queries: HashSet<QueryId>,
+    to_dial: VecDeque<PeerId>,
+    dialing: HashSet<PeerId>,
+    known_addrs: HashMap<PeerId, Vec<Multiaddr>>,
+    lingering: HashMap<PeerId, Instant>,
+    updates_since_checkpoint: usize,
+}
+
+impl<'a> Crawler<'a> {
+    pub fn new(client: &'a mut LookupClient, config: CrawlerConfig) -> Result<Self, CrawlError> {
+        let mut census = HashMap::new();
+        let mut started_at = Utc::now();
+        let mut rounds_completed = 0;
+        if let Some(state_file) = config.state_file.as_ref().filter(|path| path.exists()) {
+            let state = Census::load(state_file)?;
+            println!("Resuming crawl with {} known peers.", state.entries.len());
+            started_at = state.started_at.unwrap_or(started_at);
+            rounds_completed = state.rounds_completed;
+            for entry in state.entries {
+                census.insert(entry.peer_id, entry);
+            }
+        }
+        let mut crawler = Crawler {
+            client,
+            config,
+            census,
+            started_at,
+            rounds_completed,
+            targets: VecDeque::new(),
+            queries: HashSet::new(),
+            to_dial: VecDeque::new(),
+            dialing: HashSet::new(),
+            known_addrs: HashMap::new(),
+            lingering: HashMap::new(),
+            updates_since_checkpoint: 0,
+        };
+        // Peers recorded by a previous run but never identified get another chance.
+        let pending: Vec<PeerId> = crawler.census
+            .values()
+            .filter(|entry| !entry.is_identified() && entry.reachability != Reachability::Unreachable)
+            .map(|entry| entry.peer_id)
+            .collect();
+        crawler.to_dial.extend(pending);
+        Ok(crawler)
+    }
+
+    pub async fn run(mut self) -> Result<Census, CrawlError> {
+        let deadline = Instant::now() + self.config.timeout;
+        if self.rounds_completed < self.config.rounds {
+            self.targets = self.bucket_targets();
+        }
+        loop {
+            self.start_queries();
+            self.start_dials();
+            self.close_lingering();
+            if self.targets.is_empty() && self.queries.is_empty() && self.to_dial.is_empty() && self.dialing.is_empty() {
+                self.rounds_completed += 1;
+                println!("Crawl round {} completed, {} peers known.", self.rounds_completed, self.census.len());
+                self.checkpoint()?;
+                if self.rounds_completed >= self.config.rounds {
+                    break;
+                }
+                self.targets = self.bucket_targets();
+                continue;
+            }
+            let remaining = match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) => remaining,
+                None => {
+                    println!("Crawl deadline reached.");
+                    break;
+                }
+            };
+            // Wake up regularly so lingering connections get closed even when the network is quiet.
+            let wait = remaining.min(self.config.linger);
+            let event = match async_std::future::timeout(wait, self.client.next_event()).await {
+                Ok(event) => event,
+                Err(_) => continue,
+            };
+            self.on_event(event);
+            if self.updates_since_checkpoint >= self.config.checkpoint_interval {
+                self.checkpoint()?;
+            }
+        }
+        let census = self.census();
+        if let Some(state_file) = &self.config.state_file {
+            census.write_json(state_file)?;
+        }
+        Ok(census)
+    }
+
+    pub fn census(&self) -> Census {
+        let mut entries: Vec<CensusEntry> = self.census.values().cloned().collect();
+        entries.sort_by_key(|entry| entry.peer_id.to_base58());
+        Census {
+            started_at: Some(self.started_at),
+            finished_at: Some(Utc::now()),
+            rounds_completed: self.rounds_completed,
+            entries,
+        }
+    }
+
+    fn checkpoint(&mut self) -> Result<(), CrawlError> {
+        self.updates_since_checkpoint = 0;
+        if let Some(state_file) = &self.config.state_file {
+            let mut census = self.census();
+            census.finished_at = None;
+            census.write_json(state_file)?;
+        }
+        Ok(())
+    }
+
+    fn bucket_targets(&mut self) -> VecDeque<PeerId> {
+        let mut known: HashSet<PeerId> = self.census.keys().cloned().collect();
+        for bucket in self.client.routing_table() {
+            known.extend(bucket.entries.iter().map(|entry| entry.peer_id));
+        }
+        bucket_targets(&self.client.local_peer_id, self.config.buckets, known)
+    }
+
+    fn start_queries(&mut self) {
+        while self.queries.len() < self.config.max_concurrent_queries {
+            let target = match self.targets.pop_front() {
+                Some(target) => target,
+                None => break,
+            };
+            let query_id = self.client.swarm.behaviour_mut().kademlia.get_closest_peers(target);
+            self.queries.insert(query_id);
+        }
+    }
+
+    fn start_dials(&mut self) {
+        while self.dialing.len() < self.config.max_concurrent_dials {
+            let peer_id = match self.to_dial.pop_front() {
+                Some(peer_id) => peer_id,
+                None => break,
+            };
+            if self.census.get(&peer_id).map_or(false, |entry| entry.is_identified()) {
+                continue;
+            }
+            let addresses = self.known_addrs.get(&peer_id).cloned().unwrap_or_default();
+            let opts = DialOpts::peer_id(peer_id)
+                .condition(PeerCondition::Disconnected)
+                .addresses(addresses)
+                .extend_addresses_through_behaviour()
+                .build();
+            match self.client.swarm.dial(opts) {
+                Ok(()) => {
+                    self.dialing.insert(peer_id);
+                },
+                // Already connected, identify is on its way.
+                Err(DialError::DialPeerConditionFalse(_)) => {},
+                Err(DialError::NoAddresses) => {
+                    println!("No known addresses for {:?}.", peer_id);
+                },
+                // Out of connection slots, retried once a pending dial completes.
+                Err(DialError::ConnectionLimit(limit)) => {
+                    println!("Dial to {:?} postponed : {}", peer_id, limit);
+                    self.to_dial.push_front(peer_id);
+                    break;
+                },
+                Err(e) => {
+                    println!("Dial to {:?} failed : {:?}", peer_id, e);
+                    self.entry(peer_id).reachability = Reachability::Unreachable;
+                },
+            }
+        }
+    }
+
+    fn close_lingering(&mut self) {
+        let linger = self.config.linger;
+        let expired: Vec<PeerId> = self.lingering
+            .iter()
+            .filter(|(_, since)| since.elapsed() >= linger)
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in expired {
+            self.lingering.remove(&peer_id);
+            self.client.disconnect(peer_id);
+        }
+    }
+
+    fn entry(&mut self, peer_id: PeerId) -> &mut CensusEntry {
+        let entry = self.census.entry(peer_id).or_insert_with(|| CensusEntry::new(peer_id));
+        entry.last_seen = Utc::now();
+        entry
+    }
+
+    fn discovered(&mut self, peer_id: PeerId) {
+        if peer_id == self.client.local_peer_id {
+            return;
+        }
+        let is_new = !self.census.contains_key(&peer_id);
+        self.entry(peer_id);
+        if is_new {
+            self.updates_since_checkpoint += 1;
+            self.to_dial.push_back(peer_id);
+        }
+    }
+
+    fn on_event(&mut self, event: LookupSwarmEvent) {
+        match event {
+            SwarmEvent::Behaviour(LookupBehaviourEvent::Kademlia(
+                KademliaEvent::RoutingUpdated { peer, addresses, .. }
+            )) => {
+                self.known_addrs.insert(peer, addresses.into_vec());
+                self.discovered(peer);
+            },
+            SwarmEvent::Behaviour(LookupBehaviourEvent::Kademlia(
+                KademliaEvent::OutboundQueryCompleted { id, result: QueryResult::GetClosestPeers(result), .. }
+            )) => {
+                if !self.queries.remove(&id) {
+                    return;
+                }
+                let peers = match result {
+                    Ok(GetClosestPeersOk { peers, .. }) => peers,
+                    Err(GetClosestPeersError::Timeout { peers, .. }) => peers,
+                };
+                for peer_id in peers {
+                    self.discovered(peer_id);
+                }
+            },
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                self.discovered(peer_id);
+                self.entry(peer_id).reachability = Reachability::Reachable;
+            },
+            SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error } => {
+                if self.dialing.remove(&peer_id) {
+                    let entry = self.entry(peer_id);
+                    if entry.reachability != Reachability::Reachable {
+                        entry.reachability = match error {
+                            DialError::NoAddresses => Reachability::Unknown,
+                            _ => Reachability::Unreachable,
+                        };
+                    }
+                    self.updates_since_checkpoint += 1;
+                }
+            },
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                self.dialing.remove(&peer_id);
+                self.lingering.remove(&peer_id);
+            },
+            SwarmEvent::Behaviour(LookupBehaviourEvent::Identify(
+                identify::Event::Received { peer_id, info }
+            )) => {
+                self.discovered(peer_id);
+                let entry = self.entry(peer_id);
+                entry.agent_version = Some(info.agent_version);
+                entry.protocol_version = Some(info.protocol_version);
+                entry.protocols = info.protocols;
+                entry.listen_addrs = info.listen_addrs;
+                entry.reachability = Reachability::Reachable;
+                entry.identified_at = Some(Utc::now());
+                self.dialing.remove(&peer_id);
+                self.lingering.entry(peer_id).or_insert_with(Instant::now);
+                self.updates_since_checkpoint += 1;
+            },
+            SwarmEvent::Behaviour(LookupBehaviourEvent::Identify(
+                identify::Event::Error { peer_id, error }
+            )) => {
+                println!("Identify with {:?} failed : {:?}", peer_id, error);
+                self.dialing.remove(&peer_id);
+                self.lingering.entry(peer_id).or_insert_with(Instant::now);
+            },
+            _ => {}
+        }
+    }
+}
+
+fn key_bits(peer_id: &PeerId) -> KeyBits {
+    Sha256::digest(peer_id.to_bytes()).into()
+}
+
+fn distance(a: &KeyBits, b: &KeyBits) -> KeyBits {
+    let mut distance = [0; 32];
+    for (byte, (a, b)) in distance.iter_mut().zip(a.iter().zip(b)) {
+        *byte = a ^ b;
+    }
+    distance
+}
+
+// Same index as `Distance::ilog2`, `None` for the local key.
+fn bucket_index(local: &KeyBits, key: &KeyBits) -> Option<u32> {
+    let distance = distance(local, key);
+    let zeros = distance
+        .iter()
+        .position(|byte| *byte != 0)
+        .map(|position| position as u32 * 8 + distance[position].leading_zeros())?;
+    Some(255 - zeros)
+}
+
+// Random key in the bucket `index` of `local`: the higher bits are kept, the bit 255 - index
+// is flipped and the lower bits are random.
+fn bucket_key(local: &KeyBits, index: u32) -> KeyBits {
+    let bit = (255 - index.min(255)) as usize;
+    let (byte, offset) = (bit / 8, bit % 8);
+    let mut key: KeyBits = rand::random();
+    key[..byte].copy_from_slice(&local[..byte]);
+    let higher = !(0xffu8 >> offset);
+    let flipped = 0x80u8 >> offset;
+    key[byte] = (local[byte] & higher) | (!local[byte] & flipped) | (key[byte] & !(higher | flipped));
+    key
+}
+
+// One target per bucket, starting from the farthest bucket. Queries carry the preimage of
+// their key and SHA-256 can't be inverted, so the target of a bucket is the known peer
+// closest to a random key of that bucket. Buckets no peer is known in yet are covered by a
+// lookup of the local key, which walks through the closest buckets and fills the others for
+// the next round.
+fn bucket_targets(local_peer_id: &PeerId, buckets: u32, known: impl IntoIterator<Item = PeerId>) -> VecDeque<PeerId> {
+    let local = key_bits(local_peer_id);
+    let known: Vec<(PeerId, KeyBits)> = known
+        .into_iter()
+        .map(|peer_id| (peer_id, key_bits(&peer_id)))
+        .collect();
+    let mut targets = VecDeque::new();
+    for index in (256 - buckets.min(256)..256).rev() {
+        let key = bucket_key(&local, index);
+        let target = known
+            .iter()
+            .filter(|(_, bits)| bucket_index(&local, bits) == Some(index))
+            .min_by_key(|(_, bits)| distance(bits, &key))
+            .map_or(*local_peer_id, |(peer_id, _)| *peer_id);
+        if !targets.contains(&target) {
+            targets.push_back(target);
+        }
+    }
+    targets
+}
+
+impl LookupClient {
+    pub async fn crawl(&mut self, config: CrawlerConfig) -> Result<Census, CrawlError> {
+        Crawler::new(self, config)?.run().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Network;
+
+    fn entry(peer_id: PeerId, identified: bool, reachability: Reachability) -> CensusEntry {
+        let mut entry = CensusEntry::new(peer_id);
+        entry.reachability = reachability;
+        if identified {
+            entry.agent_version = Some("substrate-node, \"polkadot\"".to_string());
+            entry.protocols = vec!["/ipfs/id/1.0.0".to_string(), "/ipfs/ping/1.0.0".to_string()];
+            entry.listen_addrs = vec!["/ip4/10.0.0.1/tcp/30333".parse().unwrap()];
+            entry.identified_at = Some(Utc::now());
+        }
+        entry
+    }
+
+    fn temp_file(label: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("lookup-crawler-{}-{:016x}.json", label, rand::random::<u64>()))
+    }
+
+    #[test]
+    fn bucket_indices_match_kademlia() {
+        let local_peer_id = PeerId::random();
+        let local_key = kbucket::Key::from(local_peer_id);
+        for _ in 0..100 {
+            let peer_id = PeerId::random();
+            assert_eq!(
+                bucket_index(&key_bits(&local_peer_id), &key_bits(&peer_id)),
+                local_key.distance(&kbucket::Key::from(peer_id)).ilog2()
+            );
+        }
+        assert_eq!(bucket_index(&key_bits(&local_peer_id), &key_bits(&local_peer_id)), None);
+    }
+
+    #[test]
+    fn keys_fall_into_their_bucket() {
+        let local = key_bits(&PeerId::random());
+        for index in 0..256 {
+            assert_eq!(bucket_index(&local, &bucket_key(&local, index)), Some(index));
+        }
+    }
+
+    #[test]
+    fn every_bucket_gets_a_target() {
+        let local_peer_id = PeerId::random();
+        let local = key_bits(&local_peer_id);
+        let known: Vec<PeerId> = (0..64).map(|_| PeerId::random()).collect();
+        let targets = bucket_targets(&local_peer_id, 256, known.clone());
+        // The known peers of every bucket are queried, one bucket at a time.
+        let mut indices: Vec<u32> = known
+            .iter()
+            .map(|peer_id| bucket_index(&local, &key_bits(peer_id)).unwrap())
+            .collect();
+        indices.sort_unstable_by(|a, b| b.cmp(a));
+        indices.dedup();
+        let found: Vec<u32> = targets
+            .iter()
+            .filter(|target| **target != local_peer_id)
+            .map(|target| bucket_index(&local, &key_bits(target)).unwrap())
+            .collect();
+        assert_eq!(found, indices);
+        // The buckets no peer is known in share a lookup of the local key.
+        assert_eq!(targets.iter().filter(|target| **target == local_peer_id).count(), 1);
+        assert_eq!(bucket_targets(&local_peer_id, 256, Vec::new()), VecDeque::from(vec![local_peer_id]));
+    }
+
+    #[test]
+    fn csv_escapes_fields() {
+        let identified = PeerId::random();
+        let census = Census {
+            entries: vec![entry(identified, true, Reachability::Reachable)],
+            ..Default::default()
+        };
+        let csv = census.to_csv();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("peer_id,agent_version,protocol_version,protocols,listen_addrs,reachability,first_seen,last_seen,identified_at")
+        );
+        let row = lines.next().unwrap();
+        assert!(row.starts_with(&format!(
+            "{},\"substrate-node, \"\"polkadot\"\"\",,/ipfs/id/1.0.0 /ipfs/ping/1.0.0,/ip4/10.0.0.1/tcp/30333,reachable,",
+            identified.to_base58()
+        )));
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let census = Census {
+            started_at: Some(Utc::now()),
+            rounds_completed: 2,
+            entries: vec![
+                entry(PeerId::random(), true, Reachability::Reachable),
+                entry(PeerId::random(), false, Reachability::Unreachable),
+            ],
+            ..Default::default()
+        };
+        let path = temp_file("census");
+        census.write_json(&path).unwrap();
+        let loaded = Census::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.rounds_completed, 2);
+        assert_eq!(loaded.started_at, census.started_at);
+        let peers = |census: &Census| census.entries
+            .iter()
+            .map(|entry| (entry.peer_id, entry.reachability, entry.listen_addrs.clone(), entry.identified_at))
+            .collect::<Vec<_>>();
+        assert_eq!(peers(&loaded), peers(&census));
+    }
+
+    #[test]
+    fn crawls_resume_from_the_state_file() {
+        let (identified, pending, unreachable) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let state = Census {
+            rounds_completed: 1,
+            entries: vec![
+                entry(identified, true, Reachability::Reachable),
+                entry(pending, false, Reachability::Unknown),
+                entry(unreachable, false, Reachability::Unreachable),
+            ],
+            ..Default::default()
+        };
+        let path = temp_file("state");
+        state.write_json(&path).unwrap();
+        let mut client = LookupClient::new(&Network::Kusama);
+        let config = CrawlerConfig {
+            state_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let crawler = Crawler::new(&mut client, config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(crawler.rounds_completed, 1);
+        assert_eq!(crawler.census.len(), 3);
+        // Only the peer never identified nor found unreachable is dialed again.
+        assert_eq!(crawler.to_dial, VecDeque::from(vec![pending]));
+    }
+}
@@ -0,0 +1,219 @@
+// Address ranking for dialing peers with several known addresses.
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::DialError;
+use libp2p::Multiaddr;
+use crate::EvictionPolicy;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Tcp,
+    Quic,
+    // Addresses going through a relay circuit.
+    Relay,
+    Other,
+}
+
+impl TransportKind {
+    pub fn of(address: &Multiaddr) -> Self {
+        let mut kind = TransportKind::Other;
+        for protocol in address.iter() {
+            match protocol {
+                Protocol::P2pCircuit => return TransportKind::Relay,
+                Protocol::Quic => kind = TransportKind::Quic,
+                Protocol::Tcp(_) if kind == TransportKind::Other => kind = TransportKind::Tcp,
+                _ => {}
+            }
+        }
+        kind
+    }
+    // Order used when no preference is configured.
+    pub fn default_preference() -> Vec<TransportKind> {
+        vec![TransportKind::Tcp, TransportKind::Quic, TransportKind::Relay, TransportKind::Other]
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AddressStats {
+    pub successes: u32,
+    pub failures: u32,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    // Latest ping round trip over a connection to this address.
+    pub last_rtt: Option<Duration>,
+}
+
+impl AddressStats {
+    // The last dial over this address succeeded.
+    pub fn is_known_good(&self) -> bool {
+        match (self.last_success, self.last_failure) {
+            (Some(success), Some(failure)) => success > failure,
+            (Some(_), None) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    stats: AddressStats,
+    updated: DateTime<Utc>,
+}
+
+// Outcome of past dials per address. Bounded like the peer store, `max_peers` counts the
+// addresses here and the ones not updated within `max_age` are forgotten.
+#[derive(Debug, Clone, Default)]
+pub struct AddressBook {
+    entries: HashMap<Multiaddr, Entry>,
+    // Addresses ordered by their last update, the oldest first.
+    by_age: BTreeSet<(DateTime<Utc>, Multiaddr)>,
+    eviction: EvictionPolicy,
+}
+
+impl AddressBook {
+    pub fn new(eviction: EvictionPolicy) -> Self {
+        AddressBook {
+            entries: HashMap::new(),
+            by_age: BTreeSet::new(),
+            eviction,
+        }
+    }
+    pub fn record_success(&mut self, address: &Multiaddr) {
+        let now = Utc::now();
+        let stats = self.entry(address, now);
+        stats.successes += 1;
+        stats.last_success = Some(now);
+    }
+    pub fn record_failure(&mut self, address: &Multiaddr) {
+        let now = Utc::now();
+        let stats = self.entry(address, now);
+        stats.failures += 1;
+        stats.last_failure = Some(now);
+    }
+    pub fn record_rtt(&mut self, address: &Multiaddr, rtt: Duration) {
+        self.entry(address, Utc::now()).last_rtt = Some(rtt);
+    }
+    pub fn stats(&self, address: &Multiaddr) -> Option<&AddressStats> {
+        self.entries.get(address).map(|entry| &entry.stats)
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    fn entry(&mut self, address: &Multiaddr, now: DateTime<Utc>) -> &mut AddressStats {
+        // Room is made for new addresses only, updates leave the book as it is.
+        if !self.entries.contains_key(address) {
+            self.evict(now);
+        }
+        let entry = self.entries.entry(address.clone()).or_insert_with(|| Entry {
+            stats: AddressStats::default(),
+            updated: now,
+        });
+        self.by_age.remove(&(entry.updated, address.clone()));
+        self.by_age.insert((now, address.clone()));
+        entry.updated = now;
+        &mut entry.stats
+    }
+    // Makes room for one address, only the evicted entries are visited.
+    fn evict(&mut self, now: DateTime<Utc>) {
+        let max_age = self.eviction.max_age.and_then(|age| chrono::Duration::from_std(age).ok());
+        while let Some((updated, address)) = self.by_age.iter().next().cloned() {
+            let expired = max_age.map_or(false, |max_age| now - updated > max_age);
+            let full = self.eviction.max_peers.map_or(false, |max_addresses| self.entries.len() >= max_addresses);
+            if !expired && !full {
+                break;
+            }
+            self.by_age.remove(&(updated, address.clone()));
+            self.entries.remove(&address);
+        }
+    }
+    // Addresses that worked last time come first, then the preferred transports, then the
+    // addresses that failed the least and then the fastest ones. Duplicates are removed and
+    // ties keep their order.
+    pub fn rank(&self, addresses: &[Multiaddr], preference: &[TransportKind]) -> Vec<Multiaddr> {
+        let mut ranked: Vec<Multiaddr> = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            if !ranked.contains(address) {
+                ranked.push(address.clone());
+            }
+        }
+        let default_stats = AddressStats::default();
+        ranked.sort_by_key(|address| {
+            let stats = self.stats(address).unwrap_or(&default_stats);
+            let transport_rank = preference
+                .iter()
+                .position(|kind| *kind == TransportKind::of(address))
+                .unwrap_or(preference.len());
+            (!stats.is_known_good(), transport_rank, stats.failures, stats.last_rtt.unwrap_or(Duration::MAX))
+        });
+        ranked
+    }
+}
+
+// Addresses a failed dial attempt went through, with the reason each of them failed.
+pub(crate) fn failed_addresses(error: &DialError) -> Vec<(Multiaddr, String)> {
+    match error {
+        DialError::Transport(errors) => errors
+            .iter()
+            .map(|(address, error)| (address.clone(), error.to_string()))
+            .collect(),
+        DialError::WrongPeerId { endpoint, .. } => vec![(endpoint.get_remote_address().clone(), error.to_string())],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ranks_by_success_then_transport() {
+        let tcp = addr("/ip4/1.2.3.4/tcp/30333");
+        let quic = addr("/ip4/1.2.3.4/udp/30333/quic");
+        let relayed = addr("/ip4/5.6.7.8/tcp/4001/p2p/12D3KooWDgtynm4S9M3m6ZZhXYu2RrWKdvkCSScc25xKDVSg1Sjd/p2p-circuit");
+        let failing = addr("/ip4/9.9.9.9/tcp/30333");
+        let mut book = AddressBook::default();
+        book.record_failure(&failing);
+        let addresses = vec![relayed.clone(), failing.clone(), quic.clone(), tcp.clone(), tcp.clone()];
+        let preference = TransportKind::default_preference();
+        assert_eq!(book.rank(&addresses, &preference), vec![tcp.clone(), failing.clone(), quic.clone(), relayed.clone()]);
+        book.record_success(&relayed);
+        assert_eq!(book.rank(&addresses, &preference)[0], relayed);
+        let other_tcp = addr("/ip4/4.3.2.1/tcp/30333");
+        book.record_rtt(&other_tcp, Duration::from_millis(20));
+        book.record_rtt(&tcp, Duration::from_millis(80));
+        assert_eq!(book.rank(&[tcp.clone(), other_tcp.clone()], &preference), vec![other_tcp, tcp]);
+    }
+
+    #[test]
+    fn evicts_by_size_and_age() {
+        let mut book = AddressBook::new(EvictionPolicy {
+            max_peers: Some(2),
+            max_age: Some(Duration::from_secs(60)),
+        });
+        let t0 = Utc::now();
+        let (oldest, updated, newest) = (addr("/ip4/1.1.1.1/tcp/1"), addr("/ip4/2.2.2.2/tcp/2"), addr("/ip4/3.3.3.3/tcp/3"));
+        book.entry(&oldest, t0);
+        book.entry(&updated, t0 + chrono::Duration::seconds(1));
+        // Updates never evict.
+        book.entry(&oldest, t0 + chrono::Duration::seconds(2)).failures += 1;
+        assert_eq!(book.len(), 2);
+        book.entry(&newest, t0 + chrono::Duration::seconds(3));
+        assert_eq!(book.len(), 2);
+        assert!(book.stats(&updated).is_none());
+        assert_eq!(book.stats(&oldest).map(|stats| stats.failures), Some(1));
+        book.entry(&updated, t0 + chrono::Duration::seconds(120));
+        assert_eq!(book.len(), 1);
+        // Updates moved their address within the eviction order instead of adding one.
+        assert_eq!(book.by_age.len(), 1);
+    }
+}
@@ -0,0 +1,148 @@
+// Discovery of peers supporting a given protocol.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use libp2p::identify;
+use libp2p::swarm::{
+    dial_opts::{DialOpts, PeerCondition},
+    SwarmEvent
+};
+use libp2p_core::PeerId;
+use libp2p_kad::{
+    GetClosestPeersError,
+    GetClosestPeersOk,
+    KademliaEvent,
+    QueryResult
+};
+use crate::{LookupBehaviourEvent, LookupClient, Peer};
+
+const DEFAULT_DEADLINE: Duration = Duration::from_secs(60);
+const MAX_CONCURRENT_DIALS: usize = 16;
+
+impl LookupClient {
+    pub async fn find_peers_with_protocol(&mut self, protocol: &str, limit: usize) -> Vec<Peer> {
+        self.find_peers_with_protocol_within(protocol, limit, DEFAULT_DEADLINE).await
+    }
+    // Walks the DHT towards random targets and identifies the peers met on the way until
+    // `limit` of them support `protocol` or the deadline passes. Peers already in the peer
+    // store are answered from the cache. The connections the search dialed, itself or
+    // through its walk, are closed again except to the peers found. A dial that does not
+    // lead to identify info within `LookupConfig::dial_timeout` gives up its slot.
+    pub async fn find_peers_with_protocol_within(&mut self, protocol: &str, limit: usize, timeout: Duration) -> Vec<Peer> {
+        let deadline = Instant::now() + timeout;
+        let dial_timeout = self.config.dial_timeout;
+        let mut matches: Vec<Peer> = self.peer_store
+            .with_protocol(protocol)
+            .take(limit)
+            .map(|record| record.peer.clone())
+            .collect();
+        let mut checked: HashSet<PeerId> = self.peer_store.iter().map(|record| record.peer.peer_id).collect();
+        checked.insert(self.local_peer_id);
+        let mut candidates: VecDeque<PeerId> = VecDeque::new();
+        let mut queued: HashSet<PeerId> = HashSet::new();
+        let mut dialing: HashMap<PeerId, Instant> = HashMap::new();
+        let mut dialed: HashSet<PeerId> = HashSet::new();
+        let mut walk = None;
+        while matches.len() < limit {
+            if walk.is_none() {
+                walk = Some(self.swarm.behaviour_mut().kademlia.get_closest_peers(PeerId::random()));
+            }
+            let now = Instant::now();
+            let expired: Vec<PeerId> = dialing
+                .iter()
+                .filter(|(_, started)| now.saturating_duration_since(**started) >= dial_timeout)
+                .map(|(peer_id, _)| *peer_id)
+                .collect();
+            for peer_id in expired {
+                println!("{:?} was not identified in time.", peer_id);
+                dialing.remove(&peer_id);
+                checked.insert(peer_id);
+                self.disconnect(peer_id);
+            }
+            while dialing.len() < MAX_CONCURRENT_DIALS {
+                let peer_id = match candidates.pop_front() {
+                    Some(peer_id) => peer_id,
+                    None => break,
+                };
+                if checked.contains(&peer_id) {
+                    continue;
+                }
+                let opts = DialOpts::peer_id(peer_id)
+                    .condition(PeerCondition::Disconnected)
+                    .extend_addresses_through_behaviour()
+                    .build();
+                if self.swarm.dial(opts).is_ok() {
+                    dialing.insert(peer_id, Instant::now());
+                    dialed.insert(peer_id);
+                }
+            }
+            let remaining = match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) => remaining,
+                None => break,
+            };
+            // Woken up by the first dial to expire as well.
+            let wait = dialing
+                .values()
+                .map(|started| (*started + dial_timeout).saturating_duration_since(Instant::now()))
+                .min()
+                .map_or(remaining, |expiry| expiry.min(remaining));
+            let event = match async_std::future::timeout(wait, self.next_event()).await {
+                Ok(event) => event,
+                Err(_) => continue,
+            };
+            match event {
+                SwarmEvent::Behaviour(LookupBehaviourEvent::Kademlia(
+                    KademliaEvent::OutboundQueryCompleted { id, result: QueryResult::GetClosestPeers(result), .. }
+                )) if Some(id) == walk => {
+                    walk = None;
+                    let peers = match result {
+                        Ok(GetClosestPeersOk { peers, .. }) => peers,
+                        Err(GetClosestPeersError::Timeout { peers, .. }) => peers,
+                    };
+                    // Every walk meets some of the same peers again.
+                    candidates.extend(peers.into_iter().filter(|peer_id| !checked.contains(peer_id) && queued.insert(*peer_id)));
+                    self.rank_peers(candidates.make_contiguous());
+                },
+                // Dials of the walk, the peers are identified as soon as they connect.
+                SwarmEvent::Dialing(peer_id) => {
+                    dialed.insert(peer_id);
+                },
+                SwarmEvent::Behaviour(LookupBehaviourEvent::Identify(
+                    identify::Event::Received { peer_id, .. }
+                )) => {
+                    dialing.remove(&peer_id);
+                    if !checked.insert(peer_id) {
+                        continue;
+                    }
+                    let record = self.peer_store
+                        .get(&peer_id)
+                        .filter(|record| record.peer.protocols.iter().any(|p| p == protocol));
+                    match record {
+                        Some(record) => {
+                            println!("{:?} supports {}.", peer_id, protocol);
+                            matches.push(record.peer.clone());
+                        },
+                        None if dialed.contains(&peer_id) => {
+                            self.disconnect(peer_id);
+                        },
+                        None => {},
+                    }
+                },
+                SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), .. } => {
+                    dialing.remove(&peer_id);
+                },
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                    dialing.remove(&peer_id);
+                },
+                _ => {}
+            }
+        }
+        // Peers still being dialed or identified when the search ended.
+        for peer_id in dialed {
+            if self.is_connected(&peer_id) && !matches.iter().any(|peer| peer.peer_id == peer_id) {
+                self.disconnect(peer_id);
+            }
+        }
+        matches
+    }
+}
@@ -0,0 +1,433 @@
+// Connection gating by PeerId and IP range, applied to inbound and outbound connections.
+
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use ipnet::IpNet;
+use libp2p::Multiaddr;
+use libp2p_core::{
+    multiaddr::Protocol,
+    muxing::StreamMuxerBox,
+    transport::{Boxed, ListenerId, Transport, TransportError, TransportEvent},
+    PeerId
+};
+use thiserror::Error;
+use crate::LookupClient;
+
+// Denials kept until `connection_denials` is called.
+pub const MAX_DENIALS: usize = 1024;
+// Rejections kept until `rejections` is called.
+pub const MAX_REJECTIONS: usize = 1024;
+
+// Latest rejections, shared between the transport and the client.
+pub(crate) type Rejections = Arc<Mutex<VecDeque<Rejection>>>;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionGate {
+    // A non-empty allow list admits only its entries. Deny rules are checked first.
+    pub allow_peers: HashSet<PeerId>,
+    pub deny_peers: HashSet<PeerId>,
+    pub allow_ranges: Vec<IpNet>,
+    pub deny_ranges: Vec<IpNet>,
+}
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum RejectionReason {
+    #[error("peer is on the deny list")]
+    DeniedPeer,
+    #[error("address is in the denied range {0}")]
+    DeniedRange(IpNet),
+    #[error("peer is not on the allow list")]
+    PeerNotAllowed,
+    #[error("address is not in an allowed range")]
+    AddressNotAllowed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    // `None` for connections refused on their address, before the upgrade.
+    pub peer_id: Option<PeerId>,
+    pub address: Multiaddr,
+    pub direction: Direction,
+    pub reason: RejectionReason,
+}
+
+// A connection refused by the connection limits. Incoming connections are refused before
+// the remote peer is known, outgoing ones before an address is picked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionDenial {
+    pub peer_id: Option<PeerId>,
+    pub address: Option<Multiaddr>,
+    pub direction: Direction,
+    pub limit: u32,
+    pub current: u32,
+}
+
+// Error of the connections the gate refuses, its source is the reason. Upgrade errors
+// skip a level of sources, the reason stays reachable through this one.
+#[derive(Debug, Error)]
+#[error("connection refused by the gate")]
+pub(crate) struct GateRejection(#[source] RejectionReason);
+
+// Whether a transport error comes from the gate rather than from the remote peer.
+pub(crate) fn is_rejection(error: &TransportError<io::Error>) -> bool {
+    match error {
+        TransportError::Other(error) => caused_by_gate(error),
+        TransportError::MultiaddrNotSupported(_) => false,
+    }
+}
+
+fn caused_by_gate(error: &(dyn Error + 'static)) -> bool {
+    if error.is::<GateRejection>() || error.is::<RejectionReason>() {
+        return true;
+    }
+    // The source of an `io::Error` is the source of the error it wraps, not that error.
+    let inner = error
+        .downcast_ref::<io::Error>()
+        .and_then(|error| error.get_ref())
+        .map(|inner| inner as &(dyn Error + 'static));
+    inner.or_else(|| error.source()).map_or(false, caused_by_gate)
+}
+
+#[derive(Debug, Error)]
+pub enum GateError {
+    #[error("Gate file error : {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid gate rule on line {line} : {reason}")]
+    Parse {
+        line: usize,
+        reason: String,
+    },
+}
+
+// First IP address of a multiaddr. For relayed addresses this is the relay, DNS addresses
+// have none.
+fn ip_of(address: &Multiaddr) -> Option<IpAddr> {
+    address.iter().find_map(|protocol| match protocol {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    })
+}
+
+fn is_dns(address: &Multiaddr) -> bool {
+    address.iter().any(|protocol| matches!(
+        protocol,
+        Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) | Protocol::Dnsaddr(_)
+    ))
+}
+
+impl ConnectionGate {
+    pub fn check(&self, peer_id: &PeerId, address: &Multiaddr) -> Result<(), RejectionReason> {
+        if self.deny_peers.contains(peer_id) {
+            return Err(RejectionReason::DeniedPeer);
+        }
+        self.check_denied_range(address)?;
+        if !self.allow_peers.is_empty() && !self.allow_peers.contains(peer_id) {
+            return Err(RejectionReason::PeerNotAllowed);
+        }
+        self.check_allowed_range(address)
+    }
+    // The PeerId rules alone, for connections whose address was checked before the upgrade.
+    pub fn check_peer(&self, peer_id: &PeerId) -> Result<(), RejectionReason> {
+        if self.deny_peers.contains(peer_id) {
+            return Err(RejectionReason::DeniedPeer);
+        }
+        if !self.allow_peers.is_empty() && !self.allow_peers.contains(peer_id) {
+            return Err(RejectionReason::PeerNotAllowed);
+        }
+        Ok(())
+    }
+    // The IP rules alone, for connections whose peer is not authenticated yet.
+    pub fn check_address(&self, address: &Multiaddr) -> Result<(), RejectionReason> {
+        self.check_denied_range(address)?;
+        self.check_allowed_range(address)
+    }
+    fn check_denied_range(&self, address: &Multiaddr) -> Result<(), RejectionReason> {
+        match ip_of(address).and_then(|ip| self.deny_ranges.iter().find(|range| range.contains(&ip))) {
+            Some(range) => Err(RejectionReason::DeniedRange(*range)),
+            None => Ok(()),
+        }
+    }
+    fn check_allowed_range(&self, address: &Multiaddr) -> Result<(), RejectionReason> {
+        let ip = ip_of(address);
+        if !self.allow_ranges.is_empty() && !ip.map_or(false, |ip| self.allow_ranges.iter().any(|range| range.contains(&ip))) {
+            return Err(RejectionReason::AddressNotAllowed);
+        }
+        Ok(())
+    }
+    // One rule per line : `allow peer <PeerId>`, `deny peer <PeerId>`, `allow ip <CIDR>` or
+    // `deny ip <CIDR>`. A bare IP stands for a single host, `#` starts a comment.
+    pub fn parse(rules: &str) -> Result<Self, GateError> {
+        let mut gate = ConnectionGate::default();
+        for (index, line) in rules.lines().enumerate() {
+            let line_number = index + 1;
+            let rule = line.split('#').next().unwrap_or_default();
+            let words: Vec<&str> = rule.split_whitespace().collect();
+            let invalid = |reason: String| GateError::Parse { line: line_number, reason };
+            match words.as_slice() {
+                [] => {},
+                [action, "peer", value] => {
+                    let peer_id = PeerId::from_str(value).map_err(|e| invalid(e.to_string()))?;
+                    match *action {
+                        "allow" => gate.allow_peers.insert(peer_id),
+                        "deny" => gate.deny_peers.insert(peer_id),
+                        _ => return Err(invalid(format!("unknown action {}", action))),
+                    };
+                },
+                [action, "ip", value] => {
+                    let range = IpNet::from_str(value)
+                        .or_else(|_| IpAddr::from_str(value).map(IpNet::from))
+                        .map_err(|e| invalid(e.to_string()))?;
+                    match *action {
+                        "allow" => gate.allow_ranges.push(range),
+                        "deny" => gate.deny_ranges.push(range),
+                        _ => return Err(invalid(format!("unknown action {}", action))),
+                    }
+                },
+                _ => return Err(invalid(format!("unrecognised rule {}", rule.trim()))),
+            }
+        }
+        Ok(gate)
+    }
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, GateError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+}
+
+fn reject(rejections: &Rejections, rejection: Rejection) {
+    println!("Rejected connection with {:?} at {} : {}", rejection.peer_id, rejection.address, rejection.reason);
+    let mut rejections = rejections.lock().expect("Rejections lock poisoned.");
+    if rejections.len() == MAX_REJECTIONS {
+        rejections.pop_front();
+    }
+    rejections.push_back(rejection);
+}
+
+// Gate rules and the latest rejections, shared by the transports and the client.
+#[derive(Clone, Default)]
+pub(crate) struct SharedGate {
+    pub(crate) rules: Arc<RwLock<ConnectionGate>>,
+    pub(crate) rejections: Rejections,
+}
+
+impl SharedGate {
+    pub(crate) fn new(rules: ConnectionGate) -> Self {
+        SharedGate {
+            rules: Arc::new(RwLock::new(rules)),
+            rejections: Rejections::default(),
+        }
+    }
+    // Refuses dials to and upgrades from addresses out of the IP rules, before any handshake.
+    // Wraps the raw transports, under the DNS transport, so that DNS addresses are checked on
+    // the IP they resolve to.
+    pub(crate) fn addresses<T>(&self, inner: T) -> AddressGate<T> {
+        AddressGate {
+            inner,
+            gate: self.rules.clone(),
+            rejections: self.rejections.clone(),
+        }
+    }
+    // Checks the PeerId rules once the remote PeerId is authenticated.
+    pub(crate) fn peers(&self, transport: Boxed<(PeerId, StreamMuxerBox)>) -> Boxed<(PeerId, StreamMuxerBox)> {
+        let gate = self.rules.clone();
+        let rejections = self.rejections.clone();
+        transport
+            .and_then(move |(peer_id, muxer), endpoint| {
+                let verdict = gate.read().expect("Gate lock poisoned.").check_peer(&peer_id);
+                if let Err(reason) = &verdict {
+                    let address = endpoint.get_remote_address().clone();
+                    let direction = if endpoint.is_dialer() { Direction::Outbound } else { Direction::Inbound };
+                    reject(&rejections, Rejection { peer_id: Some(peer_id), address, direction, reason: reason.clone() });
+                }
+                futures::future::ready(verdict.map(|()| (peer_id, muxer)).map_err(GateRejection))
+            })
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+            .boxed()
+    }
+}
+
+pub(crate) struct AddressGate<T> {
+    inner: T,
+    gate: Arc<RwLock<ConnectionGate>>,
+    rejections: Rejections,
+}
+
+impl<T> AddressGate<T> {
+    fn check(&self, address: &Multiaddr, direction: Direction) -> Result<(), RejectionReason> {
+        let verdict = self.gate.read().expect("Gate lock poisoned.").check_address(address);
+        if let Err(reason) = &verdict {
+            reject(&self.rejections, Rejection { peer_id: None, address: address.clone(), direction, reason: reason.clone() });
+        }
+        verdict
+    }
+}
+
+impl<T> Transport for AddressGate<T>
+where
+    T: Transport<Error = io::Error> + Unpin,
+{
+    type Output = T::Output;
+    type Error = io::Error;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = T::Dial;
+
+    fn listen_on(&mut self, address: Multiaddr) -> Result<ListenerId, TransportError<Self::Error>> {
+        self.inner.listen_on(address)
+    }
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.inner.remove_listener(id)
+    }
+    fn dial(&mut self, address: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.check(&address, Direction::Outbound)
+            .map_err(|reason| TransportError::Other(io::Error::new(io::ErrorKind::PermissionDenied, GateRejection(reason))))?;
+        self.inner.dial(address)
+    }
+    fn dial_as_listener(&mut self, address: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.check(&address, Direction::Outbound)
+            .map_err(|reason| TransportError::Other(io::Error::new(io::ErrorKind::PermissionDenied, GateRejection(reason))))?;
+        self.inner.dial_as_listener(address)
+    }
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        loop {
+            let event = match Pin::new(&mut self.inner).poll(cx) {
+                Poll::Ready(event) => event,
+                Poll::Pending => return Poll::Pending,
+            };
+            if let TransportEvent::Incoming { send_back_addr, .. } = &event {
+                // Dropping the upgrade closes the connection before the handshake.
+                if self.check(send_back_addr, Direction::Inbound).is_err() {
+                    continue;
+                }
+            }
+            return Poll::Ready(event);
+        }
+    }
+    fn address_translation(&self, listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.address_translation(listen, observed)
+    }
+}
+
+impl LookupClient {
+    pub fn gate(&self) -> ConnectionGate {
+        self.gate.read().expect("Gate lock poisoned.").clone()
+    }
+    // Connections opened before the update and no longer admitted are closed. The IP a
+    // DNS address resolved to is not known here, only the PeerId rules apply to those.
+    pub fn update_gate<F: FnOnce(&mut ConnectionGate)>(&mut self, update: F) {
+        let gate = {
+            let mut gate = self.gate.write().expect("Gate lock poisoned.");
+            update(&mut gate);
+            gate.clone()
+        };
+        let rejected: Vec<PeerId> = self.connected_addrs
+            .iter()
+            .filter(|(peer_id, address)| match is_dns(address) {
+                true => gate.check_peer(peer_id).is_err(),
+                false => gate.check(peer_id, address).is_err(),
+            })
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in rejected {
+            println!("Disconnecting {:?}, no longer admitted by the gate.", peer_id);
+            self.disconnect(peer_id);
+        }
+    }
+    pub fn load_gate<P: AsRef<Path>>(&mut self, path: P) -> Result<(), GateError> {
+        let loaded = ConnectionGate::from_file(path)?;
+        self.update_gate(|gate| *gate = loaded);
+        Ok(())
+    }
+    // Connections refused by the connection limits since the last call, the latest
+    // `MAX_DENIALS` at most.
+    pub fn connection_denials(&mut self) -> Vec<ConnectionDenial> {
+        self.denials.drain(..).collect()
+    }
+    pub(crate) fn record_denial(&mut self, denial: ConnectionDenial) {
+        println!("{:?} connection {:?} at {:?} denied : {} of {} connections.", denial.direction, denial.peer_id, denial.address, denial.current, denial.limit);
+        if self.denials.len() == MAX_DENIALS {
+            self.denials.pop_front();
+        }
+        self.denials.push_back(denial);
+    }
+    // Rejections since the last call, the latest `MAX_REJECTIONS` at most.
+    pub fn rejections(&mut self) -> Vec<Rejection> {
+        self.rejections.lock().expect("Rejections lock poisoned.").drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn deny_rules_win_over_allow_lists() {
+        let known = PeerId::random();
+        let unknown = PeerId::random();
+        let mut gate = ConnectionGate::default();
+        assert_eq!(gate.check(&unknown, &addr("/ip4/1.2.3.4/tcp/30333")), Ok(()));
+        gate.allow_peers.insert(known);
+        gate.deny_ranges.push("10.0.0.0/8".parse().unwrap());
+        assert_eq!(gate.check(&known, &addr("/ip4/1.2.3.4/tcp/30333")), Ok(()));
+        assert_eq!(gate.check(&unknown, &addr("/ip4/1.2.3.4/tcp/30333")), Err(RejectionReason::PeerNotAllowed));
+        assert_eq!(
+            gate.check(&known, &addr("/ip4/10.1.2.3/tcp/30333")),
+            Err(RejectionReason::DeniedRange("10.0.0.0/8".parse().unwrap()))
+        );
+        gate.deny_peers.insert(known);
+        assert_eq!(gate.check(&known, &addr("/ip4/1.2.3.4/tcp/30333")), Err(RejectionReason::DeniedPeer));
+    }
+
+    #[test]
+    fn allowed_ranges_need_an_ip() {
+        let peer_id = PeerId::random();
+        let gate = ConnectionGate {
+            allow_ranges: vec!["2001:db8::/32".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(gate.check(&peer_id, &addr("/ip6/2001:db8::1/tcp/30333")), Ok(()));
+        assert_eq!(gate.check(&peer_id, &addr("/ip6/2001:db9::1/tcp/30333")), Err(RejectionReason::AddressNotAllowed));
+        assert_eq!(gate.check(&peer_id, &addr("/dns/example.com/tcp/30333")), Err(RejectionReason::AddressNotAllowed));
+    }
+
+    #[test]
+    fn rejections_are_told_apart_from_transport_errors() {
+        let rejected = io::Error::new(io::ErrorKind::PermissionDenied, GateRejection(RejectionReason::AddressNotAllowed));
+        // Wrapped the way the upgraded transport wraps its errors.
+        let wrapped = io::Error::new(io::ErrorKind::Other, libp2p_core::transport::TransportError::Other(rejected));
+        assert!(is_rejection(&TransportError::Other(wrapped)));
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused");
+        assert!(!is_rejection(&TransportError::Other(io::Error::new(io::ErrorKind::Other, refused))));
+        assert!(!is_rejection(&TransportError::MultiaddrNotSupported(addr("/dns/example.com/tcp/30333"))));
+    }
+
+    #[test]
+    fn parses_rule_files() {
+        let peer_id = PeerId::random();
+        let rules = format!("# handshake peers\nallow peer {}\n\ndeny ip 192.0.2.0/24\ndeny ip 198.51.100.7 # single host\n", peer_id);
+        let gate = ConnectionGate::parse(&rules).unwrap();
+        assert!(gate.allow_peers.contains(&peer_id));
+        assert_eq!(gate.deny_ranges, vec!["192.0.2.0/24".parse::<IpNet>().unwrap(), "198.51.100.7/32".parse().unwrap()]);
+        match ConnectionGate::parse("allow peer\nblock ip 10.0.0.0/8") {
+            Err(GateError::Parse { line, .. }) => assert_eq!(line, 1),
+            other => panic!("Unexpected result : {:?}", other),
+        }
+        assert!(matches!(ConnectionGate::parse("block ip 10.0.0.0/8"), Err(GateError::Parse { line: 1, .. })));
+    }
+}
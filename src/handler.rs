@@ -0,0 +1,181 @@
+// Inbound requests answered by user supplied handlers. Each request is handled on its own
+// task, the responses come back through a channel polled by `next_event`.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::{FutureExt, SinkExt};
+use libp2p::request_response::{RequestId, ResponseChannel};
+use libp2p_core::PeerId;
+use thiserror::Error;
+use crate::{LookupClient, Negotiated};
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum HandlerError {
+    #[error("{0}")]
+    Failed(String),
+    #[error("handler panicked : {0}")]
+    Panicked(String),
+    // Refused without calling the handler, `max_handled_requests` requests were in progress.
+    #[error("too many requests being handled")]
+    Busy,
+    #[error("handler timed out after {0:?}")]
+    TimedOut(Duration),
+}
+
+#[async_trait]
+pub trait RequestHandler: Send + Sync + 'static {
+    type Request: Send + 'static;
+    type Response: Send + 'static;
+
+    async fn handle(&self, peer_id: PeerId, request: Self::Request) -> Result<Self::Response, HandlerError>;
+
+    // Sent in place of the response when handling failed, none drops the channel and the
+    // remote sees the request fail.
+    fn error_response(&self, _error: &HandlerError) -> Option<Self::Response> {
+        None
+    }
+}
+
+pub type TestRequestHandler = dyn RequestHandler<Request = test_protocol::SYN, Response = test_protocol::SYNACK>;
+
+type SharedHandler<Req, Res> = Arc<dyn RequestHandler<Request = Req, Response = Res>>;
+
+pub(crate) struct Handled<Res> {
+    request_id: RequestId,
+    peer_id: PeerId,
+    result: Result<Res, HandlerError>,
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+// Handlers of the requests of a `VersionedCodec`, registered per protocol version. Every
+// request handled ends up in `handled`, answered, failed, refused or timed out, and leaves
+// `channels` there.
+pub(crate) struct Handlers<Req, Res> {
+    handlers: HashMap<String, SharedHandler<Req, Res>>,
+    // Requests being handled, with the handler they were given to.
+    channels: HashMap<RequestId, (ResponseChannel<Negotiated<Res>>, SharedHandler<Req, Res>)>,
+    max_concurrent: usize,
+    timeout: Duration,
+    sender: mpsc::Sender<Handled<Res>>,
+    pub(crate) handled: mpsc::Receiver<Handled<Res>>,
+}
+
+impl<Req: Send + 'static, Res: Send + 'static> Handlers<Req, Res> {
+    pub fn new(max_concurrent: usize, timeout: Duration) -> Self {
+        // Each request handled holds at most one slot.
+        let (sender, handled) = mpsc::channel(max_concurrent);
+        Handlers {
+            handlers: HashMap::new(),
+            channels: HashMap::new(),
+            max_concurrent,
+            timeout,
+            sender,
+            handled,
+        }
+    }
+    pub fn register(&mut self, protocol: &str, handler: SharedHandler<Req, Res>) {
+        self.handlers.insert(protocol.to_string(), handler);
+    }
+    pub fn unregister(&mut self, protocol: &str) {
+        self.handlers.remove(protocol);
+    }
+    pub fn clear(&mut self) {
+        self.handlers.clear();
+    }
+    fn handler_for(&self, request: &Negotiated<Req>) -> Option<&SharedHandler<Req, Res>> {
+        request.protocol.as_ref().and_then(|protocol| self.handlers.get(protocol.name()))
+    }
+    pub fn handles(&self, request: &Negotiated<Req>) -> bool {
+        self.handler_for(request).is_some()
+    }
+    pub fn in_progress(&self) -> usize {
+        self.channels.len()
+    }
+    // A request refused on the spot comes back to be answered right away.
+    pub fn dispatch(
+        &mut self,
+        peer_id: PeerId,
+        request_id: RequestId,
+        request: Negotiated<Req>,
+        channel: ResponseChannel<Negotiated<Res>>
+    ) -> Option<Handled<Res>> {
+        let handler = self.handler_for(&request)?.clone();
+        self.channels.insert(request_id, (channel, handler.clone()));
+        if self.channels.len() > self.max_concurrent {
+            return Some(Handled { request_id, peer_id, result: Err(HandlerError::Busy) });
+        }
+        let mut sender = self.sender.clone();
+        let timeout = self.timeout;
+        async_std::task::spawn(async move {
+            let handling = AssertUnwindSafe(handler.handle(peer_id, request.message)).catch_unwind();
+            let result = match async_std::future::timeout(timeout, handling).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(payload)) => Err(HandlerError::Panicked(panic_message(payload))),
+                Err(_) => Err(HandlerError::TimedOut(timeout)),
+            };
+            let _ = sender.send(Handled { request_id, peer_id, result }).await;
+        });
+        None
+    }
+}
+
+impl LookupClient {
+    // Inbound requests of the test protocol go to the handler from now on, instead of being
+    // returned by `next_event`, whatever version they come over. Handshake SYNs and ACKs are
+    // left out, `init_protocol` and `serve_handshakes` still answer them. Requests still being
+    // handled keep their previous handler.
+    pub fn set_request_handler<H>(&mut self, handler: H)
+    where
+        H: RequestHandler<Request = test_protocol::SYN, Response = test_protocol::SYNACK>,
+    {
+        let handler: Arc<TestRequestHandler> = Arc::new(handler);
+        for version in self.codec.versions() {
+            self.handlers.register(version.name(), handler.clone());
+        }
+    }
+    // Same as `set_request_handler` for the requests of one version of the test protocol.
+    pub fn set_protocol_handler<H>(&mut self, protocol: &str, handler: H)
+    where
+        H: RequestHandler<Request = test_protocol::SYN, Response = test_protocol::SYNACK>,
+    {
+        self.handlers.register(protocol, Arc::new(handler));
+    }
+    pub fn remove_request_handler(&mut self) {
+        self.handlers.clear();
+    }
+    pub fn remove_protocol_handler(&mut self, protocol: &str) {
+        self.handlers.unregister(protocol);
+    }
+    pub(crate) async fn on_handled(&mut self, handled: Handled<test_protocol::SYNACK>) {
+        let Handled { request_id, peer_id, result } = handled;
+        let (channel, handler) = match self.handlers.channels.remove(&request_id) {
+            Some(pending) => pending,
+            None => return,
+        };
+        let response = match result {
+            Ok(response) => Some(response),
+            Err(error) => {
+                println!("Handling request {} from {:?} failed : {}", request_id, peer_id, error);
+                handler.error_response(&error)
+            },
+        };
+        // Dropping the channel makes the request fail on the remote.
+        if let Some(response) = response {
+            if let Err(e) = self.send_response(channel, response).await {
+                println!("Response to {:?} dropped : {}", peer_id, e);
+            }
+        }
+    }
+}
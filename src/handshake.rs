@@ -0,0 +1,695 @@
+// SYN/SYNACK/ACK handshake messages and the per-peer state machine driving them.
+//
+// Requester : sends SYN with a fresh challenge, expects a SYNACK signing it, then sends ACK.
+// Responder : answers SYN with a signed SYNACK, then expects ACK and answers it with ACK.
+//
+// The signed SYNACK is an application-level proof that the responder holding the key of
+// its PeerId was alive at the time of the challenge.
+//
+// Peers only speaking the first version of the protocol exchange the bare tags instead,
+// there is nothing to verify and no proof.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use libp2p_core::{
+    identity::{Keypair, PublicKey},
+    PeerId
+};
+use thiserror::Error;
+#[cfg(feature = "test-protocol")]
+use crate::{Negotiated, SerdeProtocol, SizeLimits, TestCodec};
+
+pub const NONCE_LEN: usize = 32;
+// Keeps the signatures from being valid for anything else than this handshake.
+const SIGNING_DOMAIN: &[u8] = b"/SYNACK/liveness";
+// First byte of each message, the nonce that follows may start with any bytes.
+const SYN_TAG: u8 = 0x01;
+const SYNACK_TAG: u8 = 0x02;
+const ACK_TAG: u8 = 0x03;
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Challenge {
+    pub nonce: [u8; NONCE_LEN],
+    // Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl Challenge {
+    pub fn new(now: SystemTime) -> Self {
+        Challenge {
+            nonce: rand::random(),
+            timestamp: unix_millis(now),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedChallenge {
+    pub challenge: Challenge,
+    pub signed_at: u64,
+    // Protobuf encoding of the responder public key.
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeMessage {
+    Syn(Challenge),
+    SynAck(SignedChallenge),
+    Ack,
+    LegacySyn,
+    LegacySynAck,
+    LegacyAck,
+}
+
+// Reads fixed size fields off the front of a payload.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], HandshakeError> {
+        if self.0.len() < len {
+            return Err(HandshakeError::Malformed("truncated payload"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+    fn u64(&mut self) -> Result<u64, HandshakeError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().expect("Eight bytes taken.")))
+    }
+    fn u16(&mut self) -> Result<u16, HandshakeError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().expect("Two bytes taken.")))
+    }
+    fn challenge(&mut self) -> Result<Challenge, HandshakeError> {
+        let nonce = self.take(NONCE_LEN)?.try_into().expect("Nonce length taken.");
+        Ok(Challenge { nonce, timestamp: self.u64()? })
+    }
+}
+
+impl HandshakeMessage {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HandshakeMessage::Syn(_) | HandshakeMessage::LegacySyn => "SYN",
+            HandshakeMessage::SynAck(_) | HandshakeMessage::LegacySynAck => "SYNACK",
+            HandshakeMessage::Ack | HandshakeMessage::LegacyAck => "ACK",
+        }
+    }
+    // Same message in the first version of the protocol.
+    pub fn to_legacy(&self) -> Self {
+        match self {
+            HandshakeMessage::Syn(_) | HandshakeMessage::LegacySyn => HandshakeMessage::LegacySyn,
+            HandshakeMessage::SynAck(_) | HandshakeMessage::LegacySynAck => HandshakeMessage::LegacySynAck,
+            HandshakeMessage::Ack | HandshakeMessage::LegacyAck => HandshakeMessage::LegacyAck,
+        }
+    }
+    // The tag byte, then big endian fixed size fields. The signature takes the rest of a
+    // SYNACK. Legacy messages are the bare ASCII kind.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            HandshakeMessage::Syn(challenge) => {
+                bytes.push(SYN_TAG);
+                bytes.extend_from_slice(&challenge.nonce);
+                bytes.extend_from_slice(&challenge.timestamp.to_be_bytes());
+            },
+            HandshakeMessage::SynAck(signed) => {
+                bytes.push(SYNACK_TAG);
+                bytes.extend_from_slice(&signed.challenge.nonce);
+                bytes.extend_from_slice(&signed.challenge.timestamp.to_be_bytes());
+                bytes.extend_from_slice(&signed.signed_at.to_be_bytes());
+                bytes.extend_from_slice(&(signed.public_key.len() as u16).to_be_bytes());
+                bytes.extend_from_slice(&signed.public_key);
+                bytes.extend_from_slice(&signed.signature);
+            },
+            HandshakeMessage::Ack => bytes.push(ACK_TAG),
+            HandshakeMessage::LegacySyn
+            | HandshakeMessage::LegacySynAck
+            | HandshakeMessage::LegacyAck => bytes.extend_from_slice(self.kind().as_bytes()),
+        }
+        bytes
+    }
+    // Payloads of the first version, the tag alone.
+    pub fn parse_legacy(payload: &[u8]) -> Result<Self, HandshakeError> {
+        match payload {
+            b"SYN" => Ok(HandshakeMessage::LegacySyn),
+            b"SYNACK" => Ok(HandshakeMessage::LegacySynAck),
+            b"ACK" => Ok(HandshakeMessage::LegacyAck),
+            _ => Err(HandshakeError::UnknownPayload(payload.len())),
+        }
+    }
+    // Remote input, it is only ever sliced, never assumed to be UTF-8.
+    pub fn parse(payload: &[u8]) -> Result<Self, HandshakeError> {
+        let (tag, body) = match payload.split_first() {
+            Some((tag, body)) => (*tag, body),
+            None => return Err(HandshakeError::UnknownPayload(0)),
+        };
+        let mut reader = Reader(body);
+        let message = match tag {
+            SYN_TAG => HandshakeMessage::Syn(reader.challenge()?),
+            SYNACK_TAG => {
+                let challenge = reader.challenge()?;
+                let signed_at = reader.u64()?;
+                let key_len = reader.u16()? as usize;
+                let public_key = reader.take(key_len)?.to_vec();
+                let signature = std::mem::take(&mut reader.0).to_vec();
+                if signature.is_empty() {
+                    return Err(HandshakeError::Malformed("missing signature"));
+                }
+                HandshakeMessage::SynAck(SignedChallenge { challenge, signed_at, public_key, signature })
+            },
+            ACK_TAG => HandshakeMessage::Ack,
+            _ => return Err(HandshakeError::UnknownPayload(payload.len())),
+        };
+        if !reader.0.is_empty() {
+            return Err(HandshakeError::Malformed("trailing bytes"));
+        }
+        Ok(message)
+    }
+    // Whether a request is a SYN or an ACK of the handshake, the other ones are left to the
+    // request handler.
+    pub fn is_request(payload: &[u8], legacy: bool) -> bool {
+        let message = if legacy { Self::parse_legacy(payload) } else { Self::parse(payload) };
+        matches!(
+            message,
+            Ok(HandshakeMessage::Syn(_) | HandshakeMessage::Ack | HandshakeMessage::LegacySyn | HandshakeMessage::LegacyAck)
+        )
+    }
+    #[cfg(feature = "test-protocol")]
+    pub fn request(&self) -> test_protocol::SYN {
+        test_protocol::SYN(self.to_bytes())
+    }
+    #[cfg(feature = "test-protocol")]
+    pub fn response(&self) -> test_protocol::SYNACK {
+        test_protocol::SYNACK(self.to_bytes())
+    }
+}
+
+// The codec of the handshake behaviour. Under the legacy version, which goes raw, handshake
+// messages are rewritten to the bare tags it knows.
+#[cfg(feature = "test-protocol")]
+pub(crate) fn codec(versions: Vec<SerdeProtocol>, limits: SizeLimits) -> TestCodec {
+    TestCodec::new(versions, limits).with_rewrite(
+        |protocol, test_protocol::SYN(payload)| test_protocol::SYN(legacy_bytes(protocol, payload)),
+        |protocol, test_protocol::SYNACK(payload)| test_protocol::SYNACK(legacy_bytes(protocol, payload))
+    )
+}
+
+#[cfg(feature = "test-protocol")]
+pub(crate) fn is_handshake_request(request: &Negotiated<test_protocol::SYN>) -> bool {
+    HandshakeMessage::is_request(&request.message.0, request.protocol.as_ref().map_or(false, test_protocol::is_legacy))
+}
+
+// The first version had no challenge, handshake messages are sent as their bare tag.
+// Other payloads are sent as they are.
+#[cfg(feature = "test-protocol")]
+fn legacy_bytes(protocol: &SerdeProtocol, payload: Vec<u8>) -> Vec<u8> {
+    if !test_protocol::is_legacy(protocol) {
+        return payload;
+    }
+    match HandshakeMessage::parse(&payload) {
+        Ok(message) => message.to_legacy().to_bytes(),
+        Err(_) => payload,
+    }
+}
+
+// A verified SYNACK, kept for logging and auditing. It can be verified again later on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LivenessProof {
+    pub requester: PeerId,
+    pub responder: PeerId,
+    pub signed: SignedChallenge,
+}
+
+impl LivenessProof {
+    fn signed_bytes(requester: &PeerId, challenge: &Challenge, signed_at: u64) -> Vec<u8> {
+        let mut bytes = SIGNING_DOMAIN.to_vec();
+        bytes.extend_from_slice(&requester.to_bytes());
+        bytes.extend_from_slice(&challenge.nonce);
+        bytes.extend_from_slice(&challenge.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&signed_at.to_be_bytes());
+        bytes
+    }
+    fn sign(local_key: &Keypair, requester: &PeerId, challenge: Challenge, now: SystemTime) -> Result<SignedChallenge, HandshakeError> {
+        let signed_at = unix_millis(now);
+        let signature = local_key
+            .sign(&Self::signed_bytes(requester, &challenge, signed_at))
+            .map_err(|_| HandshakeError::Signing)?;
+        Ok(SignedChallenge {
+            challenge,
+            signed_at,
+            public_key: local_key.public().to_protobuf_encoding(),
+            signature,
+        })
+    }
+    pub fn verify(&self) -> Result<(), HandshakeError> {
+        let public_key = PublicKey::from_protobuf_encoding(&self.signed.public_key)
+            .map_err(|_| HandshakeError::Malformed("invalid public key"))?;
+        if public_key.to_peer_id() != self.responder {
+            return Err(HandshakeError::KeyMismatch);
+        }
+        let signed_bytes = Self::signed_bytes(&self.requester, &self.signed.challenge, self.signed.signed_at);
+        if !public_key.verify(&signed_bytes, &self.signed.signature) {
+            return Err(HandshakeError::InvalidSignature);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeState {
+    // Requester waiting for the SYNACK.
+    SynSent,
+    // Responder waiting for the ACK.
+    SynAckSent,
+    Completed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum HandshakeError {
+    #[error("unknown payload of {0} bytes")]
+    UnknownPayload(usize),
+    #[error("malformed message : {0}")]
+    Malformed(&'static str),
+    #[error("unexpected {message} {direction} in state {state:?}")]
+    Unexpected {
+        message: &'static str,
+        // "request" or "response".
+        direction: &'static str,
+        state: Option<HandshakeState>,
+    },
+    #[error("the SYNACK answers another challenge")]
+    NonceMismatch,
+    #[error("timestamp outside of the replay window")]
+    Stale,
+    #[error("challenge already seen")]
+    Replay,
+    #[error("public key does not match the peer")]
+    KeyMismatch,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("signing failed")]
+    Signing,
+    #[error("already serving {0} handshakes")]
+    TooManySessions(usize),
+    #[error("no progress within {0:?}")]
+    Timeout(Duration),
+}
+
+// What to do after a message was accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Step {
+    pub reply: Option<HandshakeMessage>,
+    pub completed: bool,
+    // Set on the requester side once the SYNACK is verified.
+    pub proof: Option<LivenessProof>,
+}
+
+impl Step {
+    fn reply(reply: HandshakeMessage, completed: bool) -> Self {
+        Step { reply: Some(reply), completed, proof: None }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    state: HandshakeState,
+    since: SystemTime,
+    // The challenge sent, requester side only.
+    challenge: Option<Challenge>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Handshakes {
+    local_key: Keypair,
+    local_peer_id: PeerId,
+    timeout: Duration,
+    replay_window: Duration,
+    // Handshakes answered at once, the SYN of any further peer is refused.
+    max_sessions: usize,
+    peers: HashMap<PeerId, Entry>,
+    // Nonces accepted within the replay window.
+    seen: HashMap<[u8; NONCE_LEN], SystemTime>,
+}
+
+impl Handshakes {
+    pub fn new(local_key: Keypair, timeout: Duration, replay_window: Duration, max_sessions: usize) -> Self {
+        Handshakes {
+            local_peer_id: local_key.public().to_peer_id(),
+            local_key,
+            timeout,
+            replay_window,
+            max_sessions,
+            peers: HashMap::new(),
+            seen: HashMap::new(),
+        }
+    }
+    pub fn state(&self, peer_id: &PeerId) -> Option<HandshakeState> {
+        self.peers.get(peer_id).map(|entry| entry.state)
+    }
+    pub fn in_progress(&self, peer_id: &PeerId) -> bool {
+        matches!(self.state(peer_id), Some(HandshakeState::SynSent | HandshakeState::SynAckSent))
+    }
+    // Handshakes answered and waiting for the ACK.
+    pub fn sessions(&self) -> usize {
+        self.peers.values().filter(|entry| entry.state == HandshakeState::SynAckSent).count()
+    }
+    // Returns the SYN to send.
+    pub fn start(&mut self, peer_id: PeerId, now: SystemTime) -> HandshakeMessage {
+        let challenge = Challenge::new(now);
+        self.peers.insert(peer_id, Entry { state: HandshakeState::SynSent, since: now, challenge: Some(challenge) });
+        HandshakeMessage::Syn(challenge)
+    }
+    pub fn abort(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+    fn check_fresh(&self, timestamp: u64, now: SystemTime) -> Result<(), HandshakeError> {
+        let skew = unix_millis(now).abs_diff(timestamp);
+        if skew > self.replay_window.as_millis() as u64 {
+            return Err(HandshakeError::Stale);
+        }
+        Ok(())
+    }
+    fn check_unseen(&mut self, nonce: [u8; NONCE_LEN], now: SystemTime) -> Result<(), HandshakeError> {
+        self.forget_stale_nonces(now);
+        if self.seen.insert(nonce, now).is_some() {
+            return Err(HandshakeError::Replay);
+        }
+        Ok(())
+    }
+    // A failed transition drops the state of the peer, it has to start over.
+    pub fn on_request(&mut self, peer_id: PeerId, payload: &[u8], now: SystemTime) -> Result<Step, HandshakeError> {
+        let state = self.state(&peer_id);
+        let result = HandshakeMessage::parse(payload).and_then(|message| match (message, state) {
+            (HandshakeMessage::Syn(challenge), None | Some(HandshakeState::Completed)) => {
+                if self.sessions() >= self.max_sessions {
+                    return Err(HandshakeError::TooManySessions(self.max_sessions));
+                }
+                self.check_fresh(challenge.timestamp, now)?;
+                self.check_unseen(challenge.nonce, now)?;
+                let signed = LivenessProof::sign(&self.local_key, &peer_id, challenge, now)?;
+                Ok((HandshakeState::SynAckSent, None, Step::reply(HandshakeMessage::SynAck(signed), false)))
+            },
+            (HandshakeMessage::Ack, Some(HandshakeState::SynAckSent)) => {
+                Ok((HandshakeState::Completed, None, Step::reply(HandshakeMessage::Ack, true)))
+            },
+            (message, state) => Err(HandshakeError::Unexpected { message: message.kind(), direction: "request", state }),
+        });
+        self.apply(peer_id, result, now)
+    }
+    // Requests negotiated under the first version of the protocol.
+    pub fn on_legacy_request(&mut self, peer_id: PeerId, payload: &[u8], now: SystemTime) -> Result<Step, HandshakeError> {
+        let state = self.state(&peer_id);
+        let result = HandshakeMessage::parse_legacy(payload).and_then(|message| match (message, state) {
+            (HandshakeMessage::LegacySyn, None | Some(HandshakeState::Completed)) => {
+                if self.sessions() >= self.max_sessions {
+                    return Err(HandshakeError::TooManySessions(self.max_sessions));
+                }
+                Ok((HandshakeState::SynAckSent, None, Step::reply(HandshakeMessage::LegacySynAck, false)))
+            },
+            (HandshakeMessage::LegacyAck, Some(HandshakeState::SynAckSent)) => {
+                Ok((HandshakeState::Completed, None, Step::reply(HandshakeMessage::LegacyAck, true)))
+            },
+            (message, state) => Err(HandshakeError::Unexpected { message: message.kind(), direction: "request", state }),
+        });
+        self.apply(peer_id, result, now)
+    }
+    pub fn on_response(&mut self, peer_id: PeerId, payload: &[u8], now: SystemTime) -> Result<Step, HandshakeError> {
+        let state = self.state(&peer_id);
+        let sent = self.peers.get(&peer_id).and_then(|entry| entry.challenge);
+        let result = HandshakeMessage::parse(payload).and_then(|message| match (message, state, sent) {
+            (HandshakeMessage::SynAck(signed), Some(HandshakeState::SynSent), Some(sent)) => {
+                if signed.challenge != sent {
+                    return Err(HandshakeError::NonceMismatch);
+                }
+                self.check_fresh(signed.signed_at, now)?;
+                self.check_unseen(signed.challenge.nonce, now)?;
+                let proof = LivenessProof { requester: self.local_peer_id, responder: peer_id, signed };
+                proof.verify()?;
+                let step = Step { reply: Some(HandshakeMessage::Ack), completed: true, proof: Some(proof) };
+                Ok((HandshakeState::Completed, Some(sent), step))
+            },
+            // The responder acknowledging our ACK.
+            (HandshakeMessage::Ack, Some(HandshakeState::Completed), sent) => {
+                Ok((HandshakeState::Completed, sent, Step { reply: None, completed: false, proof: None }))
+            },
+            (message, state, _) => Err(HandshakeError::Unexpected { message: message.kind(), direction: "response", state }),
+        });
+        self.apply(peer_id, result, now)
+    }
+    // Responses negotiated under the first version, the SYNACK carries no proof.
+    pub fn on_legacy_response(&mut self, peer_id: PeerId, payload: &[u8], now: SystemTime) -> Result<Step, HandshakeError> {
+        let state = self.state(&peer_id);
+        let result = HandshakeMessage::parse_legacy(payload).and_then(|message| match (message, state) {
+            (HandshakeMessage::LegacySynAck, Some(HandshakeState::SynSent)) => {
+                Ok((HandshakeState::Completed, None, Step::reply(HandshakeMessage::LegacyAck, true)))
+            },
+            (HandshakeMessage::LegacyAck, Some(HandshakeState::Completed)) => {
+                Ok((HandshakeState::Completed, None, Step { reply: None, completed: false, proof: None }))
+            },
+            (message, state) => Err(HandshakeError::Unexpected { message: message.kind(), direction: "response", state }),
+        });
+        self.apply(peer_id, result, now)
+    }
+    fn apply(
+        &mut self,
+        peer_id: PeerId,
+        result: Result<(HandshakeState, Option<Challenge>, Step), HandshakeError>,
+        now: SystemTime
+    ) -> Result<Step, HandshakeError> {
+        match result {
+            Ok((state, challenge, step)) => {
+                self.peers.insert(peer_id, Entry { state, since: now, challenge });
+                Ok(step)
+            },
+            Err(error) => {
+                self.peers.remove(&peer_id);
+                Err(error)
+            },
+        }
+    }
+    // Earliest time a handshake in progress times out.
+    pub fn next_deadline(&self) -> Option<SystemTime> {
+        self.peers
+            .values()
+            .filter(|entry| entry.state != HandshakeState::Completed)
+            .map(|entry| entry.since + self.timeout)
+            .min()
+    }
+    // Drops stale entries and returns the peers whose handshake timed out.
+    pub fn expire(&mut self, now: SystemTime) -> Vec<PeerId> {
+        let timeout = self.timeout;
+        let mut timed_out = Vec::new();
+        self.peers.retain(|peer_id, entry| {
+            if now.duration_since(entry.since).unwrap_or_default() < timeout {
+                return true;
+            }
+            if entry.state != HandshakeState::Completed {
+                timed_out.push(*peer_id);
+            }
+            false
+        });
+        self.forget_stale_nonces(now);
+        timed_out
+    }
+    // Messages older than the window are rejected as stale, their nonces can go.
+    fn forget_stale_nonces(&mut self, now: SystemTime) {
+        let replay_window = self.replay_window;
+        self.seen.retain(|_, at| now.duration_since(*at).unwrap_or_default() <= replay_window);
+    }
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    const WINDOW: Duration = Duration::from_secs(60);
+    const SESSIONS: usize = 2;
+
+    fn handshakes() -> (Handshakes, PeerId) {
+        let handshakes = Handshakes::new(Keypair::generate_ed25519(), TIMEOUT, WINDOW, SESSIONS);
+        let peer_id = handshakes.local_peer_id;
+        (handshakes, peer_id)
+    }
+
+    #[test]
+    fn requester_and_responder_complete() {
+        let now = SystemTime::now();
+        let (mut requester, requester_id) = handshakes();
+        let (mut responder, responder_id) = handshakes();
+        let syn = requester.start(responder_id, now);
+        let step = responder.on_request(requester_id, &syn.to_bytes(), now).unwrap();
+        assert!(!step.completed);
+        assert!(responder.in_progress(&requester_id));
+        let synack = step.reply.unwrap();
+        assert_eq!(synack.kind(), "SYNACK");
+        let step = requester.on_response(responder_id, &synack.to_bytes(), now).unwrap();
+        assert!(step.completed);
+        assert_eq!(step.reply, Some(HandshakeMessage::Ack));
+        let proof = step.proof.unwrap();
+        assert_eq!((proof.requester, proof.responder), (requester_id, responder_id));
+        assert_eq!(proof.verify(), Ok(()));
+        let step = responder.on_request(requester_id, &HandshakeMessage::Ack.to_bytes(), now).unwrap();
+        assert_eq!(step, Step::reply(HandshakeMessage::Ack, true));
+        assert!(!requester.on_response(responder_id, &HandshakeMessage::Ack.to_bytes(), now).unwrap().completed);
+        assert_eq!(requester.state(&responder_id), Some(HandshakeState::Completed));
+        assert_eq!(responder.state(&requester_id), Some(HandshakeState::Completed));
+    }
+
+    #[test]
+    fn legacy_peers_exchange_bare_tags() {
+        let now = SystemTime::now();
+        let (mut requester, requester_id) = handshakes();
+        let (mut responder, responder_id) = handshakes();
+        let syn = requester.start(responder_id, now).to_legacy();
+        assert_eq!(syn.to_bytes(), b"SYN");
+        let step = responder.on_legacy_request(requester_id, &syn.to_bytes(), now).unwrap();
+        assert_eq!(step, Step::reply(HandshakeMessage::LegacySynAck, false));
+        let step = requester.on_legacy_response(responder_id, b"SYNACK", now).unwrap();
+        assert!(step.completed);
+        assert_eq!(step.proof, None);
+        assert_eq!(step.reply, Some(HandshakeMessage::LegacyAck));
+        assert!(responder.on_legacy_request(requester_id, b"ACK", now).unwrap().completed);
+        // A versioned SYN is not a legacy one.
+        let syn = HandshakeMessage::Syn(Challenge::new(now)).to_bytes();
+        assert_eq!(responder.on_legacy_request(requester_id, &syn, now), Err(HandshakeError::UnknownPayload(syn.len())));
+    }
+
+    #[test]
+    fn forged_and_replayed_synacks_are_rejected() {
+        let now = SystemTime::now();
+        let (mut requester, requester_id) = handshakes();
+        let (mut responder, responder_id) = handshakes();
+        let (mut impostor, _) = handshakes();
+
+        // Signed with a key that is not the one of the responder PeerId.
+        let syn = requester.start(responder_id, now);
+        let forged = impostor.on_request(requester_id, &syn.to_bytes(), now).unwrap().reply.unwrap();
+        assert_eq!(requester.on_response(responder_id, &forged.to_bytes(), now), Err(HandshakeError::KeyMismatch));
+
+        let syn = requester.start(responder_id, now);
+        let mut synack = match responder.on_request(requester_id, &syn.to_bytes(), now).unwrap().reply {
+            Some(HandshakeMessage::SynAck(signed)) => signed,
+            other => panic!("Unexpected reply : {:?}", other),
+        };
+        let valid = HandshakeMessage::SynAck(synack.clone()).to_bytes();
+        synack.signature[0] ^= 0xff;
+        let tampered = HandshakeMessage::SynAck(synack).to_bytes();
+        assert_eq!(requester.on_response(responder_id, &tampered, now), Err(HandshakeError::InvalidSignature));
+
+        // A SYNACK answering an earlier challenge.
+        requester.start(responder_id, now);
+        assert_eq!(requester.on_response(responder_id, &valid, now), Err(HandshakeError::NonceMismatch));
+        // The same SYN sent twice.
+        responder.abort(&requester_id);
+        assert_eq!(responder.on_request(requester_id, &syn.to_bytes(), now), Err(HandshakeError::Replay));
+    }
+
+    #[test]
+    fn concurrent_sessions_are_limited() {
+        let now = SystemTime::now();
+        let (mut responder, _) = handshakes();
+        let syn = || HandshakeMessage::Syn(Challenge::new(now)).to_bytes();
+        let peers: Vec<PeerId> = (0..SESSIONS).map(|_| PeerId::random()).collect();
+        for peer_id in &peers {
+            responder.on_request(*peer_id, &syn(), now).unwrap();
+        }
+        let refused = PeerId::random();
+        assert_eq!(responder.on_request(refused, &syn(), now), Err(HandshakeError::TooManySessions(SESSIONS)));
+        // A completed session frees its slot.
+        responder.on_request(peers[0], &HandshakeMessage::Ack.to_bytes(), now).unwrap();
+        assert_eq!(responder.sessions(), SESSIONS - 1);
+        assert!(responder.on_request(refused, &syn(), now).is_ok());
+    }
+
+    #[test]
+    fn stale_challenges_are_rejected() {
+        let now = SystemTime::now();
+        let (mut requester, requester_id) = handshakes();
+        let (mut responder, responder_id) = handshakes();
+        let syn = requester.start(responder_id, now - WINDOW * 2);
+        assert_eq!(responder.on_request(requester_id, &syn.to_bytes(), now), Err(HandshakeError::Stale));
+    }
+
+    #[test]
+    fn unexpected_messages_reset_the_peer() {
+        let now = SystemTime::now();
+        let (mut handshakes, _) = handshakes();
+        let peer_id = PeerId::random();
+        assert_eq!(
+            handshakes.on_request(peer_id, &HandshakeMessage::Ack.to_bytes(), now),
+            Err(HandshakeError::Unexpected { message: "ACK", direction: "request", state: None })
+        );
+        let syn = HandshakeMessage::Syn(Challenge::new(now));
+        handshakes.on_request(peer_id, &syn.to_bytes(), now).unwrap();
+        assert!(handshakes.on_request(peer_id, &HandshakeMessage::Syn(Challenge::new(now)).to_bytes(), now).is_err());
+        assert_eq!(handshakes.state(&peer_id), None);
+        handshakes.start(peer_id, now);
+        assert!(handshakes.on_response(peer_id, &HandshakeMessage::Ack.to_bytes(), now).is_err());
+        assert_eq!(handshakes.state(&peer_id), None);
+    }
+
+    #[test]
+    fn unknown_and_malformed_payloads_are_rejected() {
+        let now = SystemTime::now();
+        let (mut handshakes, _) = handshakes();
+        let peer_id = PeerId::random();
+        assert_eq!(handshakes.on_request(peer_id, &[0xff, 0xfe, 0x00], now), Err(HandshakeError::UnknownPayload(3)));
+        assert_eq!(handshakes.on_response(peer_id, b"syn", now), Err(HandshakeError::UnknownPayload(3)));
+        assert_eq!(handshakes.on_request(peer_id, b"\x01\x01\x02", now), Err(HandshakeError::Malformed("truncated payload")));
+        assert_eq!(handshakes.on_request(peer_id, b"\x03\x00", now), Err(HandshakeError::Malformed("trailing bytes")));
+        let mut synack = vec![SYNACK_TAG];
+        synack.extend_from_slice(&[0; NONCE_LEN + 16]);
+        synack.extend_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(handshakes.on_response(peer_id, &synack, now), Err(HandshakeError::Malformed("truncated payload")));
+    }
+
+    #[test]
+    fn tags_do_not_depend_on_the_nonce() {
+        let now = SystemTime::now();
+        let (mut responder, _) = handshakes();
+        let mut challenge = Challenge::new(now);
+        challenge.nonce[..3].copy_from_slice(b"ACK");
+        let syn = HandshakeMessage::Syn(challenge);
+        assert_eq!(HandshakeMessage::parse(&syn.to_bytes()), Ok(syn.clone()));
+        assert!(responder.on_request(PeerId::random(), &syn.to_bytes(), now).is_ok());
+    }
+
+    #[test]
+    fn nonces_are_forgotten_after_the_replay_window() {
+        let now = SystemTime::now();
+        let (mut responder, _) = handshakes();
+        let syn = HandshakeMessage::Syn(Challenge::new(now)).to_bytes();
+        responder.on_request(PeerId::random(), &syn, now).unwrap();
+        assert_eq!(responder.seen.len(), 1);
+        // Without any deadline running `expire`, the next request prunes them.
+        let later = now + WINDOW + Duration::from_secs(1);
+        let syn = HandshakeMessage::Syn(Challenge::new(later)).to_bytes();
+        responder.on_request(PeerId::random(), &syn, later).unwrap();
+        assert_eq!(responder.seen.len(), 1);
+    }
+
+    #[test]
+    fn stalled_handshakes_time_out() {
+        let now = SystemTime::now();
+        let (mut requester, requester_id) = handshakes();
+        let (mut responder, completed) = handshakes();
+        let stalled = PeerId::random();
+        requester.start(stalled, now);
+        let syn = requester.start(completed, now);
+        let synack = responder.on_request(requester_id, &syn.to_bytes(), now).unwrap().reply.unwrap();
+        requester.on_response(completed, &synack.to_bytes(), now).unwrap();
+        assert_eq!(requester.next_deadline(), Some(now + TIMEOUT));
+        assert!(requester.expire(now + TIMEOUT / 2).is_empty());
+        assert_eq!(requester.expire(now + TIMEOUT), vec![stalled]);
+        assert_eq!(requester.state(&completed), None);
+        assert_eq!(requester.next_deadline(), None);
+    }
+}
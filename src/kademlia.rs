@@ -0,0 +1,399 @@
+// Kademlia behaviour wrapper that can switch between client and server mode.
+//
+// `Kademlia::new_handler` always allows inbound substreams, which makes every
+// node answer DHT requests and advertise the protocol through identify. The
+// wrapper wraps the connection handlers so that inbound Kademlia substreams are
+// only accepted while in server mode. The mode is shared with the handlers and read
+// on every inbound substream, a switch applies to the open connections as well.
+// Identify is wrapped too, the swarm computes the protocols it advertises once, from
+// a handler built in the mode the node started in.
+
+use std::borrow::Cow;
+use std::ops::{Deref, DerefMut};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc
+};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use libp2p_core::{
+    connection::ConnectionId,
+    upgrade::{DeniedUpgrade, EitherUpgrade},
+    ConnectedPoint,
+    Multiaddr,
+    PeerId
+};
+use libp2p_kad::{
+    handler::{
+        KademliaHandler,
+        KademliaHandlerConfig,
+        KademliaHandlerProto
+    },
+    protocol::KademliaProtocolConfig,
+    record::store::MemoryStore,
+    Kademlia,
+    KademliaConfig,
+    KademliaEvent,
+    QueryId
+};
+use libp2p::identify;
+use libp2p_swarm::{
+    behaviour::{
+        ConnectionClosed,
+        DialFailure,
+        FromSwarm,
+        ListenFailure
+    },
+    handler::ConnectionEvent,
+    ConnectionHandler,
+    ConnectionHandlerEvent,
+    IntoConnectionHandler,
+    KeepAlive,
+    NetworkBehaviour,
+    NetworkBehaviourAction,
+    PollParameters,
+    SubstreamProtocol
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KademliaMode {
+    // Runs queries but neither advertises nor answers the Kademlia protocol.
+    Client,
+    // Answers inbound Kademlia requests.
+    Server,
+}
+
+impl Default for KademliaMode {
+    fn default() -> Self {
+        KademliaMode::Server
+    }
+}
+
+pub struct SwitchableKademlia {
+    inner: Kademlia<MemoryStore>,
+    // Whether inbound substreams are accepted, shared with every connection handler.
+    serving: Arc<AtomicBool>,
+    protocol_config: KademliaProtocolConfig,
+    idle_timeout: Duration,
+}
+
+impl SwitchableKademlia {
+    pub fn new(
+        peer_id: PeerId,
+        store: MemoryStore,
+        mut config: KademliaConfig,
+        protocol_names: Option<Vec<Cow<'static, [u8]>>>,
+        idle_timeout: Duration,
+        mode: KademliaMode
+    ) -> Self {
+        // The handler configuration is not readable back from the `KademliaConfig`,
+        // so both are fed from the same values.
+        let mut protocol_config = KademliaProtocolConfig::default();
+        if let Some(protocol_names) = protocol_names {
+            config.set_protocol_names(protocol_names.clone());
+            protocol_config.set_protocol_names(protocol_names);
+        }
+        config.set_connection_idle_timeout(idle_timeout);
+        SwitchableKademlia {
+            inner: Kademlia::with_config(peer_id, store, config),
+            serving: Arc::new(AtomicBool::new(mode == KademliaMode::Server)),
+            protocol_config,
+            idle_timeout,
+        }
+    }
+    pub fn mode(&self) -> KademliaMode {
+        if self.serving.load(Ordering::Relaxed) {
+            KademliaMode::Server
+        } else {
+            KademliaMode::Client
+        }
+    }
+    // Applies to the substreams opened after the switch, on new and open connections.
+    // Connected peers learn about it from the identify push of
+    // `LookupClient::set_kademlia_mode`.
+    pub fn set_mode(&mut self, mode: KademliaMode) {
+        self.serving.store(mode == KademliaMode::Server, Ordering::Relaxed);
+    }
+    pub fn protocol_names(&self) -> Vec<Vec<u8>> {
+        self.protocol_config.protocol_names().iter().map(|name| name.to_vec()).collect()
+    }
+}
+
+impl Deref for SwitchableKademlia {
+    type Target = Kademlia<MemoryStore>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for SwitchableKademlia {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+// The Kademlia handlers always listen, the wrapper denies inbound substreams while the
+// node is in client mode.
+pub struct ModeHandlerProto {
+    inner: KademliaHandlerProto<QueryId>,
+    serving: Arc<AtomicBool>,
+}
+
+pub struct ModeHandler {
+    inner: KademliaHandler<QueryId>,
+    serving: Arc<AtomicBool>,
+}
+
+impl IntoConnectionHandler for ModeHandlerProto {
+    type Handler = ModeHandler;
+
+    fn into_handler(self, remote_peer_id: &PeerId, endpoint: &ConnectedPoint) -> Self::Handler {
+        ModeHandler {
+            inner: self.inner.into_handler(remote_peer_id, endpoint),
+            serving: self.serving,
+        }
+    }
+
+    fn inbound_protocol(&self) -> <Self::Handler as ConnectionHandler>::InboundProtocol {
+        if self.serving.load(Ordering::Relaxed) {
+            self.inner.inbound_protocol()
+        } else {
+            EitherUpgrade::B(DeniedUpgrade)
+        }
+    }
+}
+
+impl ConnectionHandler for ModeHandler {
+    type InEvent = <KademliaHandler<QueryId> as ConnectionHandler>::InEvent;
+    type OutEvent = <KademliaHandler<QueryId> as ConnectionHandler>::OutEvent;
+    type Error = <KademliaHandler<QueryId> as ConnectionHandler>::Error;
+    type InboundProtocol = <KademliaHandler<QueryId> as ConnectionHandler>::InboundProtocol;
+    type OutboundProtocol = <KademliaHandler<QueryId> as ConnectionHandler>::OutboundProtocol;
+    type InboundOpenInfo = <KademliaHandler<QueryId> as ConnectionHandler>::InboundOpenInfo;
+    type OutboundOpenInfo = <KademliaHandler<QueryId> as ConnectionHandler>::OutboundOpenInfo;
+
+    // Asked for every inbound substream, so the current mode applies.
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        if self.serving.load(Ordering::Relaxed) {
+            self.inner.listen_protocol()
+        } else {
+            SubstreamProtocol::new(EitherUpgrade::B(DeniedUpgrade), ())
+        }
+    }
+
+    fn on_behaviour_event(&mut self, event: Self::InEvent) {
+        self.inner.on_behaviour_event(event)
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::OutEvent, Self::Error>> {
+        self.inner.poll(cx)
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol, Self::InboundOpenInfo, Self::OutboundOpenInfo>,
+    ) {
+        self.inner.on_connection_event(event)
+    }
+}
+
+// Hands the swarm events to the inner behaviour with the handlers it built.
+fn inner_event(event: FromSwarm<ModeHandlerProto>) -> FromSwarm<KademliaHandlerProto<QueryId>> {
+    match event {
+        FromSwarm::ConnectionEstablished(event) => FromSwarm::ConnectionEstablished(event),
+        FromSwarm::ConnectionClosed(ConnectionClosed { peer_id, connection_id, endpoint, handler, remaining_established }) => {
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                connection_id,
+                endpoint,
+                handler: handler.inner,
+                remaining_established,
+            })
+        },
+        FromSwarm::AddressChange(event) => FromSwarm::AddressChange(event),
+        FromSwarm::DialFailure(DialFailure { peer_id, handler, error }) => {
+            FromSwarm::DialFailure(DialFailure {
+                peer_id,
+                handler: handler.inner,
+                error,
+            })
+        },
+        FromSwarm::ListenFailure(ListenFailure { local_addr, send_back_addr, handler }) => {
+            FromSwarm::ListenFailure(ListenFailure {
+                local_addr,
+                send_back_addr,
+                handler: handler.inner,
+            })
+        },
+        FromSwarm::NewListener(event) => FromSwarm::NewListener(event),
+        FromSwarm::NewListenAddr(event) => FromSwarm::NewListenAddr(event),
+        FromSwarm::ExpiredListenAddr(event) => FromSwarm::ExpiredListenAddr(event),
+        FromSwarm::ListenerError(event) => FromSwarm::ListenerError(event),
+        FromSwarm::ListenerClosed(event) => FromSwarm::ListenerClosed(event),
+        FromSwarm::NewExternalAddr(event) => FromSwarm::NewExternalAddr(event),
+        FromSwarm::ExpiredExternalAddr(event) => FromSwarm::ExpiredExternalAddr(event),
+    }
+}
+
+impl NetworkBehaviour for SwitchableKademlia {
+    type ConnectionHandler = ModeHandlerProto;
+    type OutEvent = KademliaEvent;
+
+    fn new_handler(&mut self) -> Self::ConnectionHandler {
+        ModeHandlerProto {
+            inner: KademliaHandlerProto::new(KademliaHandlerConfig {
+                protocol_config: self.protocol_config.clone(),
+                allow_listening: true,
+                idle_timeout: self.idle_timeout,
+            }),
+            serving: self.serving.clone(),
+        }
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        self.inner.on_swarm_event(inner_event(event))
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: <<Self::ConnectionHandler as IntoConnectionHandler>::Handler as ConnectionHandler>::OutEvent,
+    ) {
+        self.inner.on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+        self.inner.poll(cx, params).map(|action| {
+            action.map_handler(|handler| ModeHandlerProto {
+                inner: handler,
+                serving: self.serving.clone(),
+            })
+        })
+    }
+}
+
+// Identify advertising the Kademlia protocols only while in server mode.
+pub struct ModeAwareIdentify {
+    inner: identify::Behaviour,
+    kademlia_protocols: Vec<Vec<u8>>,
+    mode: KademliaMode,
+}
+
+impl ModeAwareIdentify {
+    pub fn new(inner: identify::Behaviour, kademlia_protocols: Vec<Vec<u8>>, mode: KademliaMode) -> Self {
+        ModeAwareIdentify {
+            inner,
+            kademlia_protocols,
+            mode,
+        }
+    }
+    pub fn set_kademlia_mode(&mut self, mode: KademliaMode) {
+        self.mode = mode;
+    }
+}
+
+impl Deref for ModeAwareIdentify {
+    type Target = identify::Behaviour;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for ModeAwareIdentify {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+// The parameters of the swarm, with the Kademlia protocols added or removed.
+struct ModeParameters<'a, P> {
+    params: &'a P,
+    kademlia_protocols: &'a [Vec<u8>],
+    mode: KademliaMode,
+}
+
+impl<'a, P: PollParameters> PollParameters for ModeParameters<'a, P> {
+    type SupportedProtocolsIter = std::vec::IntoIter<Vec<u8>>;
+    type ListenedAddressesIter = P::ListenedAddressesIter;
+    type ExternalAddressesIter = P::ExternalAddressesIter;
+
+    fn supported_protocols(&self) -> Self::SupportedProtocolsIter {
+        let mut protocols: Vec<Vec<u8>> = self.params
+            .supported_protocols()
+            .filter(|protocol| !self.kademlia_protocols.contains(protocol))
+            .collect();
+        if self.mode == KademliaMode::Server {
+            protocols.extend(self.kademlia_protocols.iter().cloned());
+        }
+        protocols.into_iter()
+    }
+
+    fn listened_addresses(&self) -> Self::ListenedAddressesIter {
+        self.params.listened_addresses()
+    }
+
+    fn external_addresses(&self) -> Self::ExternalAddressesIter {
+        self.params.external_addresses()
+    }
+
+    fn local_peer_id(&self) -> &PeerId {
+        self.params.local_peer_id()
+    }
+}
+
+impl NetworkBehaviour for ModeAwareIdentify {
+    type ConnectionHandler = <identify::Behaviour as NetworkBehaviour>::ConnectionHandler;
+    type OutEvent = identify::Event;
+
+    fn new_handler(&mut self) -> Self::ConnectionHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        self.inner.on_swarm_event(event)
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: <<Self::ConnectionHandler as IntoConnectionHandler>::Handler as ConnectionHandler>::OutEvent,
+    ) {
+        self.inner.on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+        let mut params = ModeParameters {
+            params: &*params,
+            kademlia_protocols: &self.kademlia_protocols,
+            mode: self.mode,
+        };
+        self.inner.poll(cx, &mut params)
+    }
+}
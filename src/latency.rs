@@ -0,0 +1,160 @@
+// Ping round trip statistics per peer.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use libp2p_core::PeerId;
+use crate::{EvictionPolicy, LookupClient};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub latest: Option<Duration>,
+    // Computed over the sliding window of the last successful pings.
+    pub min: Option<Duration>,
+    pub avg: Option<Duration>,
+    pub max: Option<Duration>,
+    pub samples: usize,
+    pub failures: u32,
+}
+
+#[derive(Debug)]
+struct PeerLatency {
+    rtts: VecDeque<Duration>,
+    failures: u32,
+    updated: DateTime<Utc>,
+}
+
+// Peers stay ranked after they disconnect, so the statistics are bounded by the peer store
+// eviction policy rather than dropped on disconnection.
+#[derive(Debug)]
+pub struct LatencyTracker {
+    window: usize,
+    peers: HashMap<PeerId, PeerLatency>,
+    eviction: EvictionPolicy,
+}
+
+impl LatencyTracker {
+    pub fn new(window: usize, eviction: EvictionPolicy) -> Self {
+        LatencyTracker {
+            window: window.max(1),
+            peers: HashMap::new(),
+            eviction,
+        }
+    }
+    pub fn record_rtt(&mut self, peer_id: PeerId, rtt: Duration) {
+        let window = self.window;
+        let latency = self.entry(peer_id, Utc::now());
+        if latency.rtts.len() == window {
+            latency.rtts.pop_front();
+        }
+        latency.rtts.push_back(rtt);
+    }
+    pub fn record_failure(&mut self, peer_id: PeerId) {
+        self.entry(peer_id, Utc::now()).failures += 1;
+    }
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+    fn entry(&mut self, peer_id: PeerId, now: DateTime<Utc>) -> &mut PeerLatency {
+        // Room is made for new peers only, updates leave the tracker as it is.
+        if !self.peers.contains_key(&peer_id) {
+            self.evict(now);
+        }
+        let latency = self.peers.entry(peer_id).or_insert_with(|| PeerLatency {
+            rtts: VecDeque::new(),
+            failures: 0,
+            updated: now,
+        });
+        latency.updated = now;
+        latency
+    }
+    fn evict(&mut self, now: DateTime<Utc>) {
+        if let Some(max_age) = self.eviction.max_age.and_then(|age| chrono::Duration::from_std(age).ok()) {
+            self.peers.retain(|_, latency| now - latency.updated <= max_age);
+        }
+        if let Some(max_peers) = self.eviction.max_peers {
+            while !self.peers.is_empty() && self.peers.len() >= max_peers {
+                let oldest = self.peers
+                    .iter()
+                    .min_by_key(|(_, latency)| latency.updated)
+                    .map(|(peer_id, _)| *peer_id);
+                if let Some(peer_id) = oldest {
+                    self.peers.remove(&peer_id);
+                }
+            }
+        }
+    }
+    pub fn stats(&self, peer_id: &PeerId) -> Option<LatencyStats> {
+        let latency = self.peers.get(peer_id)?;
+        let samples = latency.rtts.len();
+        let avg = if samples > 0 {
+            Some(latency.rtts.iter().sum::<Duration>() / samples as u32)
+        } else {
+            None
+        };
+        Some(LatencyStats {
+            latest: latency.rtts.back().cloned(),
+            min: latency.rtts.iter().min().cloned(),
+            avg,
+            max: latency.rtts.iter().max().cloned(),
+            samples,
+            failures: latency.failures,
+        })
+    }
+}
+
+impl LookupClient {
+    pub fn latency(&self, peer_id: &PeerId) -> Option<LatencyStats> {
+        self.latency.stats(peer_id)
+    }
+    // Orders peers to dial by average round trip time, peers never pinged come last.
+    pub fn rank_peers(&self, peers: &mut [PeerId]) {
+        peers.sort_by_key(|peer_id| {
+            let avg = self.latency.stats(peer_id).and_then(|stats| stats.avg);
+            (avg.is_none(), avg)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_window_statistics() {
+        let mut tracker = LatencyTracker::new(3, EvictionPolicy::default());
+        let peer_id = PeerId::random();
+        assert!(tracker.stats(&peer_id).is_none());
+        for ms in [40, 10, 20, 30] {
+            tracker.record_rtt(peer_id, Duration::from_millis(ms));
+        }
+        tracker.record_failure(peer_id);
+        let stats = tracker.stats(&peer_id).unwrap();
+        assert_eq!(stats.latest, Some(Duration::from_millis(30)));
+        assert_eq!(stats.min, Some(Duration::from_millis(10)));
+        assert_eq!(stats.avg, Some(Duration::from_millis(20)));
+        assert_eq!(stats.max, Some(Duration::from_millis(30)));
+        assert_eq!(stats.samples, 3);
+        assert_eq!(stats.failures, 1);
+    }
+
+    #[test]
+    fn evicts_by_size_and_age() {
+        let mut tracker = LatencyTracker::new(3, EvictionPolicy {
+            max_peers: Some(2),
+            max_age: Some(Duration::from_secs(60)),
+        });
+        let t0 = Utc::now();
+        let oldest = PeerId::random();
+        tracker.entry(oldest, t0);
+        tracker.entry(PeerId::random(), t0 + chrono::Duration::seconds(1));
+        tracker.entry(PeerId::random(), t0 + chrono::Duration::seconds(2));
+        assert_eq!(tracker.len(), 2);
+        assert!(tracker.stats(&oldest).is_none());
+        tracker.entry(PeerId::random(), t0 + chrono::Duration::seconds(120));
+        assert_eq!(tracker.len(), 1);
+    }
+}
@@ -1,17 +1,23 @@
 use std::borrow::{BorrowMut};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
+use std::sync::{Arc, RwLock};
+use chrono::{DateTime, Utc};
 use futures::{
+    channel::mpsc,
     executor::block_on,
+    future::FutureExt,
     stream::{
         StreamExt,
     },
 };
 use libp2p::relay::v2::client::Client;
-use libp2p::request_response::{RequestResponseCodec, RequestResponse};
-use std::time::Duration;
+use libp2p::request_response::RequestResponse;
+use std::time::{Duration, Instant, SystemTime};
 use libp2p_core::{
     self,
     transport::{
+        ListenerId,
         OrTransport,
         Transport,
         Boxed
@@ -26,17 +32,21 @@ use libp2p_core::{
 };
 use libp2p_kad::{
     record::store::MemoryStore,
-    Kademlia,
-    KademliaConfig,
     KademliaEvent,
     QueryResult,
     GetClosestPeersOk
 };
 use libp2p::swarm::{
+    dial_opts::DialOpts,
+    AddressScore,
+    ConnectionHandler,
+    DialError,
+    IntoConnectionHandler,
     Swarm,
     SwarmBuilder,
     SwarmEvent,
-    NetworkBehaviour
+    NetworkBehaviour,
+    PendingConnectionError
 };
 use libp2p::relay::v2::client::transport::ClientTransport;
 use libp2p::{
@@ -56,40 +66,240 @@ use thiserror::Error;
 use std::str::FromStr;
 #[cfg(feature = "request-response")]
 use libp2p::request_response;
+
+mod kademlia;
+pub use kademlia::{
+    KademliaMode,
+    ModeAwareIdentify,
+    SwitchableKademlia
+};
+mod address;
+pub use address::{
+    address_scope,
+    AddressPolicy,
+    AddressScope
+};
+mod peer_store;
+pub use peer_store::{
+    EvictionPolicy,
+    PeerRecord,
+    PeerStore
+};
+mod dialing;
+pub use dialing::{
+    AddressBook,
+    AddressStats,
+    TransportKind
+};
+mod discovery;
+mod latency;
+mod gate;
+mod shutdown;
+mod handshake;
+pub use handshake::{
+    Challenge,
+    HandshakeError,
+    HandshakeMessage,
+    HandshakeState,
+    LivenessProof,
+    SignedChallenge
+};
+#[cfg(feature = "test-protocol")]
+use handshake::Handshakes;
+#[cfg(feature = "test-protocol")]
+mod handler;
+#[cfg(feature = "test-protocol")]
+pub use handler::{
+    HandlerError,
+    RequestHandler,
+    TestRequestHandler
+};
+#[cfg(feature = "test-protocol")]
+use handler::Handlers;
+#[cfg(feature = "test-protocol")]
+mod server;
+#[cfg(feature = "test-protocol")]
+pub use server::HandshakeEvent;
+#[cfg(feature = "codec")]
+pub use protocol_codec::{
+    Encoding,
+    MessageKind,
+    MessageTooLarge,
+    Negotiated,
+    SerdeCodec,
+    SerdeProtocol,
+    SizeLimits,
+    VersionedCodec
+};
+// The message types, versions and codec of the SYN/SYNACK handshake are declared in
+// 'protocols/test-protocol/src/lib.rs' with `test_protocol::declare_protocol!`.
 #[cfg(feature = "test-protocol")]
-use std::iter;
+pub use test_protocol::{
+    test_protocol_versions,
+    test_protocol_versions_with_legacy,
+    TestCodec,
+    TestProtocol,
+    TEST_PROTOCOL,
+    TEST_PROTOCOL_JSON,
+    TEST_PROTOCOL_LEGACY
+};
+#[cfg(feature = "file-transfer")]
+mod transfer;
+#[cfg(feature = "file-transfer")]
+pub use transfer::{
+    Sha256Digest,
+    TransferCodec,
+    TransferError,
+    TransferEvent,
+    TransferRequest,
+    TransferResponse,
+    FILE_TRANSFER_PROTOCOL
+};
+#[cfg(feature = "file-transfer")]
+use transfer::Transfers;
+use gate::SharedGate;
+pub use shutdown::ShutdownReport;
+pub use gate::{
+    ConnectionDenial,
+    ConnectionGate,
+    Direction,
+    GateError,
+    Rejection,
+    RejectionReason
+};
+pub use latency::{
+    LatencyStats,
+    LatencyTracker
+};
+mod reputation;
+pub use reputation::{
+    Misbehaviour,
+    Reputation,
+    ReputationConfig
+};
+mod config;
+pub use config::{
+    ConfigError,
+    ConnectionLimitSettings,
+    KademliaSettings,
+    LookupConfig
+};
+mod routing;
+pub use routing::{
+    BucketInfo,
+    BucketPosition,
+    ConnectionStatus,
+    RoutingEntry
+};
+#[cfg(feature = "serde")]
+mod serde_util;
+#[cfg(feature = "crawler")]
+mod crawler;
+#[cfg(feature = "crawler")]
+pub use crawler::{
+    Census,
+    CensusEntry,
+    CrawlError,
+    Crawler,
+    CrawlerConfig,
+    Reachability
+};
 
 #[derive(libp2p_swarm::NetworkBehaviour)]
 pub struct LookupBehaviour {
-    pub(crate) kademlia: Kademlia<MemoryStore>,
+    pub(crate) kademlia: SwitchableKademlia,
     pub(crate) ping: ping::Behaviour,
-    pub(crate) identify: identify::Behaviour,
+    pub(crate) identify: ModeAwareIdentify,
     #[cfg(feature = "test-protocol")]
     pub request_response: RequestResponse<TestCodec>,
+    #[cfg(feature = "file-transfer")]
+    pub(crate) file_transfer: RequestResponse<TransferCodec>,
     relay: relay::client::Client,
     keep_alive: libp2p_swarm::keep_alive::Behaviour,
 }
 
+pub type LookupSwarmEvent = SwarmEvent<
+    LookupBehaviourEvent,
+    <<<LookupBehaviour as NetworkBehaviour>::ConnectionHandler as IntoConnectionHandler>::Handler as ConnectionHandler>::Error
+>;
+
+// What woke `next_event` up.
+enum Wakeup {
+    Swarm(LookupSwarmEvent),
+    #[cfg(feature = "test-protocol")]
+    Handled(handler::Handled<test_protocol::SYNACK>),
+    #[cfg(feature = "file-transfer")]
+    FileJob(transfer::JobDone),
+    BanExpired,
+}
+
 pub struct LookupClient {
     // local_key: Keypair,
     pub local_peer_id: PeerId,
     pub listen_addrs: Vec<Multiaddr>,
     pub network: Vec<Network>,
-    pub swarm: Swarm<LookupBehaviour>
+    pub swarm: Swarm<LookupBehaviour>,
+    pub(crate) config: LookupConfig,
+    pub(crate) peer_store: PeerStore,
+    pub(crate) address_book: AddressBook,
+    pub(crate) reputation: Reputation,
+    pub(crate) latency: LatencyTracker,
+    pub(crate) last_seen: HashMap<PeerId, DateTime<Utc>>,
+    // Remote address of the first established connection to each connected peer.
+    pub(crate) connected_addrs: HashMap<PeerId, Multiaddr>,
+    // Addresses added to Kademlia from identify that it did not know from another source.
+    pub(crate) identified_addrs: HashMap<PeerId, Vec<Multiaddr>>,
+    pub(crate) gate: Arc<RwLock<ConnectionGate>>,
+    pub(crate) rejections: gate::Rejections,
+    pub(crate) denials: VecDeque<ConnectionDenial>,
+    pub(crate) listeners: HashSet<ListenerId>,
+    // Oversized message that closed the latest connection with each peer, reported with
+    // the requests that connection failed. Dropped once the peer connects again.
+    #[cfg(feature = "codec")]
+    pub(crate) oversized: HashMap<PeerId, MessageTooLarge>,
+    // Outbound requests still waiting for a response.
+    #[cfg(feature = "test-protocol")]
+    pub(crate) pending_requests: HashMap<RequestId, PeerId>,
+    // Copy of the handshake codec, to check message sizes before sending.
+    #[cfg(feature = "test-protocol")]
+    pub(crate) codec: TestCodec,
+    #[cfg(feature = "test-protocol")]
+    pub(crate) handshakes: Handshakes,
+    // Latest verified SYNACK of each peer we requested a handshake from.
+    #[cfg(feature = "test-protocol")]
+    pub(crate) liveness_proofs: HashMap<PeerId, LivenessProof>,
+    // Protocol version of the latest completed handshake with each peer.
+    #[cfg(feature = "test-protocol")]
+    pub(crate) negotiated_versions: HashMap<PeerId, SerdeProtocol>,
+    #[cfg(feature = "test-protocol")]
+    pub(crate) handshake_events: Option<mpsc::Sender<HandshakeEvent>>,
+    // Handshakes that timed out together, reported one by one by `init_protocol`.
+    #[cfg(feature = "test-protocol")]
+    pub(crate) timed_out: VecDeque<PeerId>,
+    #[cfg(feature = "test-protocol")]
+    pub(crate) handlers: Handlers<test_protocol::SYN, test_protocol::SYNACK>,
+    #[cfg(feature = "file-transfer")]
+    pub(crate) transfers: Transfers,
 }
 
 
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Peer {
+    #[cfg_attr(feature = "serde", serde(with = "serde_util::peer_id"))]
     pub peer_id: PeerId,
     pub protocol_version: String,
     pub agent_version: String,
+    #[cfg_attr(feature = "serde", serde(with = "serde_util::multiaddrs"))]
     pub listen_addrs: Vec<Multiaddr>,
     pub protocols: Vec<String>,
+    #[cfg_attr(feature = "serde", serde(with = "serde_util::multiaddr"))]
     pub observed_addr: Multiaddr,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Network {
     Kusama
 }
@@ -104,6 +314,118 @@ pub enum NetworkError {
     NotFound,
     #[error("No Peers")]
     NoPeers,
+    #[error("Cancelled by the shutdown of the client, peer {0}")]
+    Shutdown(PeerId),
+    #[cfg(feature = "codec")]
+    #[error("{kind:?} of {size} bytes exceeds the limit of {limit} bytes")]
+    MessageTooLarge {
+        kind: MessageKind,
+        size: usize,
+        limit: usize,
+    },
+    #[error("Handshake with {peer_id} failed : {error}")]
+    Handshake {
+        peer_id: PeerId,
+        error: HandshakeError,
+    },
+    #[error("No known addresses for {0}")]
+    NoAddresses(PeerId),
+    #[error("Dial to {peer_id} refused : {reason}")]
+    DialRefused {
+        peer_id: PeerId,
+        reason: String,
+    },
+    #[error("Dial to {0} timed out")]
+    DialTimeout(PeerId),
+    #[error("Connection to {peer_id} denied, {current} of {limit} connections open")]
+    ConnectionLimit {
+        peer_id: PeerId,
+        limit: u32,
+        current: u32,
+    },
+    #[error("Dial to {peer_id} failed on {} addresses", .errors.len())]
+    DialFailed {
+        peer_id: PeerId,
+        // Each address tried along with the reason it failed.
+        errors: Vec<(Multiaddr, String)>,
+    },
+    #[cfg(feature = "request-response")]
+    #[error("Request {request_id} to {peer_id} failed, the peer could not be dialed")]
+    RequestDialFailure {
+        peer_id: PeerId,
+        request_id: RequestId,
+    },
+    #[cfg(feature = "request-response")]
+    #[error("{direction:?} request {request_id} with {peer_id} timed out")]
+    RequestTimeout {
+        peer_id: PeerId,
+        request_id: RequestId,
+        direction: Direction,
+    },
+    #[cfg(feature = "request-response")]
+    #[error("Connection with {peer_id} closed before {direction:?} request {request_id} was answered")]
+    RequestConnectionClosed {
+        peer_id: PeerId,
+        request_id: RequestId,
+        direction: Direction,
+    },
+    #[cfg(feature = "request-response")]
+    #[error("{peer_id} supports none of the protocols of {direction:?} request {request_id}")]
+    UnsupportedProtocols {
+        peer_id: PeerId,
+        request_id: RequestId,
+        direction: Direction,
+    },
+    #[cfg(feature = "codec")]
+    #[error("{direction:?} request {request_id} with {peer_id} failed : {error}")]
+    RequestTooLarge {
+        peer_id: PeerId,
+        request_id: RequestId,
+        direction: Direction,
+        error: MessageTooLarge,
+    },
+    #[cfg(feature = "request-response")]
+    #[error("Response channel closed, the connection or the request is gone")]
+    ResponseChannelClosed,
+    #[cfg(feature = "request-response")]
+    #[error("Request {request_id} from {peer_id} was left without a response")]
+    ResponseOmission {
+        peer_id: PeerId,
+        request_id: RequestId,
+    },
+}
+
+#[cfg(feature = "codec")]
+impl From<MessageTooLarge> for NetworkError {
+    fn from(error: MessageTooLarge) -> Self {
+        NetworkError::MessageTooLarge {
+            kind: error.kind,
+            size: error.size,
+            limit: error.limit,
+        }
+    }
+}
+
+#[cfg(feature = "request-response")]
+impl NetworkError {
+    pub fn from_outbound_failure(peer_id: PeerId, request_id: RequestId, failure: &OutboundFailure) -> Self {
+        let direction = Direction::Outbound;
+        match failure {
+            OutboundFailure::DialFailure => NetworkError::RequestDialFailure { peer_id, request_id },
+            OutboundFailure::Timeout => NetworkError::RequestTimeout { peer_id, request_id, direction },
+            OutboundFailure::ConnectionClosed => NetworkError::RequestConnectionClosed { peer_id, request_id, direction },
+            OutboundFailure::UnsupportedProtocols => NetworkError::UnsupportedProtocols { peer_id, request_id, direction },
+        }
+    }
+    pub fn from_inbound_failure(peer_id: PeerId, request_id: RequestId, failure: &InboundFailure) -> Self {
+        let direction = Direction::Inbound;
+        match failure {
+            InboundFailure::Timeout => NetworkError::RequestTimeout { peer_id, request_id, direction },
+            InboundFailure::ConnectionClosed => NetworkError::RequestConnectionClosed { peer_id, request_id, direction },
+            InboundFailure::UnsupportedProtocols => NetworkError::UnsupportedProtocols { peer_id, request_id, direction },
+            InboundFailure::ResponseOmission => NetworkError::ResponseOmission { peer_id, request_id },
+        }
+    }
 }
 
 impl Network {
@@ -130,14 +452,30 @@ impl Network {
 }
 
 impl LookupClient {
-    fn builder(local_key: Keypair, net: &Network) -> Self {
+    fn builder(local_key: Keypair, net: &Network, config: LookupConfig) -> Self {
         let local_peer_id = local_key.public().to_peer_id();
-        println!("Local PeerID : {:?}", local_peer_id);
         let (relay_transport, relay_client) = relay::client::Client::new_transport_and_behaviour(local_peer_id);
-        let transport = Self::build_transport(&local_key, relay_transport);
-        let behaviour = Self::build_behaviour(&local_key, &local_peer_id, Some(net), relay_client);
-        let swarm = Self::build_swarm(local_peer_id, Some(net.clone()), transport, behaviour);
-        let network = Vec::from([net.clone()]);
+        let gate = SharedGate::new(config.gate.clone());
+        let transport = Self::build_transport(&local_key, relay_transport, &gate);
+        Self::assemble(local_key, Some(net), config, transport, relay_client, gate)
+    }
+    // Shared by the public constructors and the tests running over the in-process transport.
+    // The transport checks addresses through `gate`, the PeerId rules are added here.
+    pub(crate) fn assemble(local_key: Keypair, net: Option<&Network>, config: LookupConfig, transport: Boxed<(PeerId, StreamMuxerBox)>, relay_client: Client, gate: SharedGate) -> Self {
+        let local_peer_id = local_key.public().to_peer_id();
+        println!("Local PeerID : {:?}", local_peer_id);
+        let transport = gate.peers(transport);
+        let SharedGate { rules: gate, rejections } = gate;
+        let behaviour = Self::build_behaviour(&local_key, &local_peer_id, net, relay_client, &config);
+        #[cfg(feature = "test-protocol")]
+        let handshakes = Handshakes::new(
+            local_key,
+            config.handshake_timeout,
+            config.handshake_replay_window,
+            config.max_handshake_sessions
+        );
+        let swarm = Self::build_swarm(local_peer_id, net.cloned(), transport, behaviour, &config);
+        let network = net.into_iter().cloned().collect();
         let listen_addrs: Vec<Multiaddr> = [].to_vec();
         LookupClient {
             // local_key,
@@ -145,27 +483,82 @@ impl LookupClient {
             listen_addrs,
             network,
             swarm,
+            peer_store: PeerStore::new(config.peer_store.clone()),
+            address_book: AddressBook::new(config.peer_store.clone()),
+            reputation: Reputation::new(config.reputation.clone()),
+            latency: LatencyTracker::new(config.latency_window, config.peer_store.clone()),
+            last_seen: HashMap::new(),
+            connected_addrs: HashMap::new(),
+            identified_addrs: HashMap::new(),
+            gate,
+            rejections,
+            denials: VecDeque::new(),
+            listeners: HashSet::new(),
+            #[cfg(feature = "codec")]
+            oversized: HashMap::new(),
+            #[cfg(feature = "test-protocol")]
+            pending_requests: HashMap::new(),
+            #[cfg(feature = "test-protocol")]
+            codec: handshake::codec(config.handshake_versions.clone(), config.handshake_limits),
+            #[cfg(feature = "test-protocol")]
+            handshakes,
+            #[cfg(feature = "test-protocol")]
+            liveness_proofs: HashMap::new(),
+            #[cfg(feature = "test-protocol")]
+            negotiated_versions: HashMap::new(),
+            #[cfg(feature = "test-protocol")]
+            handshake_events: None,
+            #[cfg(feature = "test-protocol")]
+            timed_out: VecDeque::new(),
+            #[cfg(feature = "test-protocol")]
+            handlers: Handlers::new(config.max_handled_requests, config.request_handler_timeout),
+            #[cfg(feature = "file-transfer")]
+            transfers: Transfers::new(config.transfer_chunk_size, config.transfer_overwrite),
+            config,
         }
     }
     // TODO: trait implementations for multiple key sources.
     pub fn from_base64(base64_string: &str, net: &Network) -> Self {
+        Self::from_base64_with_config(base64_string, net, LookupConfig::default()).expect("Valid default config.")
+    }
+    pub fn from_base64_with_config(base64_string: &str, net: &Network, config: LookupConfig) -> Result<Self, ConfigError> {
         let encoded = base64::decode(base64_string).unwrap();
-        Self::builder(Keypair::from_protobuf_encoding(&encoded).unwrap(), net)
+        Self::with_keypair(Keypair::from_protobuf_encoding(&encoded).unwrap(), net, config)
     }
     pub fn from_pkcs8_file(file_path: &str, net: &Network) -> Self {
+        Self::from_pkcs8_file_with_config(file_path, net, LookupConfig::default()).expect("Valid default config.")
+    }
+    pub fn from_pkcs8_file_with_config(file_path: &str, net: &Network, config: LookupConfig) -> Result<Self, ConfigError> {
         let mut pkcs8_der = std::fs::read(file_path).unwrap();
-        Self::builder(Keypair::rsa_from_pkcs8(&mut pkcs8_der).unwrap(), net)
+        Self::with_keypair(Keypair::rsa_from_pkcs8(&mut pkcs8_der).unwrap(), net, config)
+    }
+    // Any identity, with a validated configuration.
+    pub fn with_keypair(local_key: Keypair, net: &Network, config: LookupConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        Ok(Self::builder(local_key, net, config))
     }
     pub fn new(net: &Network) -> Self {
-        let local_key = Keypair::generate_ed25519();
-        Self::builder(local_key, net)
+        Self::builder(Keypair::generate_ed25519(), net, LookupConfig::default())
+    }
+    // Client mode is meant for short-lived lookup tools that should stay out of the
+    // remote routing tables.
+    pub fn new_with_mode(net: &Network, mode: KademliaMode) -> Self {
+        let config = LookupConfig {
+            kademlia_mode: mode,
+            ..Default::default()
+        };
+        Self::builder(Keypair::generate_ed25519(), net, config)
+    }
+    pub fn with_config(net: &Network, config: LookupConfig) -> Result<Self, ConfigError> {
+        Self::with_keypair(Keypair::generate_ed25519(), net, config)
     }
 
-    fn build_swarm(local_peer_id: PeerId, network: Option<Network>, transport: Boxed<(PeerId, StreamMuxerBox)>,behaviour: LookupBehaviour) -> Swarm<LookupBehaviour> {
+    fn build_swarm(local_peer_id: PeerId, network: Option<Network>, transport: Boxed<(PeerId, StreamMuxerBox)>,behaviour: LookupBehaviour, config: &LookupConfig) -> Swarm<LookupBehaviour> {
         let mut swarm = SwarmBuilder::new(transport, behaviour, local_peer_id)
         .executor(Box::new(|fut| {
             async_std::task::spawn(fut);
         }))
+        .connection_limits(config.connection_limits.to_connection_limits())
         .build();
 
         if let Some(network) = network {
@@ -175,7 +568,7 @@ impl LookupClient {
         }
         swarm
     }
-    fn build_transport(local_key: &Keypair, relay_transport: ClientTransport) -> Boxed<(PeerId, libp2p_core::muxing::StreamMuxerBox)> {
+    fn build_transport(local_key: &Keypair, relay_transport: ClientTransport, gate: &SharedGate) -> Boxed<(PeerId, libp2p_core::muxing::StreamMuxerBox)> {
 
         let mut config = quic::Config::new(local_key);
         // config.handshake_timeout = Duration::from_secs(1);
@@ -183,11 +576,13 @@ impl LookupClient {
         // let quic_transport = quic::async_std::Transport::new(config);
 
         // Reference: https://github.com/mxinden/libp2p-lookup/blob/41f4e2fc498b44bcdd2d4b381363dea0b740336b/src/main.rs#L136-L175
+        // The gate sits under the DNS transport and sees the resolved addresses. Relayed
+        // connections are gated on the connection to their relay.
         let transport = OrTransport::new(
             relay_transport,
-            block_on(dns::DnsConfig::system(tcp::TcpTransport::new(
+            block_on(dns::DnsConfig::system(gate.addresses(tcp::TcpTransport::new(
                 tcp::GenTcpConfig::new().port_reuse(true).nodelay(true),
-            )))
+            ))))
             .unwrap(),
         );
 
@@ -222,31 +617,42 @@ impl LookupClient {
             .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
             .boxed()
     }
-    fn build_behaviour(local_key: &Keypair, local_peer_id: &PeerId, network: Option<&Network>, relay_client: Client) -> LookupBehaviour {
+    fn build_behaviour(local_key: &Keypair, local_peer_id: &PeerId, network: Option<&Network>, relay_client: Client, config: &LookupConfig) -> LookupBehaviour {
         let peer_id = *local_peer_id;
         // Create a Kademlia behaviour.
         let store = MemoryStore::new(peer_id);
-        let mut kademlia_config = KademliaConfig::default();
-        if let Some(protocol_name) = network.and_then(|n| n.protocol()) {
-            kademlia_config.set_protocol_names(vec![protocol_name.into_bytes().into()]);
-        }
-        let kademlia = Kademlia::with_config(peer_id, store, kademlia_config);
+        let kademlia_config = config.kademlia.to_kademlia_config();
+        let protocol_names = network
+            .and_then(|n| n.protocol())
+            .map(|protocol_name| vec![protocol_name.into_bytes().into()]);
+        let kademlia = SwitchableKademlia::new(
+            peer_id,
+            store,
+            kademlia_config,
+            protocol_names,
+            config.kademlia.connection_idle_timeout,
+            config.kademlia_mode
+        );
 
         let ping = ping::Behaviour::new(ping::Config::new());
 
         #[cfg(feature = "test-protocol")]
-        let synack_protocol = RequestResponse::new(
-            TestCodec(), iter::once((TestProtocol(), 
-            request_response::ProtocolSupport::Full)), 
-            request_response::RequestResponseConfig::default() 
+        let synack_protocol = handshake::codec(config.handshake_versions.clone(), config.handshake_limits).behaviour(
+            request_response::ProtocolSupport::Full,
+            request_response::RequestResponseConfig::default()
         );
 
         let user_agent =
             "substrate-node/v2.0.0-e3245d49d-x86_64-linux-gnu (unknown)".to_string();
         let proto_version = "/ipfs/id/1.0.0".to_string();
-        let identify = identify::Behaviour::new(
-            identify::Config::new(proto_version, local_key.public())
-                .with_agent_version(user_agent),
+        let identify = ModeAwareIdentify::new(
+            identify::Behaviour::new(
+                identify::Config::new(proto_version, local_key.public())
+                    .with_agent_version(user_agent)
+                    .with_push_listen_addr_updates(true),
+            ),
+            kademlia.protocol_names(),
+            config.kademlia_mode
         );
 
         LookupBehaviour {
@@ -255,18 +661,268 @@ impl LookupClient {
             identify,
             #[cfg(feature = "test-protocol")]
             request_response: synack_protocol,
+            #[cfg(feature = "file-transfer")]
+            file_transfer: transfer::behaviour(config.transfer_chunk_size),
             relay: relay_client,
             keep_alive: libp2p_swarm::keep_alive::Behaviour,
         }
     }
     pub async fn listen(&mut self) -> Result<libp2p_core::transport::ListenerId, libp2p::TransportError<io::Error>> {
-        self.swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap())
+        let listener_id = self.swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap())?;
+        self.listeners.insert(listener_id);
+        Ok(listener_id)
+    }
+    // Every event loop goes through here so that the client bookkeeping sees all swarm events.
+    pub async fn next_event(&mut self) -> LookupSwarmEvent {
+        loop {
+            // Bans are lifted on time even when the swarm is idle.
+            let ban_expiry = self.reputation.next_expiry();
+            let ban_expired = async move {
+                match ban_expiry {
+                    Some(until) => async_std::task::sleep(until.saturating_duration_since(Instant::now())).await,
+                    None => futures::future::pending::<()>().await,
+                }
+            }.fuse();
+            futures::pin_mut!(ban_expired);
+            // Sources of the disabled features never wake up.
+            #[cfg(feature = "test-protocol")]
+            let mut handled = self.handlers.handled.select_next_some().map(Wakeup::Handled);
+            #[cfg(not(feature = "test-protocol"))]
+            let mut handled = futures::future::pending();
+            #[cfg(feature = "file-transfer")]
+            let mut file_jobs = self.transfers.done.select_next_some().map(Wakeup::FileJob);
+            #[cfg(not(feature = "file-transfer"))]
+            let mut file_jobs = futures::future::pending();
+            let wakeup = futures::select! {
+                event = self.swarm.select_next_some() => Wakeup::Swarm(event),
+                wakeup = handled => wakeup,
+                wakeup = file_jobs => wakeup,
+                _ = ban_expired => Wakeup::BanExpired,
+            };
+            let event = match wakeup {
+                Wakeup::Swarm(event) => event,
+                #[cfg(feature = "test-protocol")]
+                Wakeup::Handled(handled) => {
+                    self.on_handled(handled).await;
+                    continue;
+                },
+                #[cfg(feature = "file-transfer")]
+                Wakeup::FileJob(done) => {
+                    self.on_transfer_job_done(done);
+                    continue;
+                },
+                Wakeup::BanExpired => {
+                    self.lift_expired_bans();
+                    continue;
+                },
+            };
+            self.observe(&event);
+            #[cfg(feature = "test-protocol")]
+            let event = match event {
+                // Handshake messages stay with `init_protocol`.
+                SwarmEvent::Behaviour(LookupBehaviourEvent::RequestResponse(RequestResponseEvent::Message {
+                    peer,
+                    message: RequestResponseMessage::Request { request_id, request, channel },
+                })) if self.handlers.handles(&request) && !handshake::is_handshake_request(&request) => {
+                    if let Some(refused) = self.handlers.dispatch(peer, request_id, request, channel) {
+                        self.on_handled(refused).await;
+                    }
+                    continue;
+                },
+                event => event,
+            };
+            // File transfers are driven here, their outcome is read from `transfer_events`.
+            #[cfg(feature = "file-transfer")]
+            let event = match event {
+                SwarmEvent::Behaviour(LookupBehaviourEvent::FileTransfer(event)) => {
+                    self.on_transfer_event(event);
+                    continue;
+                },
+                event => event,
+            };
+            break event;
+        }
+    }
+    // The last connection to the peer closed.
+    fn forget_connection(&mut self, peer_id: PeerId) {
+        self.connected_addrs.remove(&peer_id);
+        #[cfg(feature = "file-transfer")]
+        self.transfers.forget_peer(peer_id);
+    }
+    fn observe(&mut self, event: &LookupSwarmEvent) {
+        match event {
+            SwarmEvent::ConnectionEstablished { peer_id, endpoint, concurrent_dial_errors, .. } => {
+                self.connected_addrs
+                    .entry(*peer_id)
+                    .or_insert_with(|| endpoint.get_remote_address().clone());
+                if endpoint.is_dialer() {
+                    self.address_book.record_success(endpoint.get_remote_address());
+                }
+                for (address, _) in concurrent_dial_errors.iter().flatten() {
+                    self.address_book.record_failure(address);
+                }
+                #[cfg(feature = "codec")]
+                self.oversized.remove(peer_id);
+            },
+            // The request-response handler closes the connection when a codec fails, the
+            // requests it carried fail right after.
+            #[cfg(feature = "codec")]
+            SwarmEvent::ConnectionClosed { peer_id, num_established, cause: Some(cause), .. } => {
+                if let Some(too_large) = MessageTooLarge::find(cause) {
+                    println!("Connection with {:?} closed : {}", peer_id, too_large);
+                    self.oversized.insert(*peer_id, *too_large);
+                }
+                if *num_established == 0 {
+                    self.forget_connection(*peer_id);
+                }
+            },
+            SwarmEvent::OutgoingConnectionError { peer_id, error: DialError::ConnectionLimit(limit) } => {
+                self.record_denial(ConnectionDenial {
+                    peer_id: *peer_id,
+                    address: None,
+                    direction: Direction::Outbound,
+                    limit: limit.limit,
+                    current: limit.current,
+                });
+            },
+            SwarmEvent::IncomingConnectionError {
+                send_back_addr,
+                error: PendingConnectionError::ConnectionLimit(limit),
+                ..
+            } => {
+                self.record_denial(ConnectionDenial {
+                    peer_id: None,
+                    address: Some(send_back_addr.clone()),
+                    direction: Direction::Inbound,
+                    limit: limit.limit,
+                    current: limit.current,
+                });
+            },
+            SwarmEvent::OutgoingConnectionError { error, .. } => {
+                for (address, _) in dialing::failed_addresses(error) {
+                    self.address_book.record_failure(&address);
+                }
+            },
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                self.forget_connection(*peer_id);
+            },
+            // Listeners opened directly on the swarm are tracked too, for the shutdown.
+            SwarmEvent::NewListenAddr { listener_id, .. } => {
+                self.listeners.insert(*listener_id);
+            },
+            SwarmEvent::ListenerClosed { listener_id, .. } => {
+                self.listeners.remove(listener_id);
+            },
+            #[cfg(feature = "test-protocol")]
+            SwarmEvent::Behaviour(LookupBehaviourEvent::RequestResponse(
+                RequestResponseEvent::Message { message: RequestResponseMessage::Response { request_id, .. }, .. }
+                | RequestResponseEvent::OutboundFailure { request_id, .. }
+            )) => {
+                self.pending_requests.remove(request_id);
+            },
+            SwarmEvent::Behaviour(LookupBehaviourEvent::Ping(ping::Event { peer, result })) => {
+                match result {
+                    Ok(ping::Success::Ping { rtt }) => {
+                        self.latency.record_rtt(*peer, *rtt);
+                        if let Some(address) = self.connected_addrs.get(peer) {
+                            self.address_book.record_rtt(address, *rtt);
+                        }
+                    },
+                    Ok(ping::Success::Pong) | Err(ping::Failure::Unsupported) => {},
+                    Err(_) => self.latency.record_failure(*peer),
+                }
+            },
+            // Also covers the updates pushed by peers whose addresses changed.
+            SwarmEvent::Behaviour(LookupBehaviourEvent::Identify(
+                identify::Event::Received { peer_id, info }
+            )) => {
+                self.refresh_identified_addresses(peer_id, &info.listen_addrs);
+                self.peer_store.insert(Peer {
+                    peer_id: *peer_id,
+                    protocol_version: info.protocol_version.clone(),
+                    agent_version: info.agent_version.clone(),
+                    listen_addrs: info.listen_addrs.clone(),
+                    protocols: info.protocols.clone(),
+                    observed_addr: info.observed_addr.clone(),
+                });
+            },
+            _ => {}
+        }
+        let peer = match event {
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => Some(*peer_id),
+            SwarmEvent::ConnectionClosed { peer_id, .. } => Some(*peer_id),
+            SwarmEvent::Behaviour(LookupBehaviourEvent::Identify(
+                identify::Event::Received { peer_id, .. }
+            )) => Some(*peer_id),
+            SwarmEvent::Behaviour(LookupBehaviourEvent::Ping(
+                ping::Event { peer, result: Ok(_) }
+            )) => Some(*peer),
+            #[cfg(feature = "test-protocol")]
+            SwarmEvent::Behaviour(LookupBehaviourEvent::RequestResponse(
+                RequestResponseEvent::Message { peer, .. }
+            )) => Some(*peer),
+            _ => None,
+        };
+        if let Some(peer) = peer {
+            self.last_seen.insert(peer, Utc::now());
+        }
+        self.forget_departed_peer(event);
+        self.update_reputation(event);
+    }
+    // Adds the advertised addresses of a peer to Kademlia, filtered by the address policy.
+    pub fn add_identified_addresses(&mut self, peer_id: &PeerId, listen_addrs: &[Multiaddr]) -> Vec<Multiaddr> {
+        let accepted = self.config.address_policy.filter(self.connected_addrs.get(peer_id), listen_addrs);
+        let known = self.addresses_in_routing_table(peer_id);
+        for address in &accepted {
+            self.swarm.behaviour_mut().kademlia.add_address(peer_id, address.clone());
+            if !known.contains(address) {
+                let identified = self.identified_addrs.entry(*peer_id).or_default();
+                if !identified.contains(address) {
+                    identified.push(address.clone());
+                }
+            }
+        }
+        accepted
+    }
+    // Replaces the addresses a peer advertised earlier with the ones it advertises now.
+    // Addresses Kademlia learned from elsewhere are left alone.
+    fn refresh_identified_addresses(&mut self, peer_id: &PeerId, listen_addrs: &[Multiaddr]) {
+        let accepted = self.add_identified_addresses(peer_id, listen_addrs);
+        // Removing the last address drops the peer from the routing table, so the new
+        // addresses are added first.
+        let stale: Vec<Multiaddr> = match self.identified_addrs.get_mut(peer_id) {
+            Some(identified) => {
+                let (kept, stale) = identified.drain(..).partition(|address| accepted.contains(address));
+                *identified = kept;
+                stale
+            },
+            None => return,
+        };
+        for address in &stale {
+            self.swarm.behaviour_mut().kademlia.remove_address(peer_id, address);
+        }
+    }
+    // Identify pushes listen address changes on its own, external addresses are pushed here.
+    pub fn add_external_address(&mut self, address: Multiaddr) {
+        self.swarm.add_external_address(address, AddressScore::Infinite);
+        self.push_identify();
+    }
+    pub fn remove_external_address(&mut self, address: &Multiaddr) -> bool {
+        let removed = self.swarm.remove_external_address(address);
+        if removed {
+            self.push_identify();
+        }
+        removed
+    }
+    fn push_identify(&mut self) {
+        let peers: Vec<PeerId> = self.swarm.connected_peers().cloned().collect();
+        self.swarm.behaviour_mut().identify.push(peers);
     }
     async fn dht(&mut self, peer: PeerId) -> Result<Peer, NetworkError> {
         // type DynFuture = Box<dyn futures::future::Future<Output = Result<Peer, NetworkError>>>;
         self.swarm.behaviour_mut().kademlia.get_closest_peers(peer);
         loop {
-            match self.swarm.next().await.expect("Infinite Stream.") {
+            match self.next_event().await {
                 SwarmEvent::NewListenAddr { address, .. } => {
                     println!("Listening on {:?}", address);
                     self.listen_addrs.push(address);
@@ -314,11 +970,6 @@ impl LookupClient {
                     };
                     if peer_id == peer {
                         break Ok(addr);
-                    } else {
-                        println!("Adding {:?} to kademlia addresses list.", &addr.peer_id);
-                        println!("Listened addresses : {:?}", &addr.listen_addrs);
-                        let listen_addrs = addr.listen_addrs[0].clone(); // We are asumming the first address is available and accesible.
-                        self.swarm.behaviour_mut().kademlia.borrow_mut().add_address(&addr.peer_id,listen_addrs );
                     }
                 },
                 SwarmEvent::Behaviour(LookupBehaviourEvent::Kademlia(
@@ -377,21 +1028,94 @@ impl LookupClient {
             }
         }
     }
-    pub async fn dial(&mut self, peer_to_dial: &Peer) {
-        let address_to_dial = peer_to_dial.listen_addrs[0].clone();
-        println!("Dialing...{:?}", address_to_dial);
-        self.swarm.dial(address_to_dial).unwrap()
+    // Dials every known address of the peer, best ranked first, and returns the address of
+    // the first connection established.
+    pub async fn dial(&mut self, peer_to_dial: &Peer) -> Result<Multiaddr, NetworkError> {
+        let peer_id = peer_to_dial.peer_id;
+        if let Some(address) = self.connected_addrs.get(&peer_id) {
+            return Ok(address.clone());
+        }
+        let addresses = self.address_book.rank(&peer_to_dial.listen_addrs, &self.config.transport_preference);
+        if addresses.is_empty() {
+            return Err(NetworkError::NoAddresses(peer_id));
+        }
+        println!("Dialing...{:?}", addresses);
+        let opts = DialOpts::peer_id(peer_id).addresses(addresses).build();
+        match self.swarm.dial(opts) {
+            Ok(()) => {},
+            // Refused before any event, so recorded here.
+            Err(DialError::ConnectionLimit(limit)) => {
+                self.record_denial(ConnectionDenial {
+                    peer_id: Some(peer_id),
+                    address: None,
+                    direction: Direction::Outbound,
+                    limit: limit.limit,
+                    current: limit.current,
+                });
+                return Err(NetworkError::ConnectionLimit { peer_id, limit: limit.limit, current: limit.current });
+            },
+            Err(e) => return Err(NetworkError::DialRefused { peer_id, reason: e.to_string() }),
+        }
+        let dial_timeout = self.config.dial_timeout;
+        let connected = async {
+            loop {
+                match self.next_event().await {
+                    SwarmEvent::ConnectionEstablished { peer_id: connected, endpoint, .. } if connected == peer_id => {
+                        break Ok(endpoint.get_remote_address().clone());
+                    },
+                    SwarmEvent::OutgoingConnectionError { peer_id: Some(failed), error: DialError::ConnectionLimit(limit) } if failed == peer_id => {
+                        break Err(NetworkError::ConnectionLimit { peer_id, limit: limit.limit, current: limit.current });
+                    },
+                    SwarmEvent::OutgoingConnectionError { peer_id: Some(failed), error } if failed == peer_id => {
+                        let errors = dialing::failed_addresses(&error);
+                        if errors.is_empty() {
+                            break Err(NetworkError::DialRefused { peer_id, reason: error.to_string() });
+                        }
+                        break Err(NetworkError::DialFailed { peer_id, errors });
+                    },
+                    _ => {}
+                }
+            }
+        };
+        async_std::future::timeout(dial_timeout, connected)
+            .await
+            .unwrap_or(Err(NetworkError::DialTimeout(peer_id)))
+    }
+    pub fn peer_store(&self) -> &PeerStore {
+        &self.peer_store
+    }
+    pub fn peer_store_mut(&mut self) -> &mut PeerStore {
+        &mut self.peer_store
+    }
+    pub fn kademlia_mode(&self) -> KademliaMode {
+        self.swarm.behaviour().kademlia.mode()
+    }
+    // Switch to server mode once the node turns out to be publicly reachable. Connected
+    // peers are pushed the protocol list of the new mode.
+    pub fn set_kademlia_mode(&mut self, mode: KademliaMode) {
+        let behaviour = self.swarm.behaviour_mut();
+        behaviour.kademlia.set_mode(mode);
+        behaviour.identify.set_kademlia_mode(mode);
+        self.push_identify();
     }
     pub fn is_connected(&self, peer_id: &PeerId) -> bool {
         Swarm::is_connected(&self.swarm, peer_id)
     }
     #[cfg(feature="test-protocol")]
-    pub async fn send_request(&mut self, peer_id:PeerId, payload: test_protocol::SYN) {
-        self.swarm.behaviour_mut().request_response.send_request(&peer_id, payload);
+    pub async fn send_request(&mut self, peer_id:PeerId, payload: test_protocol::SYN) -> Result<RequestId, NetworkError> {
+        self.codec.check_request(&payload)?;
+        let request_id = self.swarm.behaviour_mut().request_response.send_request(&peer_id, payload.into());
+        self.pending_requests.insert(request_id, peer_id);
+        Ok(request_id)
     }
     #[cfg(feature="test-protocol")]
-    pub async fn send_response(&mut self, channel: ResponseChannel<test_protocol::SYNACK>, payload: test_protocol::SYNACK) {
-        self.swarm.behaviour_mut().request_response.send_response(channel, payload).unwrap();
+    pub async fn send_response(&mut self, channel: ResponseChannel<Negotiated<test_protocol::SYNACK>>, payload: test_protocol::SYNACK) -> Result<(), NetworkError> {
+        self.codec.check_response(&payload)?;
+        self.swarm
+            .behaviour_mut()
+            .request_response
+            .send_response(channel, payload.into())
+            .map_err(|_| NetworkError::ResponseChannelClosed)
     }
     pub async fn kademlia_add_address(&mut self, peer_id: PeerId, address: Multiaddr) {
         self.swarm.behaviour_mut().kademlia.borrow_mut().add_address(&peer_id, address);
@@ -405,47 +1129,102 @@ impl LookupClient {
             .add_address(&peer_id, address)    
     }
     #[cfg(feature="test-protocol")]
+    pub async fn start_handshake(&mut self, peer_id: PeerId) -> Result<RequestId, NetworkError> {
+        let syn = self.handshakes.start(peer_id, SystemTime::now());
+        match self.send_request(peer_id, syn.request()).await {
+            Ok(request_id) => Ok(request_id),
+            Err(e) => {
+                self.handshakes.abort(&peer_id);
+                Err(e)
+            },
+        }
+    }
+    #[cfg(feature="test-protocol")]
+    pub fn handshake_state(&self, peer_id: &PeerId) -> Option<HandshakeState> {
+        self.handshakes.state(peer_id)
+    }
+    // Proof that the peer signed our latest challenge, kept once its handshake completed.
+    #[cfg(feature="test-protocol")]
+    pub fn liveness_proof(&self, peer_id: &PeerId) -> Option<&LivenessProof> {
+        self.liveness_proofs.get(peer_id)
+    }
+    // Version the latest completed handshake with the peer was negotiated under.
+    #[cfg(feature="test-protocol")]
+    pub fn negotiated_version(&self, peer_id: &PeerId) -> Option<&SerdeProtocol> {
+        self.negotiated_versions.get(peer_id)
+    }
+    // Drives the handshakes until one completes. Fails when a handshake in progress times
+    // out, the peer breaks the protocol or its SYN is refused, misbehaving peers without a
+    // handshake in progress are only logged.
+    #[cfg(feature="test-protocol")]
     pub async fn init_protocol(&mut self) -> Result<PeerId,NetworkError> {
-
-        let synack = test_protocol::SYNACK("SYNACK".to_string().into_bytes());
-        let ack = test_protocol::SYN("ACK".to_string().into_bytes());
         loop {
-            match self.swarm.next().await.expect("Infinite Stream.") {
-                SwarmEvent::NewListenAddr { address, .. } => { println!("New Listen Address : {:?}",address); },
-                SwarmEvent::Behaviour( LookupBehaviourEvent::RequestResponse(
-                    RequestResponseEvent::ResponseSent { peer, .. }
-                ) ) => {
-                    println!("Response sent to : {:?}", peer);
-                    break Ok(peer)
+            if let Some(peer_id) = self.timed_out.pop_front() {
+                println!("Handshake with {:?} timed out.", peer_id);
+                let error = HandshakeError::Timeout(self.handshakes.timeout());
+                break Err(NetworkError::Handshake { peer_id, error });
+            }
+            let event = match self.handshakes.next_deadline() {
+                Some(deadline) => {
+                    let remaining = deadline.duration_since(SystemTime::now()).unwrap_or_default();
+                    match async_std::future::timeout(remaining, self.next_event()).await {
+                        Ok(event) => event,
+                        Err(_) => {
+                            let expired = self.handshakes.expire(SystemTime::now());
+                            self.timed_out.extend(expired);
+                            continue;
+                        },
+                    }
                 },
+                None => self.next_event().await,
+            };
+            match event {
+                SwarmEvent::NewListenAddr { address, .. } => { println!("New Listen Address : {:?}",address); },
                 SwarmEvent::Behaviour(
                     LookupBehaviourEvent::RequestResponse (
-                        RequestResponseEvent::Message { 
-                            peer, 
-                            message: 
-                                RequestResponseMessage::Response { 
-                                    request_id, 
-                                    response 
+                        RequestResponseEvent::Message {
+                            peer,
+                            message:
+                                RequestResponseMessage::Response {
+                                    request_id,
+                                    response: Negotiated { message: test_protocol::SYNACK(payload), protocol }
                                 } }
                     )
                 ) => {
-                    match response {
-                        test_protocol::SYNACK(payload) => { 
-                            println!("Response received : {:?} {:?} {:?}", peer, request_id, std::str::from_utf8(&payload).unwrap());
-                            self.send_request( peer, ack.clone()).await;
-                            match std::str::from_utf8(&payload).unwrap() {
-                                "ACK" => { 
-                                     // TODO : timer
-                                    break Ok(peer)
-                                }
-                                "SYNACK" => {
-                                    // self.send_request( peer, ack.clone()).await;
-                                    println!("Handshake succeeded.");
-                                    break Ok(peer)
+                    println!("Response received : {:?} {:?} ({} bytes)", peer, request_id, payload.len());
+                    let in_progress = self.handshakes.in_progress(&peer);
+                    let step = if protocol.as_ref().map_or(false, test_protocol::is_legacy) {
+                        self.handshakes.on_legacy_response(peer, &payload, SystemTime::now())
+                    } else {
+                        self.handshakes.on_response(peer, &payload, SystemTime::now())
+                    };
+                    match step {
+                        Ok(step) => {
+                            if let Some(proof) = step.proof {
+                                println!(
+                                    "Liveness proof from {:?} : nonce {}, signed at {} ms, signature {}",
+                                    peer,
+                                    base64::encode(proof.signed.challenge.nonce),
+                                    proof.signed.signed_at,
+                                    base64::encode(&proof.signed.signature)
+                                );
+                                self.liveness_proofs.insert(peer, proof);
+                            }
+                            if let Some(reply) = step.reply {
+                                if let Err(e) = self.send_request(peer, reply.request()).await {
+                                    break Err(e);
                                 }
-                                _ => {}
                             }
-                        }
+                            if step.completed {
+                                self.handshake_completed(peer, protocol);
+                                break Ok(peer);
+                            }
+                        },
+                        Err(error) => {
+                            if let Some(e) = self.handshake_failed(peer, error, in_progress) {
+                                break Err(e);
+                            }
+                        },
                     }
                 },
                 SwarmEvent::Behaviour( LookupBehaviourEvent::RequestResponse(
@@ -453,38 +1232,126 @@ impl LookupClient {
                         peer,
                         message:
                             RequestResponseMessage::Request {
-                                request, 
+                                request: Negotiated { message: test_protocol::SYN(payload), protocol },
                                 channel, ..
                             },
                     }
                     )
                 ) => {
-                    match request {
-                        test_protocol::SYN(payload) => { 
-                            println!("Request received from : {:?} {:?}", peer, std::str::from_utf8(&payload).unwrap());
-                            match std::str::from_utf8(&payload).unwrap() {
-                                "SYN" => {
-                                    self.send_response(channel, synack.clone()).await;
-                                },
-                                "ACK" => {
-                                    println!("Handshake succeeded.");
-                                    break Ok(peer);
-                                },
-                                _ => {}
+                    println!("Request received from : {:?} ({} bytes)", peer, payload.len());
+                    let in_progress = self.handshakes.in_progress(&peer);
+                    let step = if protocol.as_ref().map_or(false, test_protocol::is_legacy) {
+                        self.handshakes.on_legacy_request(peer, &payload, SystemTime::now())
+                    } else {
+                        self.handshakes.on_request(peer, &payload, SystemTime::now())
+                    };
+                    match step {
+                        Ok(step) => {
+                            if let Some(reply) = step.reply {
+                                if let Err(e) = self.send_response(channel, reply.response()).await {
+                                    break Err(e);
+                                }
                             }
-                        }
+                            if step.completed {
+                                self.handshake_completed(peer, protocol);
+                                break Ok(peer);
+                            }
+                        },
+                        // Dropping the channel leaves the request unanswered.
+                        Err(error) => {
+                            if let Some(e) = self.handshake_failed(peer, error, in_progress) {
+                                break Err(e);
+                            }
+                        },
+                    }
+                },
+                // Failures of the last ACK of a completed handshake are only logged.
+                SwarmEvent::Behaviour(LookupBehaviourEvent::RequestResponse(
+                    RequestResponseEvent::OutboundFailure { peer, request_id, error }
+                )) => {
+                    let error = self.outbound_failure(peer, request_id, &error);
+                    println!("{}", error);
+                    if self.handshakes.state(&peer) != Some(HandshakeState::Completed) {
+                        self.handshakes.abort(&peer);
+                        break Err(error);
+                    }
+                },
+                // Requests dropped on purpose after a protocol violation end up here too.
+                SwarmEvent::Behaviour(LookupBehaviourEvent::RequestResponse(
+                    RequestResponseEvent::InboundFailure { peer, request_id, error }
+                )) => {
+                    let error = self.inbound_failure(peer, request_id, &error);
+                    println!("{}", error);
+                    if self.handshakes.in_progress(&peer) {
+                        self.handshakes.abort(&peer);
+                        break Err(error);
                     }
                 },
                 _ => { }
             }
         }
     }
+    // Same as `NetworkError::from_outbound_failure`, except that a request failed by the
+    // close of a connection over an oversized message is reported with that message.
+    #[cfg(feature = "codec")]
+    pub fn outbound_failure(&self, peer_id: PeerId, request_id: RequestId, failure: &OutboundFailure) -> NetworkError {
+        match (failure, self.oversized.get(&peer_id)) {
+            (OutboundFailure::ConnectionClosed, Some(error)) => NetworkError::RequestTooLarge {
+                peer_id,
+                request_id,
+                direction: Direction::Outbound,
+                error: *error,
+            },
+            _ => NetworkError::from_outbound_failure(peer_id, request_id, failure),
+        }
+    }
+    #[cfg(feature = "codec")]
+    pub fn inbound_failure(&self, peer_id: PeerId, request_id: RequestId, failure: &InboundFailure) -> NetworkError {
+        match (failure, self.oversized.get(&peer_id)) {
+            (InboundFailure::ConnectionClosed, Some(error)) => NetworkError::RequestTooLarge {
+                peer_id,
+                request_id,
+                direction: Direction::Inbound,
+                error: *error,
+            },
+            _ => NetworkError::from_inbound_failure(peer_id, request_id, failure),
+        }
+    }
+    #[cfg(feature="test-protocol")]
+    fn handshake_completed(&mut self, peer_id: PeerId, protocol: Option<SerdeProtocol>) {
+        match protocol {
+            Some(protocol) => {
+                println!("Handshake with {:?} succeeded over {}.", peer_id, protocol.name());
+                self.negotiated_versions.insert(peer_id, protocol);
+            },
+            None => println!("Handshake with {:?} succeeded.", peer_id),
+        }
+    }
+    #[cfg(feature="test-protocol")]
+    fn handshake_failed(&mut self, peer_id: PeerId, error: HandshakeError, in_progress: bool) -> Option<NetworkError> {
+        println!("Handshake with {:?} failed : {}", peer_id, error);
+        match error {
+            HandshakeError::UnknownPayload(_) | HandshakeError::Malformed(_) => {
+                self.report_misbehaviour(peer_id, Misbehaviour::MalformedMessage);
+            },
+            _ => {},
+        }
+        // Refused peers had no handshake in progress, the refusal is reported all the same.
+        if in_progress || matches!(error, HandshakeError::TooManySessions(_)) {
+            Some(NetworkError::Handshake { peer_id, error })
+        } else {
+            None
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
     use libp2p_swarm::DialError;
+    use libp2p_swarm::dial_opts::PeerCondition;
+    use libp2p_core::multiaddr::Protocol;
+    use libp2p_core::transport::MemoryTransport;
 
     use super::*;
 
@@ -562,8 +1429,6 @@ mod tests {
                             observed_addr,
                         };
                         {
-                            println!("Adding {:?} to kademlia addresses list.", &addr.peer_id);
-                            println!("Listened addresses : {:?}", &addr.listen_addrs);
                             let listen_addrs = addr.listen_addrs[0].clone();
                             node_a.swarm.behaviour_mut().kademlia.borrow_mut().add_address(&addr.peer_id,listen_addrs );
                         }
@@ -625,100 +1490,1068 @@ mod tests {
         }).await;
         result
     }
-}
+    fn memory_client(limits: ConnectionLimitSettings) -> LookupClient {
+        memory_client_with(LookupConfig {
+            connection_limits: limits,
+            ..Default::default()
+        })
+    }
 
+    fn memory_client_with(config: LookupConfig) -> LookupClient {
+        let local_key = Keypair::generate_ed25519();
+        let (_, relay_client) = relay::client::Client::new_transport_and_behaviour(local_key.public().to_peer_id());
+        let noise_keypair_spec = noise::Keypair::<noise::X25519Spec>::new()
+            .into_authentic(&local_key)
+            .unwrap();
+        let gate = SharedGate::new(config.gate.clone());
+        let memory = MemoryTransport::default().map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+        let transport = gate.addresses(memory)
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise::NoiseConfig::xx(noise_keypair_spec).into_authenticated())
+            .multiplex(yamux::YamuxConfig::default())
+            .boxed();
+        LookupClient::assemble(local_key, None, config, transport, relay_client, gate)
+    }
+
+    // Over TCP and DNS, with the gate placed as in the public constructors.
+    fn tcp_client_with(config: LookupConfig) -> LookupClient {
+        let local_key = Keypair::generate_ed25519();
+        let (relay_transport, relay_client) = relay::client::Client::new_transport_and_behaviour(local_key.public().to_peer_id());
+        let gate = SharedGate::new(config.gate.clone());
+        let transport = LookupClient::build_transport(&local_key, relay_transport, &gate);
+        LookupClient::assemble(local_key, None, config, transport, relay_client, gate)
+    }
 
+    async fn memory_listener(limits: ConnectionLimitSettings) -> (LookupClient, Multiaddr) {
+        listening(memory_client(limits)).await
+    }
 
+    async fn listening(mut client: LookupClient) -> (LookupClient, Multiaddr) {
+        client.swarm.listen_on("/memory/0".parse().unwrap()).unwrap();
+        loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = client.next_event().await {
+                break (client, address);
+            }
+        }
+    }
 
-// Protocol dependencies .
+    #[async_std::test]
+    async fn pending_dials_are_limited() {
+        let mut client = memory_client(ConnectionLimitSettings {
+            max_pending_outgoing: Some(1),
+            ..ConnectionLimitSettings::unlimited()
+        });
+        let dial = |port: u64| DialOpts::peer_id(PeerId::random())
+            .addresses(vec![format!("/memory/{}", port).parse().unwrap()])
+            .build();
+        assert!(client.swarm.dial(dial(1001)).is_ok());
+        match client.swarm.dial(dial(1002)) {
+            Err(DialError::ConnectionLimit(limit)) => assert_eq!((limit.limit, limit.current), (1, 1)),
+            other => panic!("Unexpected dial result : {:?}", other),
+        }
+    }
 
-use async_trait::async_trait;
-use libp2p::request_response::*;
-// use std::io;
-use futures::{prelude::*, AsyncWriteExt};
-use libp2p_core::upgrade::{
-    read_length_prefixed,
-    write_length_prefixed
-};
+    #[async_std::test]
+    async fn connections_per_peer_are_limited() {
+        let (mut server, address) = memory_listener(ConnectionLimitSettings::unlimited()).await;
+        let server_id = server.local_peer_id;
+        async_std::task::spawn(async move {
+            loop {
+                server.next_event().await;
+            }
+        });
+        let mut client = memory_client(ConnectionLimitSettings {
+            max_established_per_peer: Some(1),
+            ..ConnectionLimitSettings::unlimited()
+        });
+        let dial = || DialOpts::peer_id(server_id)
+            .condition(PeerCondition::Always)
+            .addresses(vec![address.clone()])
+            .build();
+        client.swarm.dial(dial()).unwrap();
+        let denied = async {
+            let mut redialed = false;
+            loop {
+                match client.next_event().await {
+                    SwarmEvent::ConnectionEstablished { .. } if !redialed => {
+                        redialed = true;
+                        client.swarm.dial(dial()).unwrap();
+                    },
+                    SwarmEvent::ConnectionEstablished { .. } => panic!("Second connection to the same peer."),
+                    SwarmEvent::OutgoingConnectionError { error: DialError::ConnectionLimit(limit), .. } => break limit,
+                    _ => {}
+                }
+            }
+        };
+        let limit = async_std::future::timeout(Duration::from_secs(30), denied).await.unwrap();
+        assert_eq!(limit.limit, 1);
+        assert!(client.swarm.is_connected(&server_id));
+    }
 
-#[derive(Debug, Clone)]
-pub struct TestProtocol();
-#[derive(Clone)]
-pub struct TestCodec();
-// The message types will be derived from the protocol types specification located at 'protocols/test-protocol/src/lib.rs'
-// This is intented for workspace management of protocols.
-// #[derive(Debug, Clone, PartialEq, Eq)]
-// pub struct SYN(Vec<u8>);
-// #[derive(Debug, Clone, PartialEq, Eq)]
-// pub struct SYNACK(Vec<u8>);
-// #[derive(Debug, Clone, PartialEq, Eq)]
-// struct ACK(Vec<u8>);
-
-impl ProtocolName for TestProtocol {
-    fn protocol_name(&self) -> &[u8] {
-        "/SYNACK/0.0.1".as_bytes()
+    #[async_std::test]
+    async fn established_outgoing_connections_are_limited() {
+        let mut servers = Vec::new();
+        for _ in 0..2 {
+            let (mut server, address) = memory_listener(ConnectionLimitSettings::unlimited()).await;
+            servers.push(Peer {
+                peer_id: server.local_peer_id,
+                protocol_version: String::new(),
+                agent_version: String::new(),
+                listen_addrs: vec![address],
+                protocols: Vec::new(),
+                observed_addr: Multiaddr::empty(),
+            });
+            async_std::task::spawn(async move {
+                loop {
+                    server.next_event().await;
+                }
+            });
+        }
+        let mut client = memory_client(ConnectionLimitSettings {
+            max_established_outgoing: Some(1),
+            ..ConnectionLimitSettings::unlimited()
+        });
+        client.dial(&servers[0]).await.unwrap();
+        match client.dial(&servers[1]).await {
+            Err(NetworkError::ConnectionLimit { peer_id, limit: 1, current: 1 }) => assert_eq!(peer_id, servers[1].peer_id),
+            other => panic!("Unexpected dial result : {:?}", other),
+        }
+        let denials = client.connection_denials();
+        assert_eq!(denials.len(), 1);
+        assert_eq!((denials[0].peer_id, denials[0].direction), (Some(servers[1].peer_id), Direction::Outbound));
+        assert!(client.connection_denials().is_empty());
+        assert_eq!(client.swarm.network_info().connection_counters().num_established_outgoing(), 1);
+    }
+
+    #[async_std::test]
+    async fn established_incoming_connections_are_limited() {
+        let (mut server, address) = memory_listener(ConnectionLimitSettings {
+            max_established_incoming: Some(1),
+            ..ConnectionLimitSettings::unlimited()
+        }).await;
+        for _ in 0..2 {
+            let mut client = memory_client(ConnectionLimitSettings::unlimited());
+            client.swarm.dial(address.clone()).unwrap();
+            async_std::task::spawn(async move {
+                loop {
+                    client.next_event().await;
+                }
+            });
+        }
+        let denied = async {
+            loop {
+                if let SwarmEvent::IncomingConnectionError { error: PendingConnectionError::ConnectionLimit(limit), .. } = server.next_event().await {
+                    break limit;
+                }
+            }
+        };
+        let limit = async_std::future::timeout(Duration::from_secs(30), denied).await.unwrap();
+        assert_eq!(limit.limit, 1);
+        assert_eq!(server.swarm.network_info().connection_counters().num_established_incoming(), 1);
+        assert_eq!(server.connection_denials()[0].direction, Direction::Inbound);
     }
-}
 
-#[async_trait]
-impl RequestResponseCodec for TestCodec {
-    type Protocol = TestProtocol;
-    type Request = test_protocol::SYN;
-    type Response = test_protocol::SYNACK;
+    #[async_std::test]
+    async fn gate_rejects_denied_peers() {
+        let (mut server, address) = memory_listener(ConnectionLimitSettings::unlimited()).await;
+        let mut client = memory_client(ConnectionLimitSettings::unlimited());
+        let client_id = client.local_peer_id;
+        server.update_gate(|gate| {
+            gate.deny_peers.insert(client_id);
+        });
+        client.swarm.dial(address).unwrap();
+        async_std::task::spawn(async move {
+            loop {
+                client.next_event().await;
+            }
+        });
+        let rejected = async {
+            loop {
+                server.next_event().await;
+                if let Some(rejection) = server.rejections().pop() {
+                    break rejection;
+                }
+            }
+        };
+        let rejection = async_std::future::timeout(Duration::from_secs(30), rejected).await.unwrap();
+        assert_eq!(rejection.peer_id, Some(client_id));
+        assert_eq!(rejection.direction, Direction::Inbound);
+        assert_eq!(rejection.reason, RejectionReason::DeniedPeer);
+        assert!(!server.swarm.is_connected(&client_id));
+    }
 
-    async fn read_request<T>(&mut self, _: &TestProtocol, io: &mut T) -> io::Result<Self::Request>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        let vec = read_length_prefixed(io, 1024).await?;
+    #[async_std::test]
+    async fn gate_rejects_addresses_before_the_upgrade() {
+        let (mut server, address) = memory_listener(ConnectionLimitSettings::unlimited()).await;
+        // Memory addresses have no IP, so no allowed range admits them.
+        let only_private = |gate: &mut ConnectionGate| gate.allow_ranges.push("10.0.0.0/8".parse().unwrap());
+        let mut client = memory_client(ConnectionLimitSettings::unlimited());
+        client.update_gate(only_private);
+        let server_id = server.local_peer_id;
+        client.swarm.dial(DialOpts::peer_id(server_id).addresses(vec![address.clone()]).build()).unwrap();
+        let failed = async {
+            loop {
+                if let SwarmEvent::OutgoingConnectionError { .. } = client.next_event().await {
+                    break;
+                }
+            }
+        };
+        async_std::future::timeout(Duration::from_secs(30), failed).await.unwrap();
+        let rejections = client.rejections();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!((rejections[0].peer_id, rejections[0].direction), (None, Direction::Outbound));
+        assert_eq!(rejections[0].reason, RejectionReason::AddressNotAllowed);
+        // Refused by our own gate, not held against the peer.
+        assert_eq!(client.reputation(&server_id), 0.0);
+        // Inbound connections are dropped on their remote address, the peer stays unknown.
+        server.update_gate(only_private);
+        let mut client = memory_client(ConnectionLimitSettings::unlimited());
+        client.swarm.dial(address).unwrap();
+        async_std::task::spawn(async move {
+            loop {
+                client.next_event().await;
+            }
+        });
+        // Dropped upgrades produce no swarm event, the server is polled for a while at a time.
+        let rejected = async {
+            loop {
+                let _ = async_std::future::timeout(Duration::from_millis(100), server.next_event()).await;
+                if let Some(rejection) = server.rejections().pop() {
+                    break rejection;
+                }
+            }
+        };
+        let rejection = async_std::future::timeout(Duration::from_secs(30), rejected).await.unwrap();
+        assert_eq!((rejection.peer_id, rejection.direction), (None, Direction::Inbound));
+    }
 
-        if vec.is_empty() {
-            return Err(io::ErrorKind::UnexpectedEof.into());
+    // Dials `/dns4/localhost` to a TCP listener and returns whether the connection came up.
+    async fn dial_localhost(client: &mut LookupClient) -> bool {
+        let mut server = tcp_client_with(LookupConfig::default());
+        server.swarm.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let port = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = server.next_event().await {
+                break address.iter().find_map(|protocol| match protocol {
+                    Protocol::Tcp(port) => Some(port),
+                    _ => None,
+                }).unwrap();
+            }
+        };
+        let server_id = server.local_peer_id;
+        async_std::task::spawn(async move {
+            loop {
+                server.next_event().await;
+            }
+        });
+        let address: Multiaddr = format!("/dns4/localhost/tcp/{}", port).parse().unwrap();
+        client.swarm.dial(DialOpts::peer_id(server_id).addresses(vec![address]).build()).unwrap();
+        let outcome = async {
+            loop {
+                match client.next_event().await {
+                    SwarmEvent::ConnectionEstablished { .. } => break true,
+                    SwarmEvent::OutgoingConnectionError { .. } => break false,
+                    _ => {}
+                }
+            }
+        };
+        async_std::future::timeout(Duration::from_secs(30), outcome).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn dns_addresses_are_gated_on_their_resolved_ip() {
+        let loopback: ipnet::IpNet = "127.0.0.0/8".parse().unwrap();
+        let mut denied = tcp_client_with(LookupConfig::default());
+        denied.update_gate(|gate| gate.deny_ranges.push(loopback));
+        assert!(!dial_localhost(&mut denied).await);
+        let rejections = denied.rejections();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].reason, RejectionReason::DeniedRange(loopback));
+        // The rejection names the address the name resolved to.
+        match rejections[0].address.iter().next() {
+            Some(Protocol::Ip4(ip)) => assert!(loopback.contains(&std::net::IpAddr::V4(ip))),
+            other => panic!("Unexpected rejected address {:?}", other),
         }
+        // Allowed ranges admit the DNS addresses resolving into them.
+        let mut allowed = tcp_client_with(LookupConfig::default());
+        allowed.update_gate(|gate| gate.allow_ranges.push(loopback));
+        assert!(dial_localhost(&mut allowed).await);
+        assert!(allowed.rejections().is_empty());
+    }
 
-        Ok(test_protocol::SYN(vec))
+    #[async_std::test]
+    async fn shutdown_closes_listeners_and_connections() {
+        let (mut server, address) = memory_listener(ConnectionLimitSettings::unlimited()).await;
+        let server_id = server.local_peer_id;
+        let mut client = memory_client(ConnectionLimitSettings::unlimited());
+        // Two connections to the same peer, both counted.
+        for _ in 0..2 {
+            let opts = DialOpts::peer_id(server_id)
+                .condition(PeerCondition::Always)
+                .addresses(vec![address.clone()])
+                .build();
+            client.swarm.dial(opts).unwrap();
+        }
+        let server = async_std::task::spawn(async move {
+            loop {
+                if let SwarmEvent::ConnectionEstablished { num_established, .. } = server.next_event().await {
+                    if num_established.get() == 2 {
+                        break server;
+                    }
+                }
+            }
+        });
+        loop {
+            if let SwarmEvent::ConnectionEstablished { num_established, .. } = client.next_event().await {
+                if num_established.get() == 2 {
+                    break;
+                }
+            }
+        }
+        let mut server = server.await;
+        // Either answered within the grace period or cancelled.
+        server.swarm.behaviour_mut().kademlia.get_closest_peers(PeerId::random());
+        let server = async_std::task::spawn(async move {
+            let report = server.shutdown(Duration::from_millis(200)).await;
+            (server, report)
+        });
+        let shutdown = async {
+            while client.is_connected(&server_id) {
+                client.next_event().await;
+            }
+        };
+        async_std::future::timeout(Duration::from_secs(30), shutdown).await.unwrap();
+        let (mut server, report) = server.await;
+        assert!(server.listeners.is_empty());
+        assert_eq!(report.closed_connections, 2);
+        assert_eq!(server.swarm.connected_peers().count(), 0);
+        assert!(server.swarm.behaviour_mut().kademlia.iter_queries().next().is_none());
     }
 
-    async fn read_response<T>(&mut self, _: &TestProtocol, io: &mut T) -> io::Result<Self::Response>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        let vec = read_length_prefixed(io, 1024).await?;
+    #[async_std::test]
+    async fn last_seen_is_forgotten_outside_of_the_routing_table() {
+        let (mut server, address) = memory_listener(ConnectionLimitSettings::unlimited()).await;
+        let server_id = server.local_peer_id;
+        let mut client = memory_client(ConnectionLimitSettings::unlimited());
+        let client_id = client.local_peer_id;
+        client.kademlia_add_address(server_id, address.clone()).await;
+        client.swarm.dial(address).unwrap();
+        let server = async_std::task::spawn(async move {
+            loop {
+                match server.next_event().await {
+                    SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                        assert!(server.last_seen.contains_key(&peer_id));
+                        let _ = server.swarm.disconnect_peer_id(peer_id);
+                    },
+                    SwarmEvent::ConnectionClosed { num_established: 0, .. } => break server,
+                    _ => {},
+                }
+            }
+        });
+        let closed = async {
+            loop {
+                if let SwarmEvent::ConnectionClosed { num_established: 0, .. } = client.next_event().await {
+                    break;
+                }
+            }
+        };
+        async_std::future::timeout(Duration::from_secs(30), closed).await.unwrap();
+        let server = server.await;
+        // The client never made it into the routing table of the server.
+        assert!(!server.last_seen.contains_key(&client_id));
+        assert!(client.last_seen.contains_key(&server_id));
+    }
 
-        if vec.is_empty() {
-            return Err(io::ErrorKind::UnexpectedEof.into());
+    #[async_std::test]
+    async fn routing_table_lists_the_known_peers_by_bucket() {
+        let mut client = memory_client(ConnectionLimitSettings::unlimited());
+        let local_key = libp2p_kad::kbucket::Key::from(client.local_peer_id);
+        let index_of = |peer_id: PeerId| local_key.distance(&libp2p_kad::kbucket::Key::from(peer_id)).ilog2();
+        let address = |port: usize| -> Multiaddr { format!("/ip4/1.2.3.4/tcp/{port}").parse().unwrap() };
+        // Few enough for every bucket to keep all of its peers.
+        let peers: Vec<PeerId> = (0..16).map(|_| PeerId::random()).collect();
+        for (port, peer_id) in peers.iter().enumerate() {
+            client.kademlia_add_address(*peer_id, address(port)).await;
+        }
+        let table = client.routing_table();
+        assert!(table.windows(2).all(|pair| pair[0].index < pair[1].index));
+        let mut listed = Vec::new();
+        for bucket in &table {
+            assert!(!bucket.entries.is_empty());
+            for entry in &bucket.entries {
+                let port = peers.iter().position(|peer_id| *peer_id == entry.peer_id).unwrap();
+                assert_eq!(index_of(entry.peer_id), Some(bucket.index));
+                assert_eq!(entry.addresses, vec![address(port)]);
+                assert_eq!(entry.status, ConnectionStatus::Disconnected);
+                assert_eq!(entry.last_seen, None);
+                listed.push(entry.peer_id);
+            }
         }
+        listed.sort();
+        let mut expected = peers.clone();
+        expected.sort();
+        assert_eq!(listed, expected);
+        for peer_id in &peers {
+            let position = client.bucket_of(peer_id).unwrap();
+            assert_eq!(Some(position.index), index_of(*peer_id));
+            assert!(position.present);
+            let bucket = table.iter().find(|bucket| bucket.index == position.index).unwrap();
+            assert_eq!(position.range, Some(bucket.range));
+            assert!(bucket.range.0 <= position.distance && position.distance <= bucket.range.1);
+        }
+        let unknown = PeerId::random();
+        let position = client.bucket_of(&unknown).unwrap();
+        assert_eq!(Some(position.index), index_of(unknown));
+        assert!(!position.present);
+        let local_peer_id = client.local_peer_id;
+        assert!(client.bucket_of(&local_peer_id).is_none());
+        let key = b"record".to_vec();
+        let position = client.bucket_of_key(&key).unwrap();
+        assert_eq!(Some(position.index), local_key.distance(&libp2p_kad::kbucket::Key::new(key)).ilog2());
+        assert!(!position.present);
+        let range = position.range.unwrap();
+        assert!(range.0 <= position.distance && position.distance <= range.1);
+    }
+
+    #[async_std::test]
+    async fn dials_time_out() {
+        // The listener is never polled, so the connection upgrade never completes.
+        let (listener, address) = memory_listener(ConnectionLimitSettings::unlimited()).await;
+        let mut client = memory_client_with(LookupConfig {
+            dial_timeout: Duration::from_secs(1),
+            ..Default::default()
+        });
+        let peer = Peer {
+            peer_id: listener.local_peer_id,
+            protocol_version: String::new(),
+            agent_version: String::new(),
+            listen_addrs: vec![address],
+            protocols: Vec::new(),
+            observed_addr: Multiaddr::empty(),
+        };
+        assert!(matches!(client.dial(&peer).await, Err(NetworkError::DialTimeout(peer_id)) if peer_id == listener.local_peer_id));
+    }
+
+    #[async_std::test]
+    async fn bans_expire_on_an_idle_swarm() {
+        let mut client = memory_client_with(LookupConfig {
+            reputation: ReputationConfig {
+                ban_duration: Duration::from_secs(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let peer_id = PeerId::random();
+        client.report_misbehaviour(peer_id, Misbehaviour::MalformedMessage);
+        client.report_misbehaviour(peer_id, Misbehaviour::MalformedMessage);
+        assert!(client.is_banned(&peer_id));
+        // No swarm event arrives, the ban is lifted by its deadline.
+        assert!(async_std::future::timeout(Duration::from_secs(3), client.next_event()).await.is_err());
+        assert!(!client.is_banned(&peer_id));
+    }
+
+    #[async_std::test]
+    async fn identify_only_removes_its_own_addresses() {
+        let mut client = memory_client(ConnectionLimitSettings::unlimited());
+        let peer_id = PeerId::random();
+        let address = |port: u16| -> Multiaddr { format!("/ip4/1.2.3.4/tcp/{port}").parse().unwrap() };
+        // Known from another source before identify advertises it too.
+        client.kademlia_add_address(peer_id, address(1)).await;
+        client.refresh_identified_addresses(&peer_id, &[address(1), address(2), address(3)]);
+        assert_eq!(client.addresses_in_routing_table(&peer_id).len(), 3);
+        client.refresh_identified_addresses(&peer_id, &[address(3), address(4)]);
+        let mut addresses = client.addresses_in_routing_table(&peer_id);
+        addresses.sort();
+        let mut expected = vec![address(1), address(3), address(4)];
+        expected.sort();
+        assert_eq!(addresses, expected);
+        assert_eq!(client.identified_addrs[&peer_id], vec![address(3), address(4)]);
+    }
+
+    #[async_std::test]
+    async fn peers_are_found_by_protocol() {
+        let mut peers = Vec::new();
+        for mode in [KademliaMode::Server, KademliaMode::Client] {
+            let (mut peer, address) = listening(memory_client_with(LookupConfig {
+                kademlia_mode: mode,
+                ..Default::default()
+            })).await;
+            peers.push((peer.local_peer_id, address));
+            async_std::task::spawn(async move {
+                loop {
+                    peer.next_event().await;
+                }
+            });
+        }
+        let (server_id, client_id) = (peers[0].0, peers[1].0);
+        let mut searcher = memory_client(ConnectionLimitSettings::unlimited());
+        let protocol = String::from_utf8(searcher.swarm.behaviour().kademlia.protocol_names().remove(0)).unwrap();
+        for (peer_id, address) in peers {
+            searcher.kademlia_add_address(peer_id, address).await;
+        }
+        // Connected before the search and left alone by it.
+        let (mut bystander, address) = listening(memory_client_with(LookupConfig {
+            kademlia_mode: KademliaMode::Client,
+            ..Default::default()
+        })).await;
+        let bystander_id = bystander.local_peer_id;
+        async_std::task::spawn(async move {
+            loop {
+                bystander.next_event().await;
+            }
+        });
+        searcher.swarm.dial(address).unwrap();
+        let connected = async {
+            while !searcher.is_connected(&bystander_id) {
+                searcher.next_event().await;
+            }
+        };
+        async_std::future::timeout(Duration::from_secs(30), connected).await.unwrap();
+        // Only one of them can match, the search runs until its deadline.
+        let found = searcher.find_peers_with_protocol_within(&protocol, 2, Duration::from_secs(5)).await;
+        assert_eq!(found.iter().map(|peer| peer.peer_id).collect::<Vec<_>>(), vec![server_id]);
+        assert!(searcher.peer_store().get(&client_id).is_some());
+        let closed = async {
+            while searcher.is_connected(&client_id) {
+                searcher.next_event().await;
+            }
+        };
+        async_std::future::timeout(Duration::from_secs(30), closed).await.unwrap();
+        assert!(searcher.is_connected(&server_id));
+        assert!(searcher.is_connected(&bystander_id));
+    }
 
-        Ok(test_protocol::SYNACK(vec))
+    #[async_std::test]
+    async fn kademlia_mode_switch_is_advertised() {
+        let (mut server, address) = listening(memory_client_with(LookupConfig {
+            kademlia_mode: KademliaMode::Client,
+            ..Default::default()
+        })).await;
+        let server_id = server.local_peer_id;
+        let kademlia_protocols: Vec<String> = server.swarm.behaviour().kademlia
+            .protocol_names()
+            .into_iter()
+            .map(|name| String::from_utf8(name).unwrap())
+            .collect();
+        // Switches once the first identify info went out.
+        async_std::task::spawn(async move {
+            loop {
+                let event = server.next_event().await;
+                if let SwarmEvent::Behaviour(LookupBehaviourEvent::Identify(identify::Event::Sent { .. })) = event {
+                    if server.kademlia_mode() == KademliaMode::Client {
+                        server.set_kademlia_mode(KademliaMode::Server);
+                    }
+                }
+            }
+        });
+        let mut observer = memory_client(ConnectionLimitSettings::unlimited());
+        observer.swarm.dial(address).unwrap();
+        let advertised = async {
+            let mut advertised = Vec::new();
+            while advertised.len() < 2 {
+                if let SwarmEvent::Behaviour(LookupBehaviourEvent::Identify(
+                    identify::Event::Received { peer_id, info }
+                )) = observer.next_event().await {
+                    if peer_id == server_id {
+                        advertised.push(kademlia_protocols.iter().all(|protocol| info.protocols.contains(protocol)));
+                    }
+                }
+            }
+            advertised
+        };
+        let advertised = async_std::future::timeout(Duration::from_secs(30), advertised).await.unwrap();
+        assert_eq!(advertised, vec![false, true]);
     }
 
-    async fn write_request<T>(
-        &mut self,
-        _: &TestProtocol,
-        io: &mut T,
-        test_protocol::SYN(data): test_protocol::SYN,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        write_length_prefixed(io, data).await?;
-        io.close().await?;
+    // Drives both clients until a closest peers query of `querier` completes, returns
+    // whether `peer` answered it.
+    async fn answers_kademlia(querier: &mut LookupClient, peer: &mut LookupClient) -> bool {
+        let peer_id = peer.local_peer_id;
+        querier.swarm.behaviour_mut().kademlia.get_closest_peers(PeerId::random());
+        let answered = async {
+            loop {
+                futures::select! {
+                    event = querier.next_event().fuse() => {
+                        if let SwarmEvent::Behaviour(LookupBehaviourEvent::Kademlia(KademliaEvent::OutboundQueryCompleted {
+                            result: QueryResult::GetClosestPeers(Ok(GetClosestPeersOk { peers, .. })),
+                            ..
+                        })) = event {
+                            break peers.contains(&peer_id);
+                        }
+                    },
+                    _ = peer.next_event().fuse() => {},
+                }
+            }
+        };
+        async_std::future::timeout(Duration::from_secs(30), answered).await.unwrap()
+    }
 
-        Ok(())
+    #[async_std::test]
+    async fn client_mode_refuses_inbound_kademlia_requests() {
+        let (mut peer, address) = memory_listener(ConnectionLimitSettings::unlimited()).await;
+        let peer_id = peer.local_peer_id;
+        let mut querier = memory_client(ConnectionLimitSettings::unlimited());
+        querier.kademlia_add_address(peer_id, address).await;
+        assert!(answers_kademlia(&mut querier, &mut peer).await);
+        // The open connection picks up the switch.
+        assert!(querier.is_connected(&peer_id));
+        peer.set_kademlia_mode(KademliaMode::Client);
+        assert!(!answers_kademlia(&mut querier, &mut peer).await);
+        peer.set_kademlia_mode(KademliaMode::Server);
+        assert!(answers_kademlia(&mut querier, &mut peer).await);
     }
 
-    async fn write_response<T>(
-        &mut self,
-        _: &TestProtocol,
-        io: &mut T,
-        test_protocol::SYNACK(data): test_protocol::SYNACK,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        write_length_prefixed(io, data).await?;
-        io.close().await?;
+    // Runs a handshake between clients offering the given versions, returns the version
+    // each side recorded. Only the legacy version completes without a liveness proof.
+    #[cfg(feature = "test-protocol")]
+    async fn negotiate(requested: Vec<SerdeProtocol>, offered: Vec<SerdeProtocol>) -> (Option<SerdeProtocol>, Option<SerdeProtocol>) {
+        let (mut responder, address) = listening(memory_client_with(LookupConfig {
+            handshake_versions: offered,
+            ..Default::default()
+        })).await;
+        let mut requester = memory_client_with(LookupConfig {
+            handshake_versions: requested,
+            ..Default::default()
+        });
+        let (requester_id, responder_id) = (requester.local_peer_id, responder.local_peer_id);
+        let responder = async_std::task::spawn(async move {
+            let result = responder.init_protocol().await;
+            (responder, result)
+        });
+        requester.add_address(responder_id, address).await;
+        requester.start_handshake(responder_id).await.unwrap();
+        assert_eq!(requester.init_protocol().await.unwrap(), responder_id);
+        // The ACK still has to go out.
+        let mut responder = Box::pin(responder);
+        let (responder, result) = loop {
+            match futures::future::select(responder, Box::pin(requester.next_event())).await {
+                futures::future::Either::Left((done, _)) => break done,
+                futures::future::Either::Right((_, pending)) => responder = pending,
+            }
+        };
+        assert_eq!(result.unwrap(), requester_id);
+        let legacy = requester.negotiated_version(&responder_id).map_or(false, test_protocol::is_legacy);
+        assert_eq!(requester.liveness_proof(&responder_id).is_some(), !legacy);
+        (requester.negotiated_version(&responder_id).cloned(), responder.negotiated_version(&requester_id).cloned())
+    }
 
-        Ok(())
+    #[cfg(feature = "test-protocol")]
+    #[async_std::test]
+    async fn handshake_versions_are_negotiated() {
+        let cbor = SerdeProtocol::with_encoding(TEST_PROTOCOL, Encoding::Cbor);
+        let json = SerdeProtocol::with_encoding(TEST_PROTOCOL_JSON, Encoding::Json);
+        let upgraded = vec![cbor.clone(), json.clone()];
+        let negotiated = |version: &SerdeProtocol| (Some(version.clone()), Some(version.clone()));
+        assert_eq!(negotiate(upgraded.clone(), upgraded.clone()).await, negotiated(&cbor));
+        // A peer offering JSON only is reached over JSON, whichever side dials.
+        assert_eq!(negotiate(upgraded.clone(), vec![json.clone()]).await, negotiated(&json));
+        assert_eq!(negotiate(vec![json.clone()], upgraded).await, negotiated(&json));
     }
-}
\ No newline at end of file
+
+    #[cfg(feature = "test-protocol")]
+    #[async_std::test]
+    async fn legacy_nodes_are_reached_when_opted_in() {
+        let legacy = SerdeProtocol::with_encoding(TEST_PROTOCOL_LEGACY, Encoding::Raw);
+        let negotiated = (Some(legacy.clone()), Some(legacy.clone()));
+        // Nodes still on the raw bytes of /SYNACK/0.0.1, whichever side dials.
+        assert_eq!(negotiate(test_protocol_versions_with_legacy(), vec![legacy.clone()]).await, negotiated);
+        assert_eq!(negotiate(vec![legacy.clone()], test_protocol_versions_with_legacy()).await, negotiated);
+        // Upgraded nodes never fall back to it between themselves.
+        let cbor = SerdeProtocol::with_encoding(TEST_PROTOCOL, Encoding::Cbor);
+        let versions = test_protocol_versions_with_legacy();
+        assert_eq!(negotiate(versions.clone(), versions).await, (Some(cbor.clone()), Some(cbor)));
+    }
+
+    #[cfg(feature = "test-protocol")]
+    #[async_std::test]
+    async fn legacy_nodes_are_refused_by_default() {
+        let (mut responder, address) = listening(memory_client_with(LookupConfig {
+            handshake_versions: vec![SerdeProtocol::with_encoding(TEST_PROTOCOL_LEGACY, Encoding::Raw)],
+            ..Default::default()
+        })).await;
+        let responder_id = responder.local_peer_id;
+        async_std::task::spawn(async move {
+            loop {
+                responder.next_event().await;
+            }
+        });
+        let mut requester = memory_client_with(LookupConfig::default());
+        requester.add_address(responder_id, address).await;
+        let request_id = requester.start_handshake(responder_id).await.unwrap();
+        match requester.init_protocol().await {
+            Err(NetworkError::UnsupportedProtocols { peer_id, request_id: failed, .. }) => {
+                assert_eq!((peer_id, failed), (responder_id, request_id));
+            },
+            other => panic!("Unexpected result : {:?}", other),
+        }
+        assert!(requester.negotiated_version(&responder_id).is_none());
+    }
+
+    #[cfg(feature = "test-protocol")]
+    #[async_std::test]
+    async fn handshake_server_stops_on_signal() {
+        let (mut responder, _) = memory_listener(ConnectionLimitSettings::unlimited()).await;
+        let (stop, stopped) = futures::channel::oneshot::channel::<()>();
+        let server = async_std::task::spawn(async move {
+            responder.serve_handshakes_until(stopped.map(|_| ())).await;
+            responder.shutdown(Duration::from_secs(1)).await
+        });
+        stop.send(()).unwrap();
+        let report = async_std::future::timeout(Duration::from_secs(30), server).await.unwrap();
+        assert_eq!(report.closed_connections, 0);
+    }
+
+    #[cfg(feature = "test-protocol")]
+    #[async_std::test]
+    async fn served_handshakes_are_reported() {
+        let (mut responder, address) = memory_listener(ConnectionLimitSettings::unlimited()).await;
+        let responder_id = responder.local_peer_id;
+        let mut events = responder.handshake_events();
+        async_std::task::spawn(async move { responder.serve_handshakes().await });
+        let mut requesters = Vec::new();
+        for _ in 0..3 {
+            let mut requester = memory_client(ConnectionLimitSettings::unlimited());
+            requester.add_address(responder_id, address.clone()).await;
+            requester.start_handshake(responder_id).await.unwrap();
+            requesters.push(requester);
+        }
+        let mut expected: HashSet<PeerId> = requesters.iter().map(|requester| requester.local_peer_id).collect();
+        for mut requester in requesters {
+            async_std::task::spawn(async move {
+                assert_eq!(requester.init_protocol().await.unwrap(), responder_id);
+                // Delivers the ACK.
+                loop {
+                    requester.next_event().await;
+                }
+            });
+        }
+        while !expected.is_empty() {
+            match events.next().await {
+                Some(HandshakeEvent::Completed { peer_id, protocol, .. }) => {
+                    assert!(expected.remove(&peer_id));
+                    assert_eq!(protocol.as_ref().map(SerdeProtocol::name), Some(TEST_PROTOCOL));
+                },
+                other => panic!("Unexpected event : {:?}", other),
+            }
+        }
+    }
+
+    #[cfg(feature = "test-protocol")]
+    #[async_std::test]
+    async fn refused_and_expired_handshakes_are_reported() {
+        let (mut responder, address) = listening(memory_client_with(LookupConfig {
+            max_handshake_sessions: 2,
+            handshake_timeout: Duration::from_secs(1),
+            ..Default::default()
+        })).await;
+        let responder_id = responder.local_peer_id;
+        let mut events = responder.handshake_events();
+        async_std::task::spawn(async move { responder.serve_handshakes().await });
+        // The requesters never send their ACK, the first two sessions stay open until they
+        // time out and the third SYN is refused.
+        for _ in 0..3 {
+            let mut requester = memory_client(ConnectionLimitSettings::unlimited());
+            requester.add_address(responder_id, address.clone()).await;
+            requester.start_handshake(responder_id).await.unwrap();
+            async_std::task::spawn(async move {
+                loop {
+                    requester.next_event().await;
+                }
+            });
+        }
+        let (mut refused, mut timed_out) = (0, 0);
+        while refused + timed_out < 3 {
+            match async_std::future::timeout(Duration::from_secs(30), events.next()).await.unwrap() {
+                Some(HandshakeEvent::Failed(NetworkError::Handshake { error: HandshakeError::TooManySessions(2), .. })) => refused += 1,
+                Some(HandshakeEvent::Failed(NetworkError::Handshake { error: HandshakeError::Timeout(_), .. })) => timed_out += 1,
+                other => panic!("Unexpected event : {:?}", other),
+            }
+        }
+        assert_eq!((refused, timed_out), (1, 2));
+    }
+
+    #[cfg(feature = "test-protocol")]
+    #[async_std::test]
+    async fn init_protocol_returns_request_failures() {
+        let mut requester = memory_client(ConnectionLimitSettings::unlimited());
+        let unreachable = PeerId::random();
+        requester.add_address(unreachable, "/memory/1003".parse().unwrap()).await;
+        let request_id = requester.start_handshake(unreachable).await.unwrap();
+        match requester.init_protocol().await {
+            Err(NetworkError::RequestDialFailure { peer_id, request_id: failed }) => {
+                assert_eq!((peer_id, failed), (unreachable, request_id));
+            },
+            other => panic!("Unexpected result : {:?}", other),
+        }
+        assert_eq!(requester.handshake_state(&unreachable), None);
+
+        let (mut responder, address) = listening(memory_client_with(LookupConfig {
+            handshake_versions: vec![SerdeProtocol::with_encoding(TEST_PROTOCOL_JSON, Encoding::Json)],
+            ..Default::default()
+        })).await;
+        let responder_id = responder.local_peer_id;
+        async_std::task::spawn(async move {
+            loop {
+                responder.next_event().await;
+            }
+        });
+        let mut requester = memory_client_with(LookupConfig {
+            handshake_versions: vec![SerdeProtocol::with_encoding(TEST_PROTOCOL, Encoding::Cbor)],
+            ..Default::default()
+        });
+        requester.add_address(responder_id, address).await;
+        let request_id = requester.start_handshake(responder_id).await.unwrap();
+        match requester.init_protocol().await {
+            Err(NetworkError::UnsupportedProtocols { peer_id, request_id: failed, direction }) => {
+                assert_eq!((peer_id, failed, direction), (responder_id, request_id, Direction::Outbound));
+            },
+            other => panic!("Unexpected result : {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "test-protocol")]
+    #[async_std::test]
+    async fn oversized_responses_fail_their_request() {
+        let (mut responder, address) = memory_listener(ConnectionLimitSettings::unlimited()).await;
+        let responder_id = responder.local_peer_id;
+        async_std::task::spawn(async move { responder.serve_handshakes().await });
+        let mut requester = memory_client_with(LookupConfig {
+            handshake_limits: SizeLimits::new(64 * 1024, 16),
+            ..Default::default()
+        });
+        requester.add_address(responder_id, address).await;
+        let request_id = requester.start_handshake(responder_id).await.unwrap();
+        match requester.init_protocol().await {
+            Err(NetworkError::RequestTooLarge { peer_id, request_id: failed, direction, error }) => {
+                assert_eq!((peer_id, failed, direction), (responder_id, request_id, Direction::Outbound));
+                assert_eq!((error.kind, error.limit), (MessageKind::Response, 16));
+            },
+            other => panic!("Unexpected result : {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "file-transfer")]
+    #[async_std::test]
+    async fn files_are_sent_between_clients() {
+        let temp_dir = |label: &str| std::env::temp_dir().join(format!("lookup-{}-{:016x}", label, rand::random::<u64>()));
+        let (source_dir, served) = (temp_dir("source"), temp_dir("served"));
+        std::fs::create_dir_all(&source_dir).unwrap();
+        let source = source_dir.join("census.json");
+        let content: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        std::fs::write(&source, &content).unwrap();
+        // The sender chunks are capped to the smaller ones of the receiver.
+        let (mut receiver, address) = listening(memory_client_with(LookupConfig {
+            transfer_chunk_size: 16 * 1024,
+            ..Default::default()
+        })).await;
+        receiver.serve_directory(&served).unwrap();
+        let receiver_id = receiver.local_peer_id;
+        async_std::task::spawn(async move {
+            loop {
+                receiver.next_event().await;
+            }
+        });
+        let mut sender = memory_client(ConnectionLimitSettings::unlimited());
+        sender.swarm.dial(address).unwrap();
+        while !sender.is_connected(&receiver_id) {
+            sender.next_event().await;
+        }
+        sender.send_file(receiver_id, &source).unwrap();
+        let completed = async {
+            loop {
+                sender.next_event().await;
+                for event in sender.transfer_events() {
+                    match event {
+                        TransferEvent::Completed { peer_id, sha256, .. } => return (peer_id, sha256),
+                        TransferEvent::Failed { reason, .. } => panic!("Transfer failed : {}", reason),
+                        TransferEvent::Progress { .. } => {},
+                    }
+                }
+            }
+        };
+        let (peer_id, sha256) = async_std::future::timeout(Duration::from_secs(30), completed).await.unwrap();
+        assert_eq!(peer_id, receiver_id);
+        assert_eq!(sha256, <[u8; 32]>::from(<sha2::Sha256 as sha2::Digest>::digest(&content)));
+        assert_eq!(std::fs::read(served.join("census.json")).unwrap(), content);
+        // A second copy does not replace the first one.
+        sender.send_file(receiver_id, &source).unwrap();
+        let refused = async {
+            loop {
+                sender.next_event().await;
+                if let Some(TransferEvent::Failed { reason, .. }) = sender.transfer_events().pop() {
+                    return reason;
+                }
+            }
+        };
+        let reason = async_std::future::timeout(Duration::from_secs(30), refused).await.unwrap();
+        assert_eq!(reason, "census.json already exists");
+    }
+
+    #[cfg(feature = "test-protocol")]
+    struct Echo;
+
+    #[cfg(feature = "test-protocol")]
+    #[async_trait::async_trait]
+    impl RequestHandler for Echo {
+        type Request = test_protocol::SYN;
+        type Response = test_protocol::SYNACK;
+
+        async fn handle(&self, _: PeerId, request: test_protocol::SYN) -> Result<test_protocol::SYNACK, HandlerError> {
+            match request.0.as_slice() {
+                b"fail" => Err(HandlerError::Failed("refused".to_string())),
+                b"panic" => panic!("handler bug"),
+                b"slow" => {
+                    async_std::task::sleep(Duration::from_millis(500)).await;
+                    Ok(test_protocol::SYNACK(request.0))
+                },
+                _ => Ok(test_protocol::SYNACK(request.0)),
+            }
+        }
+
+        fn error_response(&self, error: &HandlerError) -> Option<test_protocol::SYNACK> {
+            match error {
+                HandlerError::Failed(reason) => Some(test_protocol::SYNACK(reason.as_bytes().to_vec())),
+                HandlerError::Busy => Some(test_protocol::SYNACK(b"busy".to_vec())),
+                HandlerError::TimedOut(_) => Some(test_protocol::SYNACK(b"timed out".to_vec())),
+                HandlerError::Panicked(_) => None,
+            }
+        }
+    }
+
+    // Responses in the order they arrive, failures as None.
+    #[cfg(feature = "test-protocol")]
+    async fn exchange(client: &mut LookupClient, peer_id: PeerId, payloads: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        let mut pending = HashSet::new();
+        for payload in payloads {
+            pending.insert(client.send_request(peer_id, test_protocol::SYN(payload.to_vec())).await.unwrap());
+        }
+        let mut responses = Vec::new();
+        while !pending.is_empty() {
+            match client.next_event().await {
+                SwarmEvent::Behaviour(LookupBehaviourEvent::RequestResponse(RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Response { request_id, response },
+                    ..
+                })) if pending.remove(&request_id) => responses.push(Some(response.message.0)),
+                SwarmEvent::Behaviour(LookupBehaviourEvent::RequestResponse(RequestResponseEvent::OutboundFailure {
+                    request_id,
+                    ..
+                })) if pending.remove(&request_id) => responses.push(None),
+                _ => {},
+            }
+        }
+        responses
+    }
+
+    #[cfg(feature = "test-protocol")]
+    #[async_std::test]
+    async fn requests_are_dispatched_to_the_handler() {
+        let (mut responder, address) = memory_listener(ConnectionLimitSettings::unlimited()).await;
+        let responder_id = responder.local_peer_id;
+        responder.set_request_handler(Echo);
+        async_std::task::spawn(async move {
+            loop {
+                responder.next_event().await;
+            }
+        });
+        let mut requester = memory_client(ConnectionLimitSettings::unlimited());
+        requester.add_address(responder_id, address).await;
+        // The slow request does not hold the other one back.
+        assert_eq!(
+            exchange(&mut requester, responder_id, &[b"slow", b"fast"]).await,
+            vec![Some(b"fast".to_vec()), Some(b"slow".to_vec())]
+        );
+        assert_eq!(exchange(&mut requester, responder_id, &[b"fail"]).await, vec![Some(b"refused".to_vec())]);
+        assert_eq!(exchange(&mut requester, responder_id, &[b"panic"]).await, vec![None]);
+        // The responder survived the panic.
+        assert_eq!(exchange(&mut requester, responder_id, &[b"again"]).await, vec![Some(b"again".to_vec())]);
+    }
+
+    #[cfg(feature = "test-protocol")]
+    #[async_std::test]
+    async fn handlers_are_limited_in_time_and_number() {
+        let (mut responder, address) = listening(memory_client_with(LookupConfig {
+            max_handled_requests: 1,
+            request_handler_timeout: Duration::from_millis(200),
+            ..Default::default()
+        })).await;
+        let responder_id = responder.local_peer_id;
+        responder.set_request_handler(Echo);
+        let (report, mut reported) = mpsc::unbounded();
+        async_std::task::spawn(async move {
+            loop {
+                responder.next_event().await;
+                let _ = report.unbounded_send(responder.handlers.in_progress());
+            }
+        });
+        let mut requester = memory_client(ConnectionLimitSettings::unlimited());
+        requester.add_address(responder_id, address).await;
+        // The second request finds the only slot taken, the first one outlives its timeout.
+        assert_eq!(
+            exchange(&mut requester, responder_id, &[b"slow", b"slow"]).await,
+            vec![Some(b"busy".to_vec()), Some(b"timed out".to_vec())]
+        );
+        assert_eq!(exchange(&mut requester, responder_id, &[b"fast"]).await, vec![Some(b"fast".to_vec())]);
+        // Every request left the pending channels.
+        let pending = async {
+            while let Some(pending) = reported.next().await {
+                if pending == 0 {
+                    break;
+                }
+            }
+        };
+        async_std::future::timeout(Duration::from_secs(30), pending).await.unwrap();
+    }
+
+    #[cfg(feature = "test-protocol")]
+    #[async_std::test]
+    async fn handlers_are_registered_per_protocol() {
+        let (mut responder, address) = memory_listener(ConnectionLimitSettings::unlimited()).await;
+        let responder_id = responder.local_peer_id;
+        responder.set_protocol_handler(TEST_PROTOCOL_JSON, Echo);
+        async_std::task::spawn(async move {
+            loop {
+                responder.next_event().await;
+            }
+        });
+        // Requests over the CBOR version reach no handler, `next_event` hands them out and
+        // the loop above drops them unanswered.
+        let mut requester = memory_client(ConnectionLimitSettings::unlimited());
+        requester.add_address(responder_id, address.clone()).await;
+        assert_eq!(exchange(&mut requester, responder_id, &[b"echo"]).await, vec![None]);
+        let mut requester = memory_client_with(LookupConfig {
+            handshake_versions: vec![SerdeProtocol::with_encoding(TEST_PROTOCOL_JSON, Encoding::Json)],
+            ..Default::default()
+        });
+        requester.add_address(responder_id, address).await;
+        assert_eq!(exchange(&mut requester, responder_id, &[b"echo"]).await, vec![Some(b"echo".to_vec())]);
+    }
+
+    #[cfg(feature = "test-protocol")]
+    #[async_std::test]
+    async fn handshakes_are_served_alongside_the_handler() {
+        let (mut responder, address) = memory_listener(ConnectionLimitSettings::unlimited()).await;
+        let responder_id = responder.local_peer_id;
+        responder.set_request_handler(Echo);
+        let mut events = responder.handshake_events();
+        async_std::task::spawn(async move { responder.serve_handshakes().await });
+        let mut requester = memory_client(ConnectionLimitSettings::unlimited());
+        let requester_id = requester.local_peer_id;
+        requester.add_address(responder_id, address).await;
+        requester.start_handshake(responder_id).await.unwrap();
+        assert_eq!(requester.init_protocol().await.unwrap(), responder_id);
+        assert!(requester.liveness_proof(&responder_id).is_some());
+        // Delivers the ACK on the way.
+        assert_eq!(exchange(&mut requester, responder_id, &[b"echo"]).await, vec![Some(b"echo".to_vec())]);
+        match async_std::future::timeout(Duration::from_secs(30), events.next()).await.unwrap() {
+            Some(HandshakeEvent::Completed { peer_id, .. }) => assert_eq!(peer_id, requester_id),
+            other => panic!("Unexpected event : {:?}", other),
+        }
+    }
+}
+
+
+
+
+
+// Protocol dependencies .
+
+use libp2p::request_response::*;
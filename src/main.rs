@@ -18,7 +18,10 @@ async fn main() {
         
     };
     println!("Found {:?} {:?} {:?}", peer.peer_id, peer.listen_addrs, peer.protocols);
-    lookup.dial(&peer).await;
+    match lookup.dial(&peer).await {
+        Ok(address) => println!("Connected through {:?}", address),
+        Err(e) => println!("{}", e)
+    };
 
     println!("Ending Session.");
 
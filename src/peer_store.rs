@@ -0,0 +1,237 @@
+// Cache of the latest identify information of every identified peer.
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use libp2p_core::PeerId;
+use crate::{ConfigError, Peer};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerRecord {
+    pub peer: Peer,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvictionPolicy {
+    // Least recently seen peers are evicted beyond this number of records.
+    pub max_peers: Option<usize>,
+    // Records not refreshed for this long are evicted.
+    pub max_age: Option<Duration>,
+}
+
+impl EvictionPolicy {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_peers == Some(0) {
+            return Err(ConfigError::Zero("max_peers"));
+        }
+        if self.max_age == Some(Duration::ZERO) {
+            return Err(ConfigError::Zero("max_age"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        EvictionPolicy {
+            max_peers: Some(10_000),
+            max_age: Some(Duration::from_secs(24 * 60 * 60)),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct PeerStore {
+    records: HashMap<PeerId, PeerRecord>,
+    // Records ordered by `last_seen`, the oldest first.
+    by_age: BTreeSet<(DateTime<Utc>, PeerId)>,
+    eviction: EvictionPolicy,
+}
+
+impl PeerStore {
+    pub fn new(eviction: EvictionPolicy) -> Self {
+        PeerStore {
+            records: HashMap::new(),
+            by_age: BTreeSet::new(),
+            eviction,
+        }
+    }
+    pub fn insert(&mut self, peer: Peer) {
+        self.insert_at(peer, Utc::now());
+    }
+    pub(crate) fn insert_at(&mut self, peer: Peer, now: DateTime<Utc>) {
+        let peer_id = peer.peer_id;
+        match self.records.get_mut(&peer_id) {
+            Some(record) => {
+                self.by_age.remove(&(record.last_seen, peer_id));
+                record.peer = peer;
+                record.last_seen = now;
+            },
+            None => {
+                self.records.insert(peer_id, PeerRecord {
+                    peer,
+                    first_seen: now,
+                    last_seen: now,
+                });
+            }
+        }
+        self.by_age.insert((now, peer_id));
+        self.evict(now);
+    }
+    pub fn get(&self, peer_id: &PeerId) -> Option<&PeerRecord> {
+        self.records.get(peer_id)
+    }
+    pub fn remove(&mut self, peer_id: &PeerId) -> Option<PeerRecord> {
+        let record = self.records.remove(peer_id)?;
+        self.by_age.remove(&(record.last_seen, *peer_id));
+        Some(record)
+    }
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &PeerRecord> {
+        self.records.values()
+    }
+    pub fn with_protocol<'a>(&'a self, protocol: &'a str) -> impl Iterator<Item = &'a PeerRecord> + 'a {
+        self.iter().filter(move |record| record.peer.protocols.iter().any(|p| p == protocol))
+    }
+    pub fn with_agent_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a PeerRecord> + 'a {
+        self.iter().filter(move |record| record.peer.agent_version.starts_with(prefix))
+    }
+    pub fn set_eviction_policy(&mut self, eviction: EvictionPolicy) {
+        self.eviction = eviction;
+        self.evict(Utc::now());
+    }
+    // Only the evicted records are visited.
+    fn evict(&mut self, now: DateTime<Utc>) {
+        let max_age = self.eviction.max_age.and_then(|age| chrono::Duration::from_std(age).ok());
+        while let Some(&(last_seen, peer_id)) = self.by_age.iter().next() {
+            let expired = max_age.map_or(false, |max_age| now - last_seen > max_age);
+            let excess = self.eviction.max_peers.map_or(false, |max_peers| self.records.len() > max_peers);
+            if !expired && !excess {
+                break;
+            }
+            self.by_age.remove(&(last_seen, peer_id));
+            self.records.remove(&peer_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(agent_version: &str, protocols: &[&str]) -> Peer {
+        Peer {
+            peer_id: PeerId::random(),
+            protocol_version: "/ipfs/id/1.0.0".to_string(),
+            agent_version: agent_version.to_string(),
+            listen_addrs: vec!["/ip4/1.2.3.4/tcp/30333".parse().unwrap()],
+            protocols: protocols.iter().map(|p| p.to_string()).collect(),
+            observed_addr: "/ip4/5.6.7.8/tcp/4001".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn queries_by_protocol_and_agent() {
+        let mut store = PeerStore::default();
+        let parity = peer("Parity Polkadot/v0.9.36", &["/ksmcc3/kad", "/ipfs/ping/1.0.0"]);
+        let synack = peer("substrate-node/v2.0.0", &["/SYNACK/0.0.1"]);
+        let parity_id = parity.peer_id;
+        store.insert(parity);
+        store.insert(synack);
+        assert_eq!(store.len(), 2);
+        assert!(store.get(&parity_id).is_some());
+        assert_eq!(store.with_protocol("/SYNACK/0.0.1").count(), 1);
+        assert_eq!(store.with_protocol("/ksmcc3/kad").next().unwrap().peer.peer_id, parity_id);
+        assert_eq!(store.with_agent_prefix("Parity").count(), 1);
+        assert_eq!(store.with_agent_prefix("").count(), 2);
+    }
+
+    #[test]
+    fn refresh_keeps_first_seen() {
+        let mut store = PeerStore::default();
+        let first = peer("a", &[]);
+        let peer_id = first.peer_id;
+        let mut second = first.clone();
+        second.agent_version = "b".to_string();
+        let t0 = Utc::now();
+        let t1 = t0 + chrono::Duration::seconds(5);
+        store.insert_at(first, t0);
+        store.insert_at(second, t1);
+        let record = store.get(&peer_id).unwrap();
+        assert_eq!(record.first_seen, t0);
+        assert_eq!(record.last_seen, t1);
+        assert_eq!(record.peer.agent_version, "b");
+    }
+
+    #[test]
+    fn evicts_by_size_and_age() {
+        let mut store = PeerStore::new(EvictionPolicy {
+            max_peers: Some(2),
+            max_age: Some(Duration::from_secs(60)),
+        });
+        let t0 = Utc::now();
+        let oldest = peer("a", &[]);
+        let oldest_id = oldest.peer_id;
+        store.insert_at(oldest, t0);
+        store.insert_at(peer("b", &[]), t0 + chrono::Duration::seconds(1));
+        store.insert_at(peer("c", &[]), t0 + chrono::Duration::seconds(2));
+        assert_eq!(store.len(), 2);
+        assert!(store.get(&oldest_id).is_none());
+        store.insert_at(peer("d", &[]), t0 + chrono::Duration::seconds(120));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn refreshed_and_removed_records_leave_the_eviction_order() {
+        let mut store = PeerStore::new(EvictionPolicy {
+            max_peers: Some(2),
+            max_age: None,
+        });
+        let t0 = Utc::now();
+        let (first, second, third) = (peer("a", &[]), peer("b", &[]), peer("c", &[]));
+        let (first_id, second_id, third_id) = (first.peer_id, second.peer_id, third.peer_id);
+        store.insert_at(first.clone(), t0);
+        store.insert_at(second, t0 + chrono::Duration::seconds(1));
+        // Refreshed, the second peer is now the oldest.
+        store.insert_at(first, t0 + chrono::Duration::seconds(2));
+        store.insert_at(third, t0 + chrono::Duration::seconds(3));
+        assert!(store.get(&second_id).is_none());
+        assert!(store.get(&first_id).is_some());
+        store.remove(&first_id);
+        store.insert_at(peer("d", &[]), t0 + chrono::Duration::seconds(4));
+        assert_eq!(store.len(), 2);
+        assert!(store.get(&third_id).is_some());
+        assert_eq!(store.by_age.len(), 2);
+    }
+
+    #[test]
+    fn lower_limits_keep_the_latest_peers() {
+        let mut store = PeerStore::new(EvictionPolicy {
+            max_peers: None,
+            max_age: None,
+        });
+        let t0 = Utc::now();
+        let mut latest = Vec::new();
+        for second in 0..10 {
+            let seen = peer("a", &[]);
+            if second >= 7 {
+                latest.push(seen.peer_id);
+            }
+            store.insert_at(seen, t0 + chrono::Duration::seconds(second));
+        }
+        store.set_eviction_policy(EvictionPolicy {
+            max_peers: Some(3),
+            max_age: None,
+        });
+        assert_eq!(store.len(), 3);
+        assert!(latest.iter().all(|peer_id| store.get(peer_id).is_some()));
+    }
+}
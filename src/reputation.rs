@@ -0,0 +1,374 @@
+// Per-peer reputation scores with decay and temporary bans.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{Duration, Instant};
+use libp2p::swarm::{
+    ConnectionHandlerUpgrErr,
+    DialError,
+    SwarmEvent
+};
+use libp2p::{identify, ping};
+#[cfg(feature = "test-protocol")]
+use libp2p::request_response::{InboundFailure, OutboundFailure, RequestResponseEvent};
+use libp2p_core::{upgrade::UpgradeError, PeerId};
+use crate::{gate, ConfigError, LookupBehaviourEvent, LookupClient, LookupSwarmEvent};
+
+// Scores closer to 0 than this are forgotten.
+const FORGOTTEN_SCORE: f64 = 0.01;
+// Time the failures caused by a connection close of our own are not held against the peer.
+const CLOSE_GRACE: Duration = Duration::from_secs(10);
+// Smallest number of tracked peers before decayed ones are pruned.
+const MIN_PRUNE_AT: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehaviour {
+    DialFailure,
+    PingTimeout,
+    RequestFailure,
+    MalformedMessage,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReputationConfig {
+    // Penalties are negative, a peer without history scores 0.
+    pub dial_failure: f64,
+    pub ping_timeout: f64,
+    pub request_failure: f64,
+    pub malformed_message: f64,
+    // Peers at or below this score are disconnected and banned.
+    pub ban_threshold: f64,
+    pub ban_duration: Duration,
+    // Time for a score to decay halfway back to 0.
+    pub half_life: Duration,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        ReputationConfig {
+            dial_failure: -10.0,
+            ping_timeout: -5.0,
+            request_failure: -10.0,
+            malformed_message: -50.0,
+            ban_threshold: -100.0,
+            ban_duration: Duration::from_secs(60 * 60),
+            half_life: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl ReputationConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let penalties = [
+            ("dial_failure", self.dial_failure),
+            ("ping_timeout", self.ping_timeout),
+            ("request_failure", self.request_failure),
+            ("malformed_message", self.malformed_message),
+        ];
+        // A zero penalty leaves the misbehaviour unpunished.
+        if let Some((name, _)) = penalties.iter().find(|(_, penalty)| penalty.is_nan() || *penalty > 0.0) {
+            return Err(ConfigError::Positive(*name));
+        }
+        // Peers without history score 0 and would be banned on their first misbehaviour.
+        if self.ban_threshold.is_nan() || self.ban_threshold >= 0.0 {
+            return Err(ConfigError::NotNegative("ban_threshold"));
+        }
+        if self.ban_duration == Duration::ZERO {
+            return Err(ConfigError::Zero("ban_duration"));
+        }
+        if self.half_life == Duration::ZERO {
+            return Err(ConfigError::Zero("half_life"));
+        }
+        Ok(())
+    }
+    fn penalty(&self, misbehaviour: Misbehaviour) -> f64 {
+        match misbehaviour {
+            Misbehaviour::DialFailure => self.dial_failure,
+            Misbehaviour::PingTimeout => self.ping_timeout,
+            Misbehaviour::RequestFailure => self.request_failure,
+            Misbehaviour::MalformedMessage => self.malformed_message,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Score {
+    value: f64,
+    updated: Instant,
+}
+
+impl Score {
+    fn at(&self, now: Instant, half_life: Duration) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.value * 0.5f64.powf(elapsed / half_life.as_secs_f64().max(f64::EPSILON))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Reputation {
+    config: ReputationConfig,
+    scores: HashMap<PeerId, Score>,
+    // Ban expiry per banned peer.
+    banned: HashMap<PeerId, Instant>,
+    // Same expiries, earliest first.
+    expiries: BinaryHeap<Reverse<(Instant, PeerId)>>,
+    // Peers whose connections we closed ourselves, with the time of the close.
+    closing: HashMap<PeerId, Instant>,
+    // Number of tracked peers at which decayed scores and old closes are pruned, twice the
+    // number left by the last pruning so that the cost is amortised over the reports.
+    prune_at: usize,
+}
+
+impl Reputation {
+    pub fn new(config: ReputationConfig) -> Self {
+        Reputation {
+            config,
+            scores: HashMap::new(),
+            banned: HashMap::new(),
+            expiries: BinaryHeap::new(),
+            closing: HashMap::new(),
+            prune_at: MIN_PRUNE_AT,
+        }
+    }
+    pub fn score(&self, peer_id: &PeerId) -> f64 {
+        self.score_at(peer_id, Instant::now())
+    }
+    fn score_at(&self, peer_id: &PeerId, now: Instant) -> f64 {
+        self.scores.get(peer_id).map_or(0.0, |score| score.at(now, self.config.half_life))
+    }
+    pub fn scores(&self) -> Vec<(PeerId, f64)> {
+        let now = Instant::now();
+        self.scores
+            .iter()
+            .map(|(peer_id, score)| (*peer_id, score.at(now, self.config.half_life)))
+            .collect()
+    }
+    // Returns true when the report gets the peer banned.
+    pub fn report(&mut self, peer_id: PeerId, misbehaviour: Misbehaviour) -> bool {
+        self.report_at(peer_id, misbehaviour, Instant::now())
+    }
+    pub(crate) fn report_at(&mut self, peer_id: PeerId, misbehaviour: Misbehaviour, now: Instant) -> bool {
+        let value = self.score_at(&peer_id, now) + self.config.penalty(misbehaviour);
+        self.scores.insert(peer_id, Score { value, updated: now });
+        self.prune_if_grown(now);
+        if value <= self.config.ban_threshold && !self.banned.contains_key(&peer_id) {
+            let until = now + self.config.ban_duration;
+            self.banned.insert(peer_id, until);
+            self.expiries.push(Reverse((until, peer_id)));
+            return true;
+        }
+        false
+    }
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.banned.contains_key(peer_id)
+    }
+    // When the earliest ban ends, `None` without bans.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.expiries.peek().map(|Reverse((until, _))| *until)
+    }
+    // Lifts the bans whose period is over. The score keeps decaying from where it was.
+    pub fn expire_bans(&mut self) -> Vec<PeerId> {
+        self.expire_bans_at(Instant::now())
+    }
+    pub(crate) fn expire_bans_at(&mut self, now: Instant) -> Vec<PeerId> {
+        let mut expired = Vec::new();
+        while let Some(Reverse((until, peer_id))) = self.expiries.peek().cloned() {
+            if until > now {
+                break;
+            }
+            self.expiries.pop();
+            self.banned.remove(&peer_id);
+            expired.push(peer_id);
+        }
+        if !expired.is_empty() {
+            self.prune(now);
+        }
+        expired
+    }
+    // Drops the score of a peer once it decayed to nothing, unless the peer is banned.
+    pub(crate) fn forget_decayed(&mut self, peer_id: &PeerId) {
+        if !self.banned.contains_key(peer_id) && self.score(peer_id).abs() <= FORGOTTEN_SCORE {
+            self.scores.remove(peer_id);
+        }
+    }
+    pub(crate) fn closing(&mut self, peer_id: PeerId, now: Instant) {
+        self.closing.insert(peer_id, now);
+        self.prune_if_grown(now);
+    }
+    // Whether a connection of the peer was closed by us within `CLOSE_GRACE`.
+    pub(crate) fn closed_by_us(&self, peer_id: &PeerId, now: Instant) -> bool {
+        self.closing.get(peer_id).map_or(false, |since| now.saturating_duration_since(*since) < CLOSE_GRACE)
+    }
+    // A new connection, its closes are no longer ours.
+    pub(crate) fn connected(&mut self, peer_id: &PeerId) {
+        self.closing.remove(peer_id);
+    }
+    pub(crate) fn tracked(&self) -> usize {
+        self.scores.len() + self.closing.len()
+    }
+    // Peers that only ever failed to dial are never connected, and so never forgotten by
+    // `forget_decayed`.
+    fn prune_if_grown(&mut self, now: Instant) {
+        if self.tracked() >= self.prune_at.max(MIN_PRUNE_AT) {
+            self.prune(now);
+        }
+    }
+    fn prune(&mut self, now: Instant) {
+        let half_life = self.config.half_life;
+        let banned = &self.banned;
+        self.scores.retain(|peer_id, score| banned.contains_key(peer_id) || score.at(now, half_life).abs() > FORGOTTEN_SCORE);
+        self.closing.retain(|_, since| now.saturating_duration_since(*since) < CLOSE_GRACE);
+        self.prune_at = 2 * self.tracked();
+    }
+}
+
+impl LookupClient {
+    pub fn reputation(&self, peer_id: &PeerId) -> f64 {
+        self.reputation.score(peer_id)
+    }
+    pub fn reputations(&self) -> Vec<(PeerId, f64)> {
+        self.reputation.scores()
+    }
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.reputation.is_banned(peer_id)
+    }
+    // For misbehaviour only the application can detect, such as invalid payloads.
+    pub fn report_misbehaviour(&mut self, peer_id: PeerId, misbehaviour: Misbehaviour) {
+        if self.reputation.report(peer_id, misbehaviour) {
+            println!("Banning {:?}, reputation {:.1}.", peer_id, self.reputation.score(&peer_id));
+            // Disconnects the peer and refuses its connections until unbanned.
+            self.reputation.closing(peer_id, Instant::now());
+            self.swarm.ban_peer_id(peer_id);
+        }
+    }
+    // Closes the connections to a peer without holding the failures of the close against it.
+    pub(crate) fn disconnect(&mut self, peer_id: PeerId) {
+        self.reputation.closing(peer_id, Instant::now());
+        let _ = self.swarm.disconnect_peer_id(peer_id);
+    }
+    // Called by `next_event` when the earliest ban ends, no swarm event is needed.
+    pub(crate) fn lift_expired_bans(&mut self) {
+        for peer_id in self.reputation.expire_bans() {
+            println!("Ban of {:?} expired.", peer_id);
+            self.swarm.unban_peer_id(peer_id);
+        }
+    }
+    pub(crate) fn update_reputation(&mut self, event: &LookupSwarmEvent) {
+        match event {
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => self.reputation.forget_decayed(peer_id),
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => self.reputation.connected(peer_id),
+            _ => {},
+        }
+        let misbehaviour = match event {
+            // Addresses refused by our own gate say nothing about the peer.
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error: DialError::Transport(errors),
+            } if !errors.iter().all(|(_, error)| gate::is_rejection(error)) => Some((*peer_id, Misbehaviour::DialFailure)),
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error: DialError::WrongPeerId { .. },
+            } => Some((*peer_id, Misbehaviour::DialFailure)),
+            SwarmEvent::Behaviour(LookupBehaviourEvent::Ping(
+                ping::Event { peer, result: Err(ping::Failure::Timeout) }
+            )) => Some((*peer, Misbehaviour::PingTimeout)),
+            SwarmEvent::Behaviour(LookupBehaviourEvent::Identify(
+                identify::Event::Error { peer_id, error: ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Apply(_)) }
+            )) => Some((*peer_id, Misbehaviour::MalformedMessage)),
+            #[cfg(feature = "test-protocol")]
+            SwarmEvent::Behaviour(LookupBehaviourEvent::RequestResponse(
+                RequestResponseEvent::OutboundFailure { peer, error: OutboundFailure::Timeout, .. }
+            )) => Some((*peer, Misbehaviour::RequestFailure)),
+            // Unless we closed the connection ourselves.
+            #[cfg(feature = "test-protocol")]
+            SwarmEvent::Behaviour(LookupBehaviourEvent::RequestResponse(
+                RequestResponseEvent::OutboundFailure { peer, error: OutboundFailure::ConnectionClosed, .. }
+            )) if !self.reputation.closed_by_us(peer, Instant::now()) => Some((*peer, Misbehaviour::RequestFailure)),
+            #[cfg(feature = "test-protocol")]
+            SwarmEvent::Behaviour(LookupBehaviourEvent::RequestResponse(
+                RequestResponseEvent::InboundFailure { peer, error: InboundFailure::Timeout, .. }
+            )) => Some((*peer, Misbehaviour::RequestFailure)),
+            _ => None,
+        };
+        if let Some((peer_id, misbehaviour)) = misbehaviour {
+            self.report_misbehaviour(peer_id, misbehaviour);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_decay_over_time() {
+        let mut reputation = Reputation::new(ReputationConfig::default());
+        let peer_id = PeerId::random();
+        let now = Instant::now();
+        reputation.report_at(peer_id, Misbehaviour::RequestFailure, now);
+        assert_eq!(reputation.score_at(&peer_id, now), -10.0);
+        let later = now + Duration::from_secs(10 * 60);
+        assert!((reputation.score_at(&peer_id, later) + 5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn bans_below_threshold_for_a_period() {
+        let mut reputation = Reputation::new(ReputationConfig::default());
+        let peer_id = PeerId::random();
+        let now = Instant::now();
+        assert!(!reputation.report_at(peer_id, Misbehaviour::MalformedMessage, now));
+        assert!(reputation.report_at(peer_id, Misbehaviour::MalformedMessage, now));
+        assert!(reputation.is_banned(&peer_id));
+        // Already banned, no second ban.
+        assert!(!reputation.report_at(peer_id, Misbehaviour::DialFailure, now));
+        assert!(reputation.expire_bans_at(now + Duration::from_secs(60)).is_empty());
+        assert_eq!(reputation.expire_bans_at(now + Duration::from_secs(60 * 60)), vec![peer_id]);
+        assert!(!reputation.is_banned(&peer_id));
+    }
+
+    #[test]
+    fn earliest_ban_expires_first() {
+        let mut reputation = Reputation::new(ReputationConfig {
+            ban_threshold: -10.0,
+            ..Default::default()
+        });
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let now = Instant::now();
+        assert_eq!(reputation.next_expiry(), None);
+        reputation.report_at(second, Misbehaviour::DialFailure, now + Duration::from_secs(10));
+        reputation.report_at(first, Misbehaviour::DialFailure, now);
+        assert_eq!(reputation.next_expiry(), Some(now + Duration::from_secs(60 * 60)));
+        assert_eq!(reputation.expire_bans_at(now + Duration::from_secs(60 * 60)), vec![first]);
+        assert_eq!(reputation.next_expiry(), Some(now + Duration::from_secs(60 * 60 + 10)));
+        assert!(reputation.is_banned(&second));
+    }
+
+    #[test]
+    fn decayed_scores_are_pruned() {
+        let mut reputation = Reputation::new(ReputationConfig::default());
+        let now = Instant::now();
+        for _ in 0..MIN_PRUNE_AT - 1 {
+            reputation.report_at(PeerId::random(), Misbehaviour::DialFailure, now);
+        }
+        assert_eq!(reputation.tracked(), MIN_PRUNE_AT - 1);
+        // Long after, the reports above decayed to nothing and make room for the new one.
+        let later = now + Duration::from_secs(24 * 60 * 60);
+        let peer_id = PeerId::random();
+        reputation.report_at(peer_id, Misbehaviour::DialFailure, later);
+        assert_eq!(reputation.scores.keys().collect::<Vec<_>>(), vec![&peer_id]);
+        assert_eq!(reputation.prune_at, 2);
+    }
+
+    #[test]
+    fn our_own_closes_are_remembered_for_a_while() {
+        let mut reputation = Reputation::new(ReputationConfig::default());
+        let peer_id = PeerId::random();
+        let now = Instant::now();
+        assert!(!reputation.closed_by_us(&peer_id, now));
+        reputation.closing(peer_id, now);
+        assert!(reputation.closed_by_us(&peer_id, now + Duration::from_secs(1)));
+        assert!(!reputation.closed_by_us(&peer_id, now + CLOSE_GRACE));
+        reputation.connected(&peer_id);
+        assert!(!reputation.closed_by_us(&peer_id, now));
+    }
+}
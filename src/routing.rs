@@ -0,0 +1,139 @@
+// Read-only views over the Kademlia routing table.
+
+use chrono::{DateTime, Utc};
+use libp2p::Multiaddr;
+use libp2p_core::PeerId;
+use libp2p::swarm::SwarmEvent;
+use libp2p_kad::{
+    kbucket::{
+        self,
+        Distance,
+        NodeStatus
+    },
+    KademliaEvent
+};
+use crate::{LookupBehaviourEvent, LookupClient, LookupSwarmEvent};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConnectionStatus {
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RoutingEntry {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_util::peer_id"))]
+    pub peer_id: PeerId,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_util::multiaddrs"))]
+    pub addresses: Vec<Multiaddr>,
+    pub status: ConnectionStatus,
+    // Last time any swarm event involved the peer, `None` if it was never observed.
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BucketInfo {
+    // Bucket `i` holds the peers at a distance in `[2^i, 2^(i+1))` from the local key.
+    pub index: u32,
+    pub range: (Distance, Distance),
+    pub entries: Vec<RoutingEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BucketPosition {
+    pub index: u32,
+    pub distance: Distance,
+    pub range: Option<(Distance, Distance)>,
+    // Whether the peer is currently stored in that bucket, always false for plain keys.
+    pub present: bool,
+}
+
+impl LookupClient {
+    // Non-empty buckets ordered by index.
+    pub fn routing_table(&mut self) -> Vec<BucketInfo> {
+        let last_seen = &self.last_seen;
+        let mut buckets: Vec<BucketInfo> = self.swarm
+            .behaviour_mut()
+            .kademlia
+            .kbuckets()
+            .filter_map(|bucket| {
+                let range = bucket.range();
+                let index = range.0.ilog2()?;
+                let entries = bucket
+                    .iter()
+                    .map(|entry| {
+                        let peer_id = *entry.node.key.preimage();
+                        RoutingEntry {
+                            peer_id,
+                            addresses: entry.node.value.iter().cloned().collect(),
+                            status: match entry.status {
+                                NodeStatus::Connected => ConnectionStatus::Connected,
+                                NodeStatus::Disconnected => ConnectionStatus::Disconnected,
+                            },
+                            last_seen: last_seen.get(&peer_id).cloned(),
+                        }
+                    })
+                    .collect();
+                Some(BucketInfo { index, range, entries })
+            })
+            .collect();
+        buckets.sort_by_key(|bucket| bucket.index);
+        buckets
+    }
+    // Only the peers still connected or in the routing table are remembered as seen, and
+    // identify addresses are only tracked for peers in the routing table.
+    pub(crate) fn forget_departed_peer(&mut self, event: &LookupSwarmEvent) {
+        let peer_id = match event {
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => *peer_id,
+            SwarmEvent::Behaviour(LookupBehaviourEvent::Kademlia(
+                KademliaEvent::RoutingUpdated { old_peer: Some(old_peer), .. }
+            )) => *old_peer,
+            _ => return,
+        };
+        let routed = self.bucket_of(&peer_id).map_or(false, |position| position.present);
+        if !routed {
+            self.identified_addrs.remove(&peer_id);
+            if !self.is_connected(&peer_id) {
+                self.last_seen.remove(&peer_id);
+            }
+        }
+    }
+    // Addresses of a peer stored in the routing table, empty if it is not in there.
+    pub fn addresses_in_routing_table(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.swarm
+            .behaviour_mut()
+            .kademlia
+            .kbucket(*peer_id)
+            .and_then(|bucket| {
+                bucket
+                    .iter()
+                    .find(|entry| entry.node.key.preimage() == peer_id)
+                    .map(|entry| entry.node.value.iter().cloned().collect())
+            })
+            .unwrap_or_default()
+    }
+    // Bucket a peer falls into, `None` for the local peer.
+    pub fn bucket_of(&mut self, peer_id: &PeerId) -> Option<BucketPosition> {
+        let distance = kbucket::Key::from(self.local_peer_id).distance(&kbucket::Key::from(*peer_id));
+        let index = distance.ilog2()?;
+        let bucket = self.swarm.behaviour_mut().kademlia.kbucket(*peer_id);
+        let range = bucket.as_ref().map(|bucket| bucket.range());
+        let present = bucket.map_or(false, |bucket| {
+            bucket.iter().any(|entry| entry.node.key.preimage() == peer_id)
+        });
+        Some(BucketPosition { index, distance, range, present })
+    }
+    // Bucket an arbitrary key (a record key for instance) falls into.
+    pub fn bucket_of_key(&mut self, key: &[u8]) -> Option<BucketPosition> {
+        let distance = kbucket::Key::from(self.local_peer_id).distance(&kbucket::Key::from(key.to_vec()));
+        let index = distance.ilog2()?;
+        let range = self.swarm
+            .behaviour_mut()
+            .kademlia
+            .kbucket(key.to_vec())
+            .map(|bucket| bucket.range());
+        Some(BucketPosition { index, distance, range, present: false })
+    }
+}
@@ -0,0 +1,117 @@
+// Serde helpers encoding libp2p identifiers through their canonical string forms.
+
+pub(crate) mod peer_id {
+    use libp2p_core::PeerId;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::str::FromStr;
+
+    pub fn serialize<S: Serializer>(peer_id: &PeerId, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&peer_id.to_base58())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PeerId, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        PeerId::from_str(&encoded).map_err(D::Error::custom)
+    }
+}
+
+pub(crate) mod multiaddr {
+    use libp2p_core::Multiaddr;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::str::FromStr;
+
+    pub fn serialize<S: Serializer>(address: &Multiaddr, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&address.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Multiaddr, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        Multiaddr::from_str(&encoded).map_err(D::Error::custom)
+    }
+}
+
+pub(crate) mod multiaddrs {
+    use libp2p_core::Multiaddr;
+    use serde::{de::Error, ser::SerializeSeq, Deserialize, Deserializer, Serializer};
+    use std::str::FromStr;
+
+    pub fn serialize<S: Serializer>(addresses: &[Multiaddr], serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(addresses.len()))?;
+        for address in addresses {
+            seq.serialize_element(&address.to_string())?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Multiaddr>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|encoded| Multiaddr::from_str(encoded).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use libp2p_core::PeerId;
+    use crate::{ConnectionStatus, Network, Peer, PeerRecord, RoutingEntry};
+
+    fn peer() -> Peer {
+        Peer {
+            peer_id: PeerId::random(),
+            protocol_version: "/ipfs/id/1.0.0".to_string(),
+            agent_version: "substrate-node/v2.0.0".to_string(),
+            listen_addrs: vec![
+                "/ip4/1.2.3.4/tcp/30333".parse().unwrap(),
+                "/dns/p2p.cc3-0.kusama.network/tcp/30100".parse().unwrap(),
+            ],
+            protocols: vec!["/ksmcc3/kad".to_string(), "/SYNACK/0.0.1".to_string()],
+            observed_addr: "/ip4/5.6.7.8/tcp/4001".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn peer_round_trip() {
+        let peer = peer();
+        let encoded = serde_json::to_value(&peer).unwrap();
+        assert_eq!(encoded["peer_id"], peer.peer_id.to_base58());
+        assert_eq!(encoded["listen_addrs"][1], "/dns/p2p.cc3-0.kusama.network/tcp/30100");
+        assert_eq!(encoded["observed_addr"], "/ip4/5.6.7.8/tcp/4001");
+        let decoded: Peer = serde_json::from_value(encoded).unwrap();
+        assert_eq!(decoded, peer);
+    }
+
+    #[test]
+    fn lookup_results_round_trip() {
+        let record = PeerRecord {
+            peer: peer(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+        };
+        let decoded: PeerRecord = serde_json::from_str(&serde_json::to_string(&record).unwrap()).unwrap();
+        assert_eq!(decoded, record);
+
+        let entry = RoutingEntry {
+            peer_id: PeerId::random(),
+            addresses: vec!["/ip4/1.2.3.4/tcp/30333".parse().unwrap()],
+            status: ConnectionStatus::Connected,
+            last_seen: None,
+        };
+        let decoded: RoutingEntry = serde_json::from_str(&serde_json::to_string(&entry).unwrap()).unwrap();
+        assert_eq!(decoded, entry);
+
+        let network: Network = serde_json::from_str(&serde_json::to_string(&Network::Kusama).unwrap()).unwrap();
+        assert_eq!(network, Network::Kusama);
+    }
+
+    #[test]
+    fn invalid_strings_are_rejected() {
+        let mut encoded = serde_json::to_value(peer()).unwrap();
+        encoded["peer_id"] = "not a peer id".into();
+        assert!(serde_json::from_value::<Peer>(encoded).is_err());
+        let mut encoded = serde_json::to_value(peer()).unwrap();
+        encoded["observed_addr"] = "tcp/4001".into();
+        assert!(serde_json::from_value::<Peer>(encoded).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+// Long-running responder answering the handshakes of any number of peers.
+
+use std::future::Future;
+use futures::channel::mpsc;
+use futures::{FutureExt, SinkExt};
+use libp2p_core::PeerId;
+use crate::{LivenessProof, LookupClient, NetworkError, SerdeProtocol};
+
+#[derive(Debug)]
+pub enum HandshakeEvent {
+    Completed {
+        peer_id: PeerId,
+        // Version the handshake was negotiated under.
+        protocol: Option<SerdeProtocol>,
+        // Latest proof of the peer, if this client ever requested a handshake from it.
+        proof: Option<LivenessProof>,
+    },
+    Failed(NetworkError),
+}
+
+// Events kept for a slow consumer, the server waits for it beyond that.
+const HANDSHAKE_EVENT_BUFFER: usize = 64;
+
+impl LookupClient {
+    // Events of the handshakes served from now on. A new call replaces the previous receiver.
+    pub fn handshake_events(&mut self) -> mpsc::Receiver<HandshakeEvent> {
+        let (sender, receiver) = mpsc::channel(HANDSHAKE_EVENT_BUFFER);
+        self.handshake_events = Some(sender);
+        receiver
+    }
+    // Answers handshakes until the receiver of `handshake_events` is dropped, or forever
+    // without one. Failures only end the session of the peer they concern, at most
+    // `max_handshake_sessions` peers are served at once and the SYNs of further peers are
+    // reported as refused.
+    pub async fn serve_handshakes(&mut self) {
+        self.serve_handshakes_until(futures::future::pending()).await
+    }
+    // Same as `serve_handshakes`, returns as well once `stop` completes. The handshakes in
+    // progress are left to `shutdown`.
+    pub async fn serve_handshakes_until<F: Future<Output = ()>>(&mut self, stop: F) {
+        let stop = stop.fuse();
+        futures::pin_mut!(stop);
+        loop {
+            let result = futures::select! {
+                result = self.init_protocol().fuse() => result,
+                () = stop => {
+                    println!("Stopping the handshake server.");
+                    break;
+                },
+            };
+            let event = match result {
+                Ok(peer_id) => HandshakeEvent::Completed {
+                    peer_id,
+                    protocol: self.negotiated_versions.get(&peer_id).cloned(),
+                    proof: self.liveness_proofs.get(&peer_id).cloned(),
+                },
+                Err(error) => {
+                    println!("Handshake failed : {}", error);
+                    HandshakeEvent::Failed(error)
+                },
+            };
+            if let Some(events) = &mut self.handshake_events {
+                if events.send(event).await.is_err() {
+                    println!("Handshake events dropped, stopping the handshake server.");
+                    self.handshake_events = None;
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,84 @@
+// Graceful shutdown of a LookupClient.
+
+use std::time::{Duration, Instant};
+#[cfg(feature = "test-protocol")]
+use libp2p::request_response::RequestId;
+use libp2p::swarm::SwarmEvent;
+use libp2p_core::PeerId;
+use libp2p_kad::QueryId;
+use crate::LookupClient;
+#[cfg(feature = "test-protocol")]
+use crate::NetworkError;
+
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    // Queries still running after the grace period, finished with the peers found so far.
+    pub cancelled_queries: Vec<QueryId>,
+    // Requests left without a response, each with the error it ended with.
+    #[cfg(feature = "test-protocol")]
+    pub failed_requests: Vec<(RequestId, NetworkError)>,
+    // Connections closed while shutting down, several per peer possibly.
+    pub closed_connections: usize,
+}
+
+impl LookupClient {
+    // Stops listening, gives in-flight queries and requests `grace` to complete, then
+    // cancels what is left and closes every connection. The client holds no state on disk,
+    // the crawler writes its own checkpoints.
+    pub async fn shutdown(&mut self, grace: Duration) -> ShutdownReport {
+        let mut report = ShutdownReport::default();
+        for listener_id in self.listeners.drain() {
+            self.swarm.remove_listener(listener_id);
+        }
+        let deadline = Instant::now() + grace;
+        while !self.is_idle() && self.drive_until(deadline, &mut report).await {}
+
+        for mut query in self.swarm.behaviour_mut().kademlia.iter_queries_mut() {
+            report.cancelled_queries.push(query.id());
+            query.finish();
+        }
+        if !report.cancelled_queries.is_empty() {
+            println!("Cancelled {} queries.", report.cancelled_queries.len());
+        }
+
+        let peers: Vec<PeerId> = self.swarm.connected_peers().cloned().collect();
+        for peer_id in peers {
+            self.disconnect(peer_id);
+        }
+        // Lets the muxers close their streams and the cancelled queries report.
+        let deadline = Instant::now() + grace;
+        while self.swarm.connected_peers().next().is_some() && self.drive_until(deadline, &mut report).await {}
+
+        #[cfg(feature = "test-protocol")]
+        for (request_id, peer_id) in self.pending_requests.drain() {
+            report.failed_requests.push((request_id, NetworkError::Shutdown(peer_id)));
+        }
+        report
+    }
+    fn is_idle(&mut self) -> bool {
+        #[cfg(feature = "test-protocol")]
+        if !self.pending_requests.is_empty() {
+            return false;
+        }
+        self.swarm.behaviour_mut().kademlia.iter_queries().next().is_none()
+    }
+    // Handles one event, false once the deadline passed.
+    async fn drive_until(&mut self, deadline: Instant, report: &mut ShutdownReport) -> bool {
+        let remaining = match deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) => remaining,
+            None => return false,
+        };
+        match async_std::future::timeout(remaining, self.next_event()).await {
+            Ok(SwarmEvent::ListenerClosed { addresses, .. }) => {
+                println!("Stopped listening on {:?}", addresses);
+                true
+            },
+            Ok(SwarmEvent::ConnectionClosed { .. }) => {
+                report.closed_connections += 1;
+                true
+            },
+            Ok(_) => true,
+            Err(_) => false,
+        }
+    }
+}
@@ -0,0 +1,878 @@
+// Chunked file transfer over request-response. The sender offers a file, the receiver answers
+// with how much of it it already holds and the sender pushes the rest one chunk at a time.
+// Files are hashed, read and written on blocking tasks.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use futures::channel::mpsc;
+use libp2p::request_response::{
+    ProtocolSupport,
+    RequestId,
+    RequestResponse,
+    RequestResponseConfig,
+    RequestResponseEvent,
+    RequestResponseMessage,
+    ResponseChannel
+};
+use libp2p_core::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use crate::{Direction, LookupClient, SerdeCodec, SerdeProtocol, SizeLimits};
+
+pub const FILE_TRANSFER_PROTOCOL: &str = "/lookup/file-transfer/0.1.0";
+// Files still being received are kept next to their final name with this extension.
+const PARTIAL_EXTENSION: &str = "part";
+// Room left in a request for everything but the chunk data.
+const ENVELOPE_SIZE: usize = 1024;
+// Files a peer may send us at once, counting the offers being answered.
+const MAX_INCOMING_PER_PEER: usize = 4;
+// File jobs running at once for the transfers with a peer, the others wait their turn.
+const MAX_JOBS_PER_PEER: usize = 2;
+
+pub type Sha256Digest = [u8; 32];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferRequest {
+    Offer {
+        name: String,
+        size: u64,
+        sha256: Sha256Digest,
+    },
+    Chunk {
+        name: String,
+        offset: u64,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+        sha256: Sha256Digest,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferResponse {
+    // Bytes of the file the receiver already holds, with their hash, and the largest chunk
+    // the receiver reads.
+    Accepted {
+        offset: u64,
+        prefix_sha256: Sha256Digest,
+        max_chunk_size: u64,
+    },
+    Received {
+        offset: u64,
+    },
+    Completed,
+    Refused(String),
+}
+
+impl TransferRequest {
+    pub fn name(&self) -> &str {
+        match self {
+            TransferRequest::Offer { name, .. } | TransferRequest::Chunk { name, .. } => name,
+        }
+    }
+}
+
+pub type TransferCodec = SerdeCodec<TransferRequest, TransferResponse>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferEvent {
+    Progress {
+        peer_id: PeerId,
+        name: String,
+        direction: Direction,
+        transferred: u64,
+        size: u64,
+    },
+    Completed {
+        peer_id: PeerId,
+        name: String,
+        direction: Direction,
+        sha256: Sha256Digest,
+    },
+    Failed {
+        peer_id: PeerId,
+        name: String,
+        direction: Direction,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum TransferError {
+    #[error("File transfer I/O error : {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid file name {0:?}")]
+    InvalidName(String),
+    #[error("{0} is already being sent to this peer")]
+    InProgress(String),
+}
+
+pub(crate) fn behaviour(chunk_size: usize) -> RequestResponse<TransferCodec> {
+    TransferCodec::cbor(SizeLimits::new(chunk_size + ENVELOPE_SIZE, ENVELOPE_SIZE)).behaviour(
+        SerdeProtocol::new(FILE_TRANSFER_PROTOCOL),
+        ProtocolSupport::Full,
+        RequestResponseConfig::default()
+    )
+}
+
+// Hasher fed with the first `len` bytes of a file, the rest can be added to it as it comes.
+fn hash_prefix(path: &Path, len: u64) -> io::Result<Sha256> {
+    let mut hasher = Sha256::new();
+    if len > 0 {
+        let copied = io::copy(&mut File::open(path)?.take(len), &mut hasher)?;
+        if copied < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    Ok(hasher)
+}
+
+// Names come from the remote, they must not reach outside of the served directory.
+fn checked_name(name: &str) -> Result<&str, String> {
+    let valid = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', '\0']);
+    if valid {
+        Ok(name)
+    } else {
+        Err(format!("invalid file name {:?}", name))
+    }
+}
+
+// Each sender has its own partial file, they may send files of the same name.
+fn partial_path(directory: &Path, peer_id: &PeerId, name: &str) -> PathBuf {
+    directory.join(format!("{}.{}.{}", name, peer_id, PARTIAL_EXTENSION))
+}
+
+type TransferKey = (PeerId, String);
+
+#[derive(Debug)]
+struct Outgoing {
+    path: PathBuf,
+    size: u64,
+    // Known once the file is hashed, before it is offered.
+    sha256: Option<Sha256Digest>,
+    sent: u64,
+    // The smaller of ours and the one of the receiver.
+    chunk_size: usize,
+}
+
+#[derive(Debug)]
+struct Incoming {
+    size: u64,
+    sha256: Sha256Digest,
+    received: u64,
+    // Fed with every byte received so far, taken by the job writing the latest chunk.
+    hasher: Option<Sha256>,
+}
+
+#[derive(Debug)]
+enum Purpose {
+    // The whole file, to offer it.
+    Offer { size: u64 },
+    // The prefix the receiver holds, to resume from it when it matches.
+    Resume { offset: u64, prefix_sha256: Sha256Digest },
+    // The partial file, to answer an offer.
+    Accept { offset: u64, size: u64, sha256: Sha256Digest },
+    // The chunk starting at `offset`, to send it.
+    Send { offset: u64, len: usize },
+    // A received chunk, checked against its hash and written at `offset` of the partial
+    // file. The hasher of the partial file is fed with it.
+    Store { offset: u64, data: Vec<u8>, sha256: Sha256Digest, hasher: Sha256 },
+}
+
+// File I/O and hashing of a transfer, run on a blocking task.
+#[derive(Debug)]
+pub(crate) struct FileJob {
+    key: TransferKey,
+    path: PathBuf,
+    purpose: Purpose,
+}
+
+impl FileJob {
+    fn run(self) -> JobDone {
+        let FileJob { key, path, purpose } = self;
+        let done = match purpose {
+            Purpose::Offer { size } => Done::Offered {
+                size,
+                result: hash_prefix(&path, size).map(|hasher| hasher.finalize().into()),
+            },
+            Purpose::Resume { offset, prefix_sha256 } => Done::Resumed {
+                offset,
+                matches: hash_prefix(&path, offset).map_or(false, |hasher| Sha256Digest::from(hasher.finalize()) == prefix_sha256),
+            },
+            Purpose::Accept { offset, size, sha256 } => Done::Accepted { offset, size, sha256, result: hash_prefix(&path, offset) },
+            Purpose::Send { offset, len } => Done::Read { offset, result: read_chunk(&path, offset, len) },
+            Purpose::Store { offset, data, sha256, hasher } => Done::Stored {
+                end: offset + data.len() as u64,
+                result: store_chunk(&path, offset, &data, sha256, hasher),
+            },
+        };
+        JobDone { key, done }
+    }
+}
+
+fn read_chunk(path: &Path, offset: u64, len: usize) -> io::Result<(Vec<u8>, Sha256Digest)> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::with_capacity(len);
+    file.take(len as u64).read_to_end(&mut data)?;
+    let sha256 = Sha256::digest(&data).into();
+    Ok((data, sha256))
+}
+
+// Nothing is written when the chunk does not match its hash.
+fn store_chunk(path: &Path, offset: u64, data: &[u8], sha256: Sha256Digest, mut hasher: Sha256) -> Result<Sha256, String> {
+    if Sha256Digest::from(Sha256::digest(data)) != sha256 {
+        return Err(format!("hash mismatch in the chunk at {}", offset));
+    }
+    let write = || -> io::Result<()> {
+        let mut file = OpenOptions::new().write(true).create(true).open(path)?;
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    };
+    write().map_err(|e| e.to_string())?;
+    hasher.update(data);
+    Ok(hasher)
+}
+
+#[derive(Debug)]
+enum Done {
+    Offered { size: u64, result: io::Result<Sha256Digest> },
+    Resumed { offset: u64, matches: bool },
+    Accepted { offset: u64, size: u64, sha256: Sha256Digest, result: io::Result<Sha256> },
+    Read { offset: u64, result: io::Result<(Vec<u8>, Sha256Digest)> },
+    Stored { end: u64, result: Result<Sha256, String> },
+}
+
+#[derive(Debug)]
+pub(crate) struct JobDone {
+    key: TransferKey,
+    done: Done,
+}
+
+// What a transfer sends once a job is done.
+#[derive(Debug)]
+enum Next {
+    Request(TransferRequest),
+    Response(TransferResponse),
+    Nothing,
+}
+
+#[derive(Debug)]
+pub(crate) struct Transfers {
+    chunk_size: usize,
+    // Whether received files may replace the files already in the directory.
+    overwrite: bool,
+    // Where offered files are written, none refuses every offer.
+    directory: Option<PathBuf>,
+    outgoing: HashMap<TransferKey, Outgoing>,
+    incoming: HashMap<TransferKey, Incoming>,
+    requests: HashMap<RequestId, TransferKey>,
+    // Requests answered once their file job is done.
+    accepting: HashMap<TransferKey, ResponseChannel<TransferResponse>>,
+    // Jobs waiting for a blocking task, and the number running for each peer.
+    jobs: VecDeque<FileJob>,
+    running: HashMap<PeerId, usize>,
+    sender: mpsc::UnboundedSender<JobDone>,
+    pub(crate) done: mpsc::UnboundedReceiver<JobDone>,
+    events: Vec<TransferEvent>,
+}
+
+impl Transfers {
+    pub fn new(chunk_size: usize, overwrite: bool) -> Self {
+        let (sender, done) = mpsc::unbounded();
+        Transfers {
+            chunk_size,
+            overwrite,
+            directory: None,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            requests: HashMap::new(),
+            accepting: HashMap::new(),
+            jobs: VecDeque::new(),
+            running: HashMap::new(),
+            sender,
+            done,
+            events: Vec::new(),
+        }
+    }
+    // Reads, writes and hashes take long, the results come back through `done`. The jobs of
+    // a peer above `MAX_JOBS_PER_PEER` wait for one of its jobs to finish.
+    fn spawn_jobs(&mut self) {
+        let mut waiting = VecDeque::new();
+        while let Some(job) = self.jobs.pop_front() {
+            let running = self.running.entry(job.key.0).or_insert(0);
+            if *running >= MAX_JOBS_PER_PEER {
+                waiting.push_back(job);
+                continue;
+            }
+            *running += 1;
+            let sender = self.sender.clone();
+            async_std::task::spawn_blocking(move || {
+                let _ = sender.unbounded_send(job.run());
+            });
+        }
+        self.jobs = waiting;
+    }
+    fn push_job(&mut self, key: TransferKey, path: PathBuf, purpose: Purpose) {
+        self.jobs.push_back(FileJob { key, path, purpose });
+    }
+    // The offer is sent once the file is hashed.
+    fn offer(&mut self, peer_id: PeerId, path: &Path) -> Result<(), TransferError> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| TransferError::InvalidName(path.display().to_string()))?
+            .to_string();
+        let key = (peer_id, name.clone());
+        if self.outgoing.contains_key(&key) {
+            return Err(TransferError::InProgress(name));
+        }
+        let size = fs::metadata(path)?.len();
+        let outgoing = Outgoing { path: path.to_path_buf(), size, sha256: None, sent: 0, chunk_size: self.chunk_size };
+        self.outgoing.insert(key.clone(), outgoing);
+        self.push_job(key, path.to_path_buf(), Purpose::Offer { size });
+        Ok(())
+    }
+    // The chunk is sent once it is read.
+    fn read_next_chunk(&mut self, key: &TransferKey) -> Result<(), String> {
+        let outgoing = self.outgoing.get(key).ok_or("transfer dropped")?;
+        let (path, offset, len) = (outgoing.path.clone(), outgoing.sent, outgoing.chunk_size);
+        self.push_job(key.clone(), path, Purpose::Send { offset, len });
+        Ok(())
+    }
+    // The next request to send, none once the transfer is over or waits for a job.
+    fn on_response(&mut self, key: &TransferKey, response: TransferResponse) -> Result<Option<TransferRequest>, String> {
+        let outgoing = self.outgoing.get_mut(key).ok_or("transfer dropped")?;
+        match response {
+            TransferResponse::Accepted { offset, prefix_sha256, max_chunk_size } => {
+                if max_chunk_size == 0 {
+                    return Err("the receiver accepts no data".to_string());
+                }
+                outgoing.chunk_size = outgoing.chunk_size.min(max_chunk_size.try_into().unwrap_or(usize::MAX));
+                outgoing.sent = 0;
+                if offset > 0 && offset <= outgoing.size {
+                    let path = outgoing.path.clone();
+                    self.push_job(key.clone(), path, Purpose::Resume { offset, prefix_sha256 });
+                    return Ok(None);
+                }
+            },
+            TransferResponse::Received { offset } => {
+                if offset <= outgoing.sent || offset >= outgoing.size {
+                    return Err(format!("unexpected offset {}", offset));
+                }
+                outgoing.sent = offset;
+                let (transferred, size) = (outgoing.sent, outgoing.size);
+                self.events.push(TransferEvent::Progress {
+                    peer_id: key.0,
+                    name: key.1.clone(),
+                    direction: Direction::Outbound,
+                    transferred,
+                    size,
+                });
+            },
+            TransferResponse::Completed => {
+                let sha256 = outgoing.sha256.unwrap_or_default();
+                self.outgoing.remove(key);
+                println!("Sent {} to {:?}.", key.1, key.0);
+                self.events.push(TransferEvent::Completed { peer_id: key.0, name: key.1.clone(), direction: Direction::Outbound, sha256 });
+                return Ok(None);
+            },
+            TransferResponse::Refused(reason) => return Err(reason),
+        }
+        self.read_next_chunk(key).map(|()| None)
+    }
+    fn fail_outgoing(&mut self, key: TransferKey, reason: String) {
+        println!("Sending {} to {:?} failed : {}", key.1, key.0, reason);
+        self.outgoing.remove(&key);
+        self.events.push(TransferEvent::Failed { peer_id: key.0, name: key.1, direction: Direction::Outbound, reason });
+    }
+    // Carries on with the transfer the job was for.
+    fn on_done(&mut self, job_done: JobDone) -> (TransferKey, Next) {
+        let JobDone { key, done } = job_done;
+        if let Some(running) = self.running.get_mut(&key.0) {
+            *running -= 1;
+            if *running == 0 {
+                self.running.remove(&key.0);
+            }
+        }
+        let next = match done {
+            Done::Offered { size, result } => {
+                let sha256 = match result {
+                    Ok(sha256) => sha256,
+                    Err(e) => {
+                        if self.outgoing.contains_key(&key) {
+                            self.fail_outgoing(key.clone(), e.to_string());
+                        }
+                        return (key, Next::Nothing);
+                    },
+                };
+                match self.outgoing.get_mut(&key) {
+                    Some(outgoing) => {
+                        outgoing.sha256 = Some(sha256);
+                        Next::Request(TransferRequest::Offer { name: key.1.clone(), size, sha256 })
+                    },
+                    None => Next::Nothing,
+                }
+            },
+            Done::Resumed { offset, matches } => {
+                let outgoing = match self.outgoing.get_mut(&key) {
+                    Some(outgoing) => outgoing,
+                    None => return (key, Next::Nothing),
+                };
+                // A partial copy that differs from our file is overwritten.
+                if matches {
+                    outgoing.sent = offset;
+                    println!("Resuming {} at {} of {} bytes.", key.1, outgoing.sent, outgoing.size);
+                }
+                if let Err(reason) = self.read_next_chunk(&key) {
+                    self.fail_outgoing(key.clone(), reason);
+                }
+                Next::Nothing
+            },
+            Done::Read { offset, result } => {
+                // Chunks read for a transfer dropped or restarted since are not sent.
+                if self.outgoing.get(&key).map_or(true, |outgoing| outgoing.sent != offset) {
+                    return (key, Next::Nothing);
+                }
+                match result {
+                    Ok((data, sha256)) => Next::Request(TransferRequest::Chunk { name: key.1.clone(), offset, data, sha256 }),
+                    Err(e) => {
+                        self.fail_outgoing(key.clone(), e.to_string());
+                        Next::Nothing
+                    },
+                }
+            },
+            Done::Accepted { offset, size, sha256, result } => match result {
+                Ok(hasher) => {
+                    let prefix_sha256 = hasher.clone().finalize().into();
+                    self.incoming.insert(key.clone(), Incoming { size, sha256, received: offset, hasher: Some(hasher) });
+                    Next::Response(TransferResponse::Accepted { offset, prefix_sha256, max_chunk_size: self.chunk_size as u64 })
+                },
+                Err(e) => Next::Response(self.fail_incoming(key.0, key.1.clone(), e.to_string())),
+            },
+            Done::Stored { end, result } => {
+                // The request still waits for an answer.
+                if !self.incoming.contains_key(&key) {
+                    return (key, Next::Response(TransferResponse::Refused("transfer dropped".to_string())));
+                }
+                let response = result
+                    .and_then(|hasher| self.on_stored(&key, end, hasher))
+                    .unwrap_or_else(|reason| self.fail_incoming(key.0, key.1.clone(), reason));
+                Next::Response(response)
+            },
+        };
+        (key, next)
+    }
+    // None when the response waits for a file job.
+    fn on_request(&mut self, peer_id: PeerId, request: TransferRequest) -> Option<TransferResponse> {
+        let name = request.name().to_string();
+        let directory = match &self.directory {
+            Some(directory) => directory.clone(),
+            None => return Some(TransferResponse::Refused("not serving files".to_string())),
+        };
+        let result = checked_name(&name).and_then(|name| match request {
+            TransferRequest::Offer { size, sha256, .. } => self.on_offer(peer_id, name, &directory, size, sha256),
+            TransferRequest::Chunk { offset, data, sha256, .. } => self.on_chunk(peer_id, name, &directory, offset, data, sha256),
+        });
+        result.unwrap_or_else(|reason| Some(self.fail_incoming(peer_id, name, reason)))
+    }
+    fn fail_incoming(&mut self, peer_id: PeerId, name: String, reason: String) -> TransferResponse {
+        println!("Receiving {} from {:?} failed : {}", name, peer_id, reason);
+        self.incoming.remove(&(peer_id, name.clone()));
+        self.events.push(TransferEvent::Failed { peer_id, name, direction: Direction::Inbound, reason: reason.clone() });
+        TransferResponse::Refused(reason)
+    }
+    // Transfers from a peer that disconnected are resumed by a new offer.
+    pub fn forget_peer(&mut self, peer_id: PeerId) {
+        let names: Vec<String> = self.incoming.keys().filter(|key| key.0 == peer_id).map(|key| key.1.clone()).collect();
+        for name in names {
+            self.incoming.remove(&(peer_id, name.clone()));
+            let reason = "connection closed".to_string();
+            self.events.push(TransferEvent::Failed { peer_id, name, direction: Direction::Inbound, reason });
+        }
+        self.accepting.retain(|key, _| key.0 != peer_id);
+    }
+    fn check_target(&self, directory: &Path, name: &str) -> Result<(), String> {
+        if !self.overwrite && directory.join(name).exists() {
+            return Err(format!("{} already exists", name));
+        }
+        Ok(())
+    }
+    fn on_offer(&mut self, peer_id: PeerId, name: &str, directory: &Path, size: u64, sha256: Sha256Digest) -> Result<Option<TransferResponse>, String> {
+        self.check_target(directory, name)?;
+        let key = (peer_id, name.to_string());
+        // An offer of a file already coming in restarts it and takes no more room.
+        let others = self.incoming
+            .keys()
+            .chain(self.accepting.keys())
+            .filter(|other| other.0 == peer_id && **other != key)
+            .collect::<HashSet<_>>()
+            .len();
+        if others >= MAX_INCOMING_PER_PEER {
+            return Err(format!("already receiving {} files from this peer", others));
+        }
+        self.incoming.remove(&key);
+        let partial = partial_path(directory, &peer_id, name);
+        let held = fs::metadata(&partial).map_or(0, |metadata| metadata.len());
+        // A complete partial file failed its hash check, it is received again.
+        let offset = if held < size { held } else { 0 };
+        self.push_job(key, partial, Purpose::Accept { offset, size, sha256 });
+        Ok(None)
+    }
+    // The chunk is answered once it is written.
+    fn on_chunk(
+        &mut self,
+        peer_id: PeerId,
+        name: &str,
+        directory: &Path,
+        offset: u64,
+        data: Vec<u8>,
+        sha256: Sha256Digest
+    ) -> Result<Option<TransferResponse>, String> {
+        let key = (peer_id, name.to_string());
+        let incoming = self.incoming.get_mut(&key).ok_or_else(|| format!("no offer for {}", name))?;
+        let end = offset + data.len() as u64;
+        // The sender starts over when our partial file differs from its file.
+        if (offset != incoming.received && offset != 0) || end > incoming.size {
+            return Err(format!("chunk {}..{} out of order", offset, end));
+        }
+        let hasher = incoming.hasher.take().ok_or_else(|| format!("chunk at {} sent before the previous one was written", offset))?;
+        let hasher = if offset == 0 { Sha256::new() } else { hasher };
+        self.push_job(key, partial_path(directory, &peer_id, name), Purpose::Store { offset, data, sha256, hasher });
+        Ok(None)
+    }
+    fn on_stored(&mut self, key: &TransferKey, end: u64, hasher: Sha256) -> Result<TransferResponse, String> {
+        let (peer_id, name) = (key.0, key.1.as_str());
+        let directory = self.directory.clone().ok_or("not serving files")?;
+        let partial = &partial_path(&directory, &peer_id, name);
+        let incoming = self.incoming.get_mut(key).expect("Transfer checked by the caller.");
+        incoming.received = end;
+        let size = incoming.size;
+        if end < size {
+            incoming.hasher = Some(hasher);
+            self.events.push(TransferEvent::Progress { peer_id, name: key.1.clone(), direction: Direction::Inbound, transferred: end, size });
+            return Ok(TransferResponse::Received { offset: end });
+        }
+        let incoming = self.incoming.remove(key).expect("Transfer checked above.");
+        if Sha256Digest::from(hasher.finalize()) != incoming.sha256 {
+            let _ = fs::remove_file(partial);
+            return Err("hash mismatch in the complete file".to_string());
+        }
+        // The file may have appeared since the offer.
+        if let Err(reason) = self.check_target(&directory, name) {
+            let _ = fs::remove_file(partial);
+            return Err(reason);
+        }
+        fs::rename(partial, directory.join(name)).map_err(|e| e.to_string())?;
+        println!("Received {} from {:?}, {} bytes.", name, peer_id, size);
+        self.events.push(TransferEvent::Completed { peer_id, name: key.1.clone(), direction: Direction::Inbound, sha256: incoming.sha256 });
+        Ok(TransferResponse::Completed)
+    }
+}
+
+impl LookupClient {
+    // Offered files are written into `directory`, existing files are only replaced when
+    // `LookupConfig::transfer_overwrite` is set. Partial ones keep the sender PeerId and a
+    // `.part` extension until their hash is verified, so that an interrupted transfer resumes
+    // where it stopped.
+    pub fn serve_directory<P: Into<PathBuf>>(&mut self, directory: P) -> Result<(), TransferError> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        self.transfers.directory = Some(directory);
+        Ok(())
+    }
+    // Pushes a file to a peer serving a directory, progress is reported by `transfer_events`.
+    // Sending the same file again after a failure resumes it.
+    pub fn send_file<P: AsRef<Path>>(&mut self, peer_id: PeerId, path: P) -> Result<(), TransferError> {
+        self.transfers.offer(peer_id, path.as_ref())?;
+        self.transfers.spawn_jobs();
+        Ok(())
+    }
+    // Transfer events since the last call.
+    pub fn transfer_events(&mut self) -> Vec<TransferEvent> {
+        std::mem::take(&mut self.transfers.events)
+    }
+    fn send_transfer_request(&mut self, key: TransferKey, request: TransferRequest) {
+        let request_id = self.swarm.behaviour_mut().file_transfer.send_request(&key.0, request);
+        self.transfers.requests.insert(request_id, key);
+    }
+    fn send_transfer_response(&mut self, key: &TransferKey, channel: ResponseChannel<TransferResponse>, response: TransferResponse) {
+        if self.swarm.behaviour_mut().file_transfer.send_response(channel, response).is_err() {
+            println!("File transfer response to {:?} dropped, the connection closed.", key.0);
+            self.transfers.incoming.remove(key);
+        }
+    }
+    pub(crate) fn on_transfer_job_done(&mut self, done: JobDone) {
+        match self.transfers.on_done(done) {
+            (key, Next::Request(request)) => self.send_transfer_request(key, request),
+            (key, Next::Response(response)) => match self.transfers.accepting.remove(&key) {
+                Some(channel) => self.send_transfer_response(&key, channel, response),
+                // The peer disconnected or offered the file again meanwhile.
+                None => {
+                    self.transfers.incoming.remove(&key);
+                },
+            },
+            (_, Next::Nothing) => {},
+        }
+        self.transfers.spawn_jobs();
+    }
+    pub(crate) fn on_transfer_event(&mut self, event: RequestResponseEvent<TransferRequest, TransferResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message: RequestResponseMessage::Request { request, channel, .. } } => {
+                let key = (peer, request.name().to_string());
+                match self.transfers.on_request(peer, request) {
+                    Some(response) => self.send_transfer_response(&key, channel, response),
+                    None => {
+                        self.transfers.accepting.insert(key, channel);
+                    },
+                }
+            },
+            RequestResponseEvent::Message { message: RequestResponseMessage::Response { request_id, response }, .. } => {
+                if let Some(key) = self.transfers.requests.remove(&request_id) {
+                    match self.transfers.on_response(&key, response) {
+                        Ok(Some(request)) => self.send_transfer_request(key, request),
+                        Ok(None) => {},
+                        Err(reason) => self.transfers.fail_outgoing(key, reason),
+                    }
+                }
+            },
+            RequestResponseEvent::OutboundFailure { request_id, error, .. } => {
+                if let Some(key) = self.transfers.requests.remove(&request_id) {
+                    self.transfers.fail_outgoing(key, error.to_string());
+                }
+            },
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                println!("File transfer request from {:?} failed : {}", peer, error);
+            },
+            RequestResponseEvent::ResponseSent { .. } => {},
+        }
+        self.transfers.spawn_jobs();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(label: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lookup-transfer-{}-{:016x}", label, rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Runs the pending jobs in place, and the ones they lead to, returns what the transfer
+    // sends next.
+    fn run_jobs(transfers: &mut Transfers) -> Option<Next> {
+        let mut next = None;
+        while !transfers.jobs.is_empty() {
+            let jobs: Vec<FileJob> = transfers.jobs.drain(..).collect();
+            for job in jobs {
+                next = Some(transfers.on_done(job.run()).1);
+            }
+        }
+        next
+    }
+
+    fn offer(sender: &mut Transfers, receiver_id: PeerId, path: &Path) -> (TransferKey, TransferRequest) {
+        sender.offer(receiver_id, path).unwrap();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        match run_jobs(sender) {
+            Some(Next::Request(offer)) => ((receiver_id, name), offer),
+            other => panic!("Unexpected step : {:?}", other),
+        }
+    }
+
+    fn receive(receiver: &mut Transfers, sender_id: PeerId, request: TransferRequest) -> TransferResponse {
+        receiver.on_request(sender_id, request).unwrap_or_else(|| match run_jobs(receiver) {
+            Some(Next::Response(response)) => response,
+            other => panic!("Unexpected step : {:?}", other),
+        })
+    }
+
+    fn respond(sender: &mut Transfers, key: &TransferKey, response: TransferResponse) -> Option<TransferRequest> {
+        sender.on_response(key, response).unwrap().or_else(|| match run_jobs(sender) {
+            Some(Next::Request(request)) => Some(request),
+            None => None,
+            other => panic!("Unexpected step : {:?}", other),
+        })
+    }
+
+    // Relays requests and responses until the sender is done or `max_requests` went through.
+    fn run(sender: &mut Transfers, receiver: &mut Transfers, sender_id: PeerId, key: &TransferKey, mut request: TransferRequest, max_requests: usize) {
+        for _ in 0..max_requests {
+            let response = receive(receiver, sender_id, request);
+            match respond(sender, key, response) {
+                Some(next) => request = next,
+                None => return,
+            }
+        }
+    }
+
+    fn setup(content: &[u8]) -> (Transfers, Transfers, PathBuf, PathBuf) {
+        let source = temp_dir("source").join("census.json");
+        fs::write(&source, content).unwrap();
+        let served = temp_dir("served");
+        let sender = Transfers::new(4, false);
+        let mut receiver = Transfers::new(4, false);
+        receiver.directory = Some(served.clone());
+        (sender, receiver, source, served)
+    }
+
+    #[test]
+    fn files_are_sent_in_verified_chunks() {
+        let content = b"0123456789";
+        let (mut sender, mut receiver, source, served) = setup(content);
+        let sender_id = PeerId::random();
+        let (key, offer) = offer(&mut sender, PeerId::random(), &source);
+        run(&mut sender, &mut receiver, sender_id, &key, offer, 10);
+        assert_eq!(fs::read(served.join("census.json")).unwrap(), content);
+        assert!(!partial_path(&served, &sender_id, "census.json").exists());
+        let progress: Vec<u64> = sender.events.iter().filter_map(|event| match event {
+            TransferEvent::Progress { transferred, .. } => Some(*transferred),
+            _ => None,
+        }).collect();
+        assert_eq!(progress, vec![4, 8]);
+        let sha256: Sha256Digest = Sha256::digest(content).into();
+        assert!(matches!(sender.events.last(), Some(TransferEvent::Completed { sha256: s, direction: Direction::Outbound, .. }) if *s == sha256));
+        assert!(matches!(receiver.events.last(), Some(TransferEvent::Completed { direction: Direction::Inbound, .. })));
+        assert!(sender.outgoing.is_empty() && receiver.incoming.is_empty());
+    }
+
+    #[test]
+    fn chunks_fit_the_receiver() {
+        let content = b"0123456789";
+        let (_, mut receiver, source, served) = setup(content);
+        let mut sender = Transfers::new(8, false);
+        let sender_id = PeerId::random();
+        let (key, offer) = offer(&mut sender, PeerId::random(), &source);
+        run(&mut sender, &mut receiver, sender_id, &key, offer, 10);
+        assert_eq!(fs::read(served.join("census.json")).unwrap(), content);
+        let progress: Vec<u64> = receiver.events.iter().filter_map(|event| match event {
+            TransferEvent::Progress { transferred, .. } => Some(*transferred),
+            _ => None,
+        }).collect();
+        assert_eq!(progress, vec![4, 8]);
+    }
+
+    #[test]
+    fn interrupted_transfers_resume() {
+        let content = b"0123456789";
+        let (mut sender, mut receiver, source, served) = setup(content);
+        let sender_id = PeerId::random();
+        let (key, offer) = offer(&mut sender, PeerId::random(), &source);
+        // The offer and the first chunk go through, then the connection drops.
+        run(&mut sender, &mut receiver, sender_id, &key, offer, 2);
+        sender.fail_outgoing(key.clone(), "connection closed".to_string());
+        receiver.forget_peer(sender_id);
+        assert!(receiver.incoming.is_empty());
+        assert!(matches!(receiver.events.last(), Some(TransferEvent::Failed { direction: Direction::Inbound, .. })));
+        assert_eq!(fs::read(partial_path(&served, &sender_id, "census.json")).unwrap(), b"0123");
+
+        let (key, offer) = self::offer(&mut sender, key.0, &source);
+        let accepted = receive(&mut receiver, sender_id, offer);
+        let prefix_sha256 = Sha256::digest(b"0123").into();
+        assert_eq!(accepted, TransferResponse::Accepted { offset: 4, prefix_sha256, max_chunk_size: 4 });
+        let chunk = respond(&mut sender, &key, accepted).unwrap();
+        assert!(matches!(&chunk, TransferRequest::Chunk { offset: 4, .. }));
+        run(&mut sender, &mut receiver, sender_id, &key, chunk, 10);
+        assert_eq!(fs::read(served.join("census.json")).unwrap(), content);
+    }
+
+    #[test]
+    fn diverging_partial_files_are_overwritten() {
+        let content = b"0123456789";
+        let (mut sender, mut receiver, source, served) = setup(content);
+        let sender_id = PeerId::random();
+        fs::write(partial_path(&served, &sender_id, "census.json"), b"abcdef").unwrap();
+        let (key, offer) = offer(&mut sender, PeerId::random(), &source);
+        run(&mut sender, &mut receiver, sender_id, &key, offer, 10);
+        assert_eq!(fs::read(served.join("census.json")).unwrap(), content);
+    }
+
+    #[test]
+    fn senders_of_the_same_name_do_not_mix() {
+        let (mut first, mut receiver, source, served) = setup(b"0123456789");
+        let other = temp_dir("other").join("census.json");
+        fs::write(&other, b"abcdefghij").unwrap();
+        let mut second = Transfers::new(4, false);
+        let (first_id, second_id) = (PeerId::random(), PeerId::random());
+        let receiver_id = PeerId::random();
+        let (first_key, first_offer) = offer(&mut first, receiver_id, &source);
+        let (second_key, second_offer) = offer(&mut second, receiver_id, &other);
+        run(&mut first, &mut receiver, first_id, &first_key, first_offer, 2);
+        run(&mut second, &mut receiver, second_id, &second_key, second_offer, 2);
+        assert_eq!(fs::read(partial_path(&served, &first_id, "census.json")).unwrap(), b"0123");
+        assert_eq!(fs::read(partial_path(&served, &second_id, "census.json")).unwrap(), b"abcd");
+    }
+
+    #[test]
+    fn existing_files_are_kept_unless_overwriting() {
+        let (mut sender, mut receiver, source, served) = setup(b"0123456789");
+        fs::write(served.join("census.json"), b"kept").unwrap();
+        let sender_id = PeerId::random();
+        let (_, offer) = offer(&mut sender, PeerId::random(), &source);
+        assert!(matches!(receive(&mut receiver, sender_id, offer.clone()), TransferResponse::Refused(_)));
+        assert_eq!(fs::read(served.join("census.json")).unwrap(), b"kept");
+        receiver.overwrite = true;
+        assert!(matches!(receive(&mut receiver, sender_id, offer), TransferResponse::Accepted { .. }));
+    }
+
+    #[test]
+    fn corrupted_chunks_and_unsafe_names_are_refused() {
+        let (_, mut receiver, _, served) = setup(b"");
+        let sender_id = PeerId::random();
+        let offer = TransferRequest::Offer { name: "../escape".to_string(), size: 1, sha256: [0; 32] };
+        assert!(matches!(receive(&mut receiver, sender_id, offer), TransferResponse::Refused(_)));
+        let offer = TransferRequest::Offer { name: "census.json".to_string(), size: 4, sha256: Sha256::digest(b"0123").into() };
+        receive(&mut receiver, sender_id, offer);
+        let chunk = TransferRequest::Chunk { name: "census.json".to_string(), offset: 0, data: b"0124".to_vec(), sha256: Sha256::digest(b"0123").into() };
+        assert!(matches!(receive(&mut receiver, sender_id, chunk), TransferResponse::Refused(_)));
+        assert!(!partial_path(&served, &sender_id, "census.json").exists());
+        assert!(matches!(receiver.events.last(), Some(TransferEvent::Failed { direction: Direction::Inbound, .. })));
+    }
+
+    #[test]
+    fn peers_are_limited_in_transfers_and_jobs() {
+        let (_, mut receiver, _, _) = setup(b"");
+        let (sender_id, other_id) = (PeerId::random(), PeerId::random());
+        let offer = |name: &str| TransferRequest::Offer { name: name.to_string(), size: 4, sha256: Sha256::digest(b"0123").into() };
+        for index in 0..MAX_INCOMING_PER_PEER {
+            assert!(matches!(receive(&mut receiver, sender_id, offer(&format!("{}.json", index))), TransferResponse::Accepted { .. }));
+        }
+        // Offered again, a file coming in takes no more room.
+        assert!(matches!(receive(&mut receiver, sender_id, offer("0.json")), TransferResponse::Accepted { .. }));
+        assert!(matches!(receive(&mut receiver, sender_id, offer("extra.json")), TransferResponse::Refused(_)));
+        assert!(matches!(receive(&mut receiver, other_id, offer("extra.json")), TransferResponse::Accepted { .. }));
+        // A chunk sent before the previous one is written is refused.
+        let chunk = |offset: u64, data: &[u8]| TransferRequest::Chunk {
+            name: "1.json".to_string(),
+            offset,
+            data: data.to_vec(),
+            sha256: Sha256::digest(data).into(),
+        };
+        assert_eq!(receiver.on_request(sender_id, chunk(0, b"01")), None);
+        assert!(matches!(receiver.on_request(sender_id, chunk(0, b"01")), Some(TransferResponse::Refused(_))));
+        receiver.jobs.clear();
+        // Jobs above the limit of a peer wait in the queue.
+        for index in 0..MAX_JOBS_PER_PEER + 1 {
+            receiver.push_job((sender_id, index.to_string()), PathBuf::from("missing"), Purpose::Offer { size: 0 });
+        }
+        receiver.push_job((other_id, "0".to_string()), PathBuf::from("missing"), Purpose::Offer { size: 0 });
+        receiver.spawn_jobs();
+        assert_eq!(receiver.jobs.len(), 1);
+        assert_eq!(receiver.running.get(&sender_id), Some(&MAX_JOBS_PER_PEER));
+    }
+
+    #[test]
+    fn nothing_is_accepted_without_a_directory() {
+        let mut receiver = Transfers::new(4, false);
+        let offer = TransferRequest::Offer { name: "census.json".to_string(), size: 0, sha256: Sha256::digest(b"").into() };
+        assert_eq!(receive(&mut receiver, PeerId::random(), offer), TransferResponse::Refused("not serving files".to_string()));
+    }
+}